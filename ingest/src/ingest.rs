@@ -1,29 +1,81 @@
 use crate::config::InnerConfig;
-use crate::{validate_batch_data, BatchData, IngestConfig};
+use crate::{validate_batch_data, BatchData, IngestConfig, IngestMetrics};
 use anyhow::{Context, Error, Result};
 use futures::{stream, StreamExt};
 use skar_format::{Block, BlockNumber, Transaction, TransactionReceipt};
 use skar_rpc_client::{GetBlockByNumber, GetBlockNumber, GetBlockReceipts, RpcClient, RpcRequest};
 use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
 pub struct Ingest {
     data_rx: mpsc::Receiver<BatchData>,
+    status: IngestStatus,
+}
+
+/// Progress of the ingester that can be checked from other tasks.
+#[derive(Clone)]
+pub struct IngestStatus {
+    client: Arc<RpcClient>,
+    following_tip: Arc<AtomicBool>,
+}
+
+/// Highest block reported by the health checks of the rpc endpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainTip {
+    /// This is `None` if none of the endpoints are healthy.
+    pub block_number: Option<u64>,
+    pub healthy_endpoints: usize,
+    pub num_endpoints: usize,
+}
+
+impl IngestStatus {
+    /// Returns true if the initial sync is finished and new blocks are ingested one by one.
+    pub fn is_following_tip(&self) -> bool {
+        self.following_tip.load(Ordering::Relaxed)
+    }
+
+    pub async fn chain_tip(&self) -> ChainTip {
+        let endpoints = self.client.endpoints();
+
+        let mut block_number = None;
+        let mut healthy_endpoints = 0;
+        for endpoint in endpoints {
+            if let Some(last_block) = endpoint.last_block().await {
+                let last_block: u64 = last_block.into();
+                block_number = cmp::max(block_number, Some(last_block));
+                healthy_endpoints += 1;
+            }
+        }
+
+        ChainTip {
+            block_number,
+            healthy_endpoints,
+            num_endpoints: endpoints.len(),
+        }
+    }
 }
 
 impl Ingest {
-    pub fn spawn(config: IngestConfig) -> Self {
+    pub fn spawn(config: IngestConfig, metrics: IngestMetrics) -> Self {
         let (data_tx, data_rx) = mpsc::channel(4);
 
-        let client = RpcClient::new(config.rpc_client).into();
+        let client: Arc<RpcClient> = RpcClient::new(config.rpc_client, &metrics.rpc_client).into();
+        let status = IngestStatus {
+            client: client.clone(),
+            following_tip: Arc::new(AtomicBool::new(false)),
+        };
+        let following_tip = status.following_tip.clone();
 
         tokio::spawn(async move {
             let e = Ingester {
                 client,
                 data_tx,
                 config: config.inner,
+                metrics,
+                following_tip,
             }
             .ingest()
             .await;
@@ -33,7 +85,11 @@ impl Ingest {
             }
         });
 
-        Self { data_rx }
+        Self { data_rx, status }
+    }
+
+    pub fn status(&self) -> IngestStatus {
+        self.status.clone()
     }
 
     pub async fn recv(&mut self) -> Result<BatchData> {
@@ -45,6 +101,8 @@ struct Ingester {
     client: Arc<RpcClient>,
     data_tx: mpsc::Sender<BatchData>,
     config: InnerConfig,
+    metrics: IngestMetrics,
+    following_tip: Arc<AtomicBool>,
 }
 
 impl Ingester {
@@ -53,6 +111,7 @@ impl Ingester {
         let mut tip_block_num = 0;
 
         log::info!("starting to wait for new blocks");
+        self.following_tip.store(true, Ordering::Relaxed);
 
         loop {
             if tip_block_num >= next_block {
@@ -82,6 +141,8 @@ impl Ingester {
 
                 validate_batch_data(&data).context("validate batch data")?;
 
+                self.metrics.blocks.inc();
+
                 if self.data_tx.send(data).await.is_err() {
                     log::warn!("quitting ingest loop because the receiver is dropped");
                     break;
@@ -158,6 +219,8 @@ impl Ingester {
 
             validate_batch_data(&data).context("validate data")?;
 
+            self.metrics.blocks.inc_by(data.to_block - data.from_block);
+
             if self.data_tx.send(data).await.is_err() {
                 log::warn!("no one is listening so quitting ingest loop.");
                 break;
@@ -179,6 +242,8 @@ impl Ingester {
             .unwrap();
         let to_block: u64 = to_block.into();
 
+        self.metrics.tip_block.set(to_block as i64);
+
         Ok(to_block)
     }
 }
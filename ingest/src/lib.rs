@@ -1,9 +1,11 @@
 mod config;
 mod ingest;
+mod metrics;
 mod types;
 mod validate;
 
 pub use config::IngestConfig;
-pub use ingest::Ingest;
+pub use ingest::{ChainTip, Ingest, IngestStatus};
+pub use metrics::IngestMetrics;
 pub use types::BatchData;
 pub use validate::validate_batch_data;
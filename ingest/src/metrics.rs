@@ -0,0 +1,35 @@
+use prometheus::{IntCounter, IntGauge, Registry};
+use skar_rpc_client::RpcClientMetrics;
+
+/// Metrics of downloading the data from the rpc endpoints.
+#[derive(Clone)]
+pub struct IngestMetrics {
+    pub rpc_client: RpcClientMetrics,
+    /// Latest block number that the rpc endpoints reported.
+    pub tip_block: IntGauge,
+    /// Blocks that were downloaded and validated.
+    pub blocks: IntCounter,
+}
+
+impl IngestMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let rpc_client = RpcClientMetrics::new(registry)?;
+        let tip_block = IntGauge::new(
+            "skar_ingest_tip_block",
+            "Latest block number reported by the rpc endpoints",
+        )?;
+        let blocks = IntCounter::new(
+            "skar_ingest_downloaded_blocks_total",
+            "Blocks that were downloaded from the rpc endpoints",
+        )?;
+
+        registry.register(Box::new(tip_block.clone()))?;
+        registry.register(Box::new(blocks.clone()))?;
+
+        Ok(Self {
+            rpc_client,
+            tip_block,
+            blocks,
+        })
+    }
+}
@@ -11,6 +11,11 @@ pub struct RpcClientConfig {
 #[derive(Serialize, Deserialize)]
 pub struct EndpointConfig {
     pub url: Url,
+    /// Name of the endpoint in the `endpoint` label of the metrics.
+    ///
+    /// Defaults to the index of the endpoint and the host of the url, since providers
+    /// often put the api key in the path of the url.
+    pub name: Option<String>,
     pub bearer_token: Option<String>,
     pub status_refresh_interval_secs: NonZeroU64,
     #[serde(flatten)]
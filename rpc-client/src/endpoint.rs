@@ -1,6 +1,6 @@
 use crate::{
-    EndpointConfig, Error, GetBlockNumber, GetLogs, LimitConfig, Result, RpcRequest,
-    RpcRequestImpl, RpcResponse,
+    metrics::EndpointMetrics, EndpointConfig, Error, GetBlockNumber, GetLogs, LimitConfig, Result,
+    RpcRequest, RpcRequestImpl, RpcResponse,
 };
 use reqwest::Method;
 use skar_format::BlockNumber;
@@ -19,10 +19,15 @@ pub struct Endpoint {
     url: Arc<Url>,
     last_block: Arc<RwLock<Option<BlockNumber>>>,
     job_tx: mpsc::Sender<Job>,
+    metrics: EndpointMetrics,
 }
 
 impl Endpoint {
-    pub fn new(http_client: reqwest::Client, config: EndpointConfig) -> Self {
+    pub(crate) fn new(
+        http_client: reqwest::Client,
+        config: EndpointConfig,
+        metrics: EndpointMetrics,
+    ) -> Self {
         let last_block = Arc::new(RwLock::new(None));
         let url = Arc::new(config.url);
         let bearer_token = config.bearer_token.map(Arc::new);
@@ -34,6 +39,7 @@ impl Endpoint {
                 status_refresh_interval_secs: config.status_refresh_interval_secs,
                 url: url.clone(),
                 bearer_token: bearer_token.clone(),
+                metrics: metrics.clone(),
             }
             .watch(),
         );
@@ -49,6 +55,7 @@ impl Endpoint {
                 last_limit_refresh: Instant::now(),
                 url: url.clone(),
                 bearer_token,
+                metrics: metrics.clone(),
             }
             .listen(),
         );
@@ -57,6 +64,7 @@ impl Endpoint {
             url,
             last_block,
             job_tx,
+            metrics,
         }
     }
 
@@ -64,11 +72,22 @@ impl Endpoint {
         &self.url
     }
 
+    /// Returns the block number of the last successful health check of the endpoint.
+    ///
+    /// This is `None` if the last health check failed.
+    pub async fn last_block(&self) -> Option<BlockNumber> {
+        *self.last_block.read().await
+    }
+
     pub async fn send(&self, req: Arc<RpcRequest>) -> Result<RpcResponse> {
         if let Some(requirement) = Self::calculate_required_last_block(&req) {
             match *self.last_block.read().await {
                 Some(last_block) if requirement <= last_block => (),
-                _ => return Err(Error::EndpointTooBehind),
+                _ => {
+                    let e = Error::EndpointTooBehind;
+                    self.metrics.record_error(&e);
+                    return Err(e);
+                }
             }
         }
 
@@ -109,6 +128,7 @@ struct WatchHealth {
     http_client: reqwest::Client,
     last_block: Arc<RwLock<Option<BlockNumber>>>,
     status_refresh_interval_secs: NonZeroU64,
+    metrics: EndpointMetrics,
 }
 
 impl WatchHealth {
@@ -124,15 +144,19 @@ impl WatchHealth {
                     bearer_token: self.bearer_token.clone(),
                     http_client: self.http_client.clone(),
                     job: Job { res_tx, req },
+                    metrics: self.metrics.clone(),
                 }
                 .send(),
             );
 
             match res_rx.recv().await.unwrap() {
                 Ok(resp) => {
-                    *self.last_block.write().await = Some(resp.try_into_single().unwrap());
+                    let last_block: BlockNumber = resp.try_into_single().unwrap();
+                    self.metrics.last_block.set(u64::from(last_block) as i64);
+                    *self.last_block.write().await = Some(last_block);
                 }
                 Err(e) => {
+                    self.metrics.last_block.set(-1);
                     *self.last_block.write().await = None;
                     log::error!(
                         "Failed to get last block for {}. Caused By:\n{}",
@@ -160,12 +184,14 @@ struct Listen {
     limit_config: LimitConfig,
     window_num_reqs: usize,
     last_limit_refresh: Instant,
+    metrics: EndpointMetrics,
 }
 
 impl Listen {
     async fn listen(mut self) {
         while let Some(job) = self.job_rx.recv().await {
             if let Err(e) = self.update_limit(&job.req) {
+                self.metrics.limiter_rejections.inc();
                 tokio::spawn(async move {
                     job.res_tx.send(Err(e)).await.ok();
                 });
@@ -178,6 +204,7 @@ impl Listen {
                     job,
                     url: self.url.clone(),
                     bearer_token: self.bearer_token.clone(),
+                    metrics: self.metrics.clone(),
                 }
                 .send(),
             );
@@ -246,12 +273,23 @@ struct SendRpcRequest {
     bearer_token: Option<Arc<String>>,
     http_client: reqwest::Client,
     job: Job,
+    metrics: EndpointMetrics,
 }
 
 impl SendRpcRequest {
     async fn send(self) {
         let res_tx = self.job.res_tx.clone();
+        let metrics = self.metrics.clone();
+
+        metrics.requests.inc();
+        let timer = metrics.latency.start_timer();
         let res = self.send_impl().await;
+        timer.observe_duration();
+
+        if let Err(e) = &res {
+            metrics.record_error(e);
+        }
+
         res_tx.send(res).await.ok();
     }
 
@@ -289,6 +327,12 @@ mod tests {
 
     use super::*;
 
+    fn test_metrics() -> EndpointMetrics {
+        crate::RpcClientMetrics::new(&prometheus::Registry::new())
+            .unwrap()
+            .endpoint("http://hello.com/")
+    }
+
     #[test]
     fn test_calculate_required_last_block() {
         let req = RpcRequest::Batch(vec![
@@ -359,6 +403,7 @@ mod tests {
             },
             window_num_reqs: 0,
             last_limit_refresh: Instant::now(),
+            metrics: test_metrics(),
         };
 
         let res = listen.update_limit(&RpcRequest::Batch(
@@ -399,6 +444,7 @@ mod tests {
             },
             window_num_reqs: 0,
             last_limit_refresh: Instant::now(),
+            metrics: test_metrics(),
         };
 
         let n = listen.calculate_needed_reqs(&RpcRequest::Single(RpcRequestImpl::GetBlockNumber));
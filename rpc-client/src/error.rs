@@ -15,4 +15,17 @@ pub enum Error {
     InvalidRPCResponse(String),
 }
 
+impl Error {
+    /// Short name of the error that is used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::HttpRequest(_) => "http_request",
+            Self::NoHealthyEndpoints(_) => "no_healthy_endpoints",
+            Self::EndpointLimitTooLow => "endpoint_limit_too_low",
+            Self::EndpointTooBehind => "endpoint_too_behind",
+            Self::InvalidRPCResponse(_) => "invalid_rpc_response",
+        }
+    }
+}
+
 pub type Result<T> = StdResult<T, Error>;
@@ -1,11 +1,13 @@
 mod config;
 mod endpoint;
 mod error;
+mod metrics;
 mod rpc_client;
 mod types;
 
 pub use config::{EndpointConfig, LimitConfig, RpcClientConfig};
 pub use error::{Error, Result};
+pub use metrics::RpcClientMetrics;
 pub use rpc_client::RpcClient;
 pub use types::{
     GetBlockByNumber, GetBlockNumber, GetBlockReceipts, GetLogs, GetTransactionReceipt, MaybeBatch,
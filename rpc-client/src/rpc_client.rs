@@ -1,4 +1,7 @@
-use crate::{endpoint::Endpoint, Error, Result, RpcClientConfig, RpcRequest, RpcResponse};
+use crate::{
+    endpoint::Endpoint, metrics::endpoint_label, Error, Result, RpcClientConfig, RpcClientMetrics,
+    RpcRequest, RpcResponse,
+};
 use std::cmp;
 use std::sync::Arc;
 use std::time::Duration;
@@ -9,7 +12,7 @@ pub struct RpcClient {
 }
 
 impl RpcClient {
-    pub fn new(config: RpcClientConfig) -> Self {
+    pub fn new(config: RpcClientConfig, metrics: &RpcClientMetrics) -> Self {
         let http_client = reqwest::Client::builder()
             .gzip(true)
             .timeout(Duration::from_millis(config.http_req_timeout_millis.get()))
@@ -20,7 +23,11 @@ impl RpcClient {
         let endpoints = config
             .endpoints
             .into_iter()
-            .map(|cfg| Endpoint::new(http_client.clone(), cfg))
+            .enumerate()
+            .map(|(i, cfg)| {
+                let metrics = metrics.endpoint(&endpoint_label(i, &cfg));
+                Endpoint::new(http_client.clone(), cfg, metrics)
+            })
             .collect::<Vec<_>>();
 
         Self { endpoints }
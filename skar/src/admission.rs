@@ -0,0 +1,186 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{config::AdmissionConfig, types::Query};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryCost {
+    Light,
+    Heavy,
+}
+
+impl QueryCost {
+    /// Classifies the query by the number of parquet folders in its block range and
+    /// how selective its log and transaction selections are.
+    pub fn estimate(cfg: &AdmissionConfig, query: &Query, num_folders: usize) -> Self {
+        let broad_logs = query
+            .logs
+            .iter()
+            .any(|selection| selection.address.is_empty());
+        let broad_transactions = query.transactions.iter().any(|selection| {
+            selection.from.is_empty() && selection.to.is_empty() && selection.sighash.is_empty()
+        });
+        let broad = broad_logs || broad_transactions || query.include_all_blocks;
+
+        if num_folders >= cfg.heavy_query_folders || (broad && num_folders > 1) {
+            QueryCost::Heavy
+        } else {
+            QueryCost::Light
+        }
+    }
+}
+
+/// Limits the number of queries that are executed at the same time.
+///
+/// Queries that can't start right away wait in a bounded queue, so a burst of requests
+/// is rejected early instead of slowing down every query that is already running.
+/// Heavy queries have their own smaller limit so they can't take all of the slots.
+pub struct Admission {
+    cfg: AdmissionConfig,
+    slots: Arc<Semaphore>,
+    heavy_slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+/// Held while a query is executing, the slot is released when this is dropped.
+pub struct AdmissionPermit {
+    _slot: OwnedSemaphorePermit,
+    _heavy_slot: Option<OwnedSemaphorePermit>,
+}
+
+/// The query was rejected because the server is overloaded.
+pub struct Overloaded {
+    pub retry_after: Duration,
+}
+
+impl Admission {
+    pub fn new(cfg: AdmissionConfig) -> Self {
+        Self {
+            cfg,
+            slots: Arc::new(Semaphore::new(cfg.max_concurrent_queries)),
+            heavy_slots: Arc::new(Semaphore::new(cfg.max_concurrent_heavy_queries)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn cfg(&self) -> &AdmissionConfig {
+        &self.cfg
+    }
+
+    /// Waits for a free slot for a query of the given cost.
+    ///
+    /// Returns `Overloaded` if the queue is full or the query waits longer than the queue timeout.
+    pub async fn admit(&self, cost: QueryCost) -> Result<AdmissionPermit, Overloaded> {
+        if let Some(permit) = self.try_admit(cost) {
+            return Ok(permit);
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.cfg.max_queued_queries {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(self.overloaded());
+        }
+
+        let res = tokio::time::timeout(
+            Duration::from_millis(self.cfg.queue_timeout_ms),
+            self.acquire(cost),
+        )
+        .await;
+
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        res.map_err(|_| self.overloaded())
+    }
+
+    fn try_admit(&self, cost: QueryCost) -> Option<AdmissionPermit> {
+        let heavy_slot = match cost {
+            QueryCost::Heavy => Some(self.heavy_slots.clone().try_acquire_owned().ok()?),
+            QueryCost::Light => None,
+        };
+        let slot = self.slots.clone().try_acquire_owned().ok()?;
+
+        Some(AdmissionPermit {
+            _slot: slot,
+            _heavy_slot: heavy_slot,
+        })
+    }
+
+    async fn acquire(&self, cost: QueryCost) -> AdmissionPermit {
+        // The semaphores are never closed so acquiring can't fail.
+        let heavy_slot = match cost {
+            QueryCost::Heavy => Some(self.heavy_slots.clone().acquire_owned().await.unwrap()),
+            QueryCost::Light => None,
+        };
+        let slot = self.slots.clone().acquire_owned().await.unwrap();
+
+        AdmissionPermit {
+            _slot: slot,
+            _heavy_slot: heavy_slot,
+        }
+    }
+
+    fn overloaded(&self) -> Overloaded {
+        Overloaded {
+            retry_after: Duration::from_secs(self.cfg.retry_after_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(json: serde_json::Value) -> Query {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_estimate_cost() {
+        let cfg = AdmissionConfig::default();
+
+        let selective = query(serde_json::json!({
+            "from_block": 0,
+            "logs": [{ "address": ["0x3883f5e181fccaf8410fa61e12b59bad963fb645"] }],
+        }));
+        let topic_only = query(serde_json::json!({
+            "from_block": 0,
+            "logs": [{ "topics": [["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"]] }],
+        }));
+
+        assert_eq!(QueryCost::estimate(&cfg, &selective, 2), QueryCost::Light);
+        assert_eq!(
+            QueryCost::estimate(&cfg, &selective, cfg.heavy_query_folders),
+            QueryCost::Heavy
+        );
+        assert_eq!(QueryCost::estimate(&cfg, &topic_only, 1), QueryCost::Light);
+        assert_eq!(QueryCost::estimate(&cfg, &topic_only, 2), QueryCost::Heavy);
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let admission = Admission::new(AdmissionConfig {
+            max_concurrent_queries: 2,
+            max_concurrent_heavy_queries: 1,
+            max_queued_queries: 1,
+            queue_timeout_ms: 10,
+            ..Default::default()
+        });
+
+        let heavy = admission.admit(QueryCost::Heavy).await.ok().unwrap();
+        // The heavy slot is taken so the second heavy query times out in the queue.
+        assert!(admission.admit(QueryCost::Heavy).await.is_err());
+
+        let light = admission.admit(QueryCost::Light).await.ok().unwrap();
+        assert!(admission.admit(QueryCost::Light).await.is_err());
+
+        drop(heavy);
+        drop(light);
+        assert!(admission.admit(QueryCost::Heavy).await.is_ok());
+    }
+}
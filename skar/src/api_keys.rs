@@ -0,0 +1,361 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    config::{ApiKeyConfig, HttpServerConfig},
+    db::{ApiKeyUsage, Db},
+    query::QueryLimits,
+    write_parquet::MEGABYTE,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Api keys that are accepted by the http and flight servers.
+///
+/// Usage is counted in memory and written to the database by [`ApiKeys::persist_usage`],
+/// so the usage since the last call is lost if the process stops.
+pub struct ApiKeys {
+    /// bearer token -> key
+    keys: HashMap<String, Arc<ApiKey>>,
+    db: Arc<Db>,
+}
+
+pub struct ApiKey {
+    cfg: ApiKeyConfig,
+    state: Mutex<KeyState>,
+}
+
+struct KeyState {
+    /// Each request takes a token, tokens are refilled at `requests_per_second`.
+    tokens: f64,
+    last_refill: Instant,
+    usage: ApiKeyUsage,
+    /// The usage changed since it was last written to the database.
+    dirty: bool,
+}
+
+/// The request was rejected because of its api key.
+pub enum Rejection {
+    /// The request doesn't have a known api key.
+    Unauthorized,
+    RateLimited {
+        retry_after: Duration,
+    },
+    /// One of the daily quotas of the key is used up, `retry_after` is the start of the next day.
+    QuotaExceeded {
+        retry_after: Duration,
+    },
+}
+
+#[derive(Deserialize)]
+struct ApiKeyFile {
+    #[serde(default)]
+    api_keys: Vec<ApiKeyConfig>,
+}
+
+impl ApiKeys {
+    /// Reads the keys from the config and the key file and their usage for today from the database.
+    ///
+    /// Returns `None` if keys are not required.
+    pub fn load(cfg: &HttpServerConfig, db: Arc<Db>) -> Result<Option<Self>> {
+        let mut configs = cfg.api_keys.clone();
+
+        match &cfg.api_key_file {
+            Some(path) => {
+                let file = std::fs::read_to_string(path).context("read api key file")?;
+                let file: ApiKeyFile = toml::de::from_str(&file).context("parse api key file")?;
+                configs.extend(file.api_keys);
+            }
+            None if configs.is_empty() => return Ok(None),
+            None => (),
+        }
+
+        let today = day(unix_time());
+        let mut names = HashSet::new();
+        let mut keys = HashMap::new();
+
+        for cfg in configs {
+            if !names.insert(cfg.name.clone()) {
+                return Err(anyhow!("duplicate api key name: {}", cfg.name));
+            }
+            if cfg.requests_per_second == Some(0) {
+                return Err(anyhow!(
+                    "requests_per_second of api key {} is zero",
+                    cfg.name
+                ));
+            }
+
+            let usage = db
+                .get_api_key_usage(&cfg.name)
+                .context("read api key usage")?
+                .filter(|usage| usage.day == today)
+                .unwrap_or(ApiKeyUsage {
+                    day: today,
+                    ..Default::default()
+                });
+
+            let name = cfg.name.clone();
+            let token = cfg.key.clone();
+            if keys
+                .insert(token, Arc::new(ApiKey::new(cfg, usage, Instant::now())))
+                .is_some()
+            {
+                return Err(anyhow!(
+                    "api key {} has the same value as another key",
+                    name
+                ));
+            }
+        }
+
+        Ok(Some(Self { keys, db }))
+    }
+
+    /// Finds the key of the given `Authorization` header value and counts the request against its limits.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Arc<ApiKey>, Rejection> {
+        let key = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.keys.get(token.trim()))
+            .ok_or(Rejection::Unauthorized)?;
+
+        key.admit(Instant::now(), unix_time())?;
+
+        Ok(key.clone())
+    }
+
+    /// Writes the usage of the keys that changed since the last call to the database.
+    pub async fn persist_usage(&self) -> Result<()> {
+        let usage = self
+            .keys
+            .values()
+            .filter_map(|key| key.take_changed_usage())
+            .collect::<Vec<_>>();
+
+        if usage.is_empty() {
+            return Ok(());
+        }
+
+        self.db
+            .write_api_key_usage(&usage)
+            .await
+            .context("write api key usage")
+    }
+}
+
+impl ApiKey {
+    fn new(cfg: ApiKeyConfig, usage: ApiKeyUsage, now: Instant) -> Self {
+        let tokens = cfg.requests_per_second.unwrap_or(0).into();
+
+        Self {
+            cfg,
+            state: Mutex::new(KeyState {
+                tokens,
+                last_refill: now,
+                usage,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Lowers the given server limits to the limits of the key.
+    pub fn query_limits(&self, size_limit: usize) -> QueryLimits {
+        let size_limit = match self.cfg.max_response_size_mb {
+            Some(max_response_size_mb) => size_limit.min(max_response_size_mb * MEGABYTE),
+            None => size_limit,
+        };
+
+        QueryLimits {
+            size_limit,
+            time_limit_ms: self.cfg.max_time_limit_ms,
+        }
+    }
+
+    /// Counts the bytes of a response against the daily response quota.
+    pub fn add_response_bytes(&self, num_bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        state.start_day(day(unix_time()));
+        state.usage.num_bytes += num_bytes as u64;
+        state.dirty = true;
+    }
+
+    fn admit(&self, now: Instant, unix_time: u64) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap();
+
+        state.start_day(day(unix_time));
+
+        let requests_exceeded = self
+            .cfg
+            .daily_request_quota
+            .map(|quota| state.usage.num_requests >= quota)
+            .unwrap_or(false);
+        let bytes_exceeded = self
+            .cfg
+            .daily_response_quota_mb
+            .map(|quota_mb| state.usage.num_bytes >= quota_mb * MEGABYTE as u64)
+            .unwrap_or(false);
+
+        if requests_exceeded || bytes_exceeded {
+            return Err(Rejection::QuotaExceeded {
+                retry_after: Duration::from_secs(SECONDS_PER_DAY - unix_time % SECONDS_PER_DAY),
+            });
+        }
+
+        if let Some(requests_per_second) = self.cfg.requests_per_second {
+            let rate = f64::from(requests_per_second);
+            let elapsed = now.saturating_duration_since(state.last_refill);
+
+            state.tokens = (state.tokens + elapsed.as_secs_f64() * rate).min(rate);
+            state.last_refill = now;
+
+            if state.tokens < 1.0 {
+                return Err(Rejection::RateLimited {
+                    retry_after: Duration::from_secs_f64((1.0 - state.tokens) / rate),
+                });
+            }
+
+            state.tokens -= 1.0;
+        }
+
+        state.usage.num_requests += 1;
+        state.dirty = true;
+
+        Ok(())
+    }
+
+    fn take_changed_usage(&self) -> Option<(String, ApiKeyUsage)> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.dirty {
+            return None;
+        }
+        state.dirty = false;
+
+        Some((self.cfg.name.clone(), state.usage))
+    }
+}
+
+impl KeyState {
+    // Resets the usage if the day changed since the last request.
+    fn start_day(&mut self, day: u32) {
+        if self.usage.day != day {
+            self.usage = ApiKeyUsage {
+                day,
+                ..Default::default()
+            };
+            self.dirty = true;
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+fn day(unix_time: u64) -> u32 {
+    (unix_time / SECONDS_PER_DAY) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_config() -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: "test".to_owned(),
+            key: "secret".to_owned(),
+            requests_per_second: None,
+            daily_request_quota: None,
+            daily_response_quota_mb: None,
+            max_time_limit_ms: None,
+            max_response_size_mb: None,
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let unix_time = 100 * SECONDS_PER_DAY;
+
+        let key = ApiKey::new(
+            ApiKeyConfig {
+                requests_per_second: Some(2),
+                ..key_config()
+            },
+            ApiKeyUsage::default(),
+            start,
+        );
+
+        assert!(key.admit(start, unix_time).is_ok());
+        assert!(key.admit(start, unix_time).is_ok());
+        assert!(matches!(
+            key.admit(start, unix_time),
+            Err(Rejection::RateLimited { .. })
+        ));
+
+        let later = start + Duration::from_millis(500);
+        assert!(key.admit(later, unix_time).is_ok());
+        assert!(key.admit(later, unix_time).is_err());
+    }
+
+    #[test]
+    fn test_daily_quota() {
+        let now = Instant::now();
+        let unix_time = 100 * SECONDS_PER_DAY + 60;
+
+        let key = ApiKey::new(
+            ApiKeyConfig {
+                daily_request_quota: Some(2),
+                ..key_config()
+            },
+            ApiKeyUsage {
+                day: 100,
+                num_requests: 1,
+                num_bytes: 0,
+            },
+            now,
+        );
+
+        assert!(key.admit(now, unix_time).is_ok());
+        match key.admit(now, unix_time) {
+            Err(Rejection::QuotaExceeded { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(SECONDS_PER_DAY - 60))
+            }
+            _ => panic!("expected the quota to be exceeded"),
+        }
+
+        // The usage of the previous day doesn't count.
+        assert!(key.admit(now, unix_time + SECONDS_PER_DAY).is_ok());
+        assert_eq!(
+            key.take_changed_usage().unwrap().1,
+            ApiKeyUsage {
+                day: 101,
+                num_requests: 1,
+                num_bytes: 0,
+            }
+        );
+        assert!(key.take_changed_usage().is_none());
+
+        let key = ApiKey::new(
+            ApiKeyConfig {
+                daily_response_quota_mb: Some(1),
+                ..key_config()
+            },
+            ApiKeyUsage {
+                day: 100,
+                num_requests: 0,
+                num_bytes: MEGABYTE as u64,
+            },
+            now,
+        );
+        assert!(key.admit(now, unix_time).is_err());
+    }
+}
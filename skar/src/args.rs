@@ -1,9 +1,26 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug, Clone)]
 pub struct Args {
     #[clap(long, default_value_t = default_config_path())]
     pub config_path: String,
+    /// Run a maintenance command instead of starting the server
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Measure the false positive rates of the bloom filters of existing folders
+    FilterStats {
+        /// Number of keys to probe each filter with
+        #[clap(long, default_value_t = 10_000)]
+        num_probes: usize,
+    },
+    /// Rebuild the bloom filters of existing folders using the current config.
+    ///
+    /// Skar must not be running on the same database while this runs.
+    RebuildFilters,
 }
 
 fn default_config_path() -> String {
@@ -1,4 +1,9 @@
-use std::{cmp, collections::BTreeSet, fs::File, path::Path};
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    path::Path,
+};
 
 use anyhow::{Context, Result};
 use arrow2::{
@@ -9,14 +14,18 @@ use sbbf_rs_safe::Filter as BFilter;
 use wyhash::wyhash;
 
 use crate::{
+    config::{BloomFilterConfig, FilterConfig},
     db::{
-        BlockRange, BlockRowGroupIndex, BloomFilter, FolderIndex, LogRowGroupIndex, RowGroupIndex,
-        TransactionRowGroupIndex,
+        AddressIndex, BlockRange, BlockRowGroupIndex, BloomFilter, FolderIndex, HashIndex,
+        LogRowGroupIndex, RowGroupIndex, TransactionRowGroupIndex,
     },
     state::ArrowChunk,
 };
 
-pub fn build_parquet_indices(path: &Path) -> Result<(FolderIndex, RowGroupIndex)> {
+pub fn build_parquet_indices(
+    path: &Path,
+    cfg: &BloomFilterConfig,
+) -> Result<(FolderIndex, RowGroupIndex)> {
     let blocks = {
         let mut path = path.to_owned();
         path.push("blocks.parquet");
@@ -36,7 +45,7 @@ pub fn build_parquet_indices(path: &Path) -> Result<(FolderIndex, RowGroupIndex)
         load_file(&path).context("load logs")?
     };
 
-    let mut folder_addr_set = BTreeSet::new();
+    let mut folder_keys = FolderKeys::default();
 
     let mut rg_index = RowGroupIndex {
         block: Vec::new(),
@@ -47,112 +56,166 @@ pub fn build_parquet_indices(path: &Path) -> Result<(FolderIndex, RowGroupIndex)
     let mut folder_min_block_num = u64::MAX;
     let mut folder_max_block_num = u64::MIN;
     for chunk in blocks {
-        let block_num = chunk.columns()[0]
-            .as_any()
-            .downcast_ref::<UInt64Array>()
-            .unwrap();
-        let mut min_block_num = u64::MAX;
-        let mut max_block_num = u64::MIN;
+        let index = build_block_row_group_index(&chunk);
 
-        for b in block_num.iter().flatten() {
-            min_block_num = cmp::min(min_block_num, *b);
-            max_block_num = cmp::max(max_block_num, *b);
-        }
+        folder_min_block_num = cmp::min(folder_min_block_num, index.min_block_num);
+        folder_max_block_num = cmp::max(folder_max_block_num, index.max_block_num);
 
-        rg_index.block.push(BlockRowGroupIndex {
-            min_block_num,
-            max_block_num,
-        });
-
-        folder_min_block_num = cmp::min(folder_min_block_num, min_block_num);
-        folder_max_block_num = cmp::max(folder_max_block_num, max_block_num);
+        rg_index.block.push(index);
     }
 
     for chunk in transactions {
-        let block_num = chunk.columns()[1]
-            .as_any()
-            .downcast_ref::<UInt64Array>()
-            .unwrap();
-        let mut min_block_num = u64::MAX;
-        let mut max_block_num = u64::MIN;
+        rg_index.transaction.push(build_tx_row_group_index(
+            &chunk,
+            cfg,
+            Some(&mut folder_keys),
+        ));
+    }
 
-        for b in block_num.iter().flatten() {
-            min_block_num = cmp::min(min_block_num, *b);
-            max_block_num = cmp::max(max_block_num, *b);
-        }
+    for chunk in logs {
+        rg_index.log.push(build_log_row_group_index(
+            &chunk,
+            cfg,
+            Some(&mut folder_keys),
+        ));
+    }
 
-        let from = chunk.columns()[2]
-            .as_any()
-            .downcast_ref::<BinaryArray<i32>>()
-            .unwrap();
-        let mut from_addr_set = BTreeSet::new();
+    let FolderKeys {
+        address: folder_addr_set,
+        topics: folder_topic_sets,
+        sighash: folder_sighash_set,
+    } = folder_keys;
 
-        for f in from.iter().flatten() {
-            from_addr_set.insert(f);
-            folder_addr_set.insert(f.to_vec());
-        }
+    let address_filter = build_folder_filter(&cfg.folder_address, folder_addr_set);
 
-        let mut from_address_filter = BFilter::new(8, from_addr_set.len());
-        for addr in from_addr_set.into_iter() {
-            from_address_filter.insert_hash(wyhash(addr, 0));
-        }
+    let topic_filters = folder_topic_sets
+        .into_iter()
+        .map(|set| build_folder_filter(&cfg.folder_topic, set))
+        .collect::<Vec<_>>();
 
-        let to = chunk.columns()[8]
-            .as_any()
-            .downcast_ref::<BinaryArray<i32>>()
-            .unwrap();
-        let mut to_addr_set = BTreeSet::new();
+    let sighash_filter = build_folder_filter(&cfg.folder_sighash, folder_sighash_set);
 
-        for t in to.iter().flatten() {
-            to_addr_set.insert(t);
-            folder_addr_set.insert(t.to_vec());
-        }
+    let folder_index = FolderIndex {
+        block_range: BlockRange(folder_min_block_num, folder_max_block_num + 1),
+        address_filter,
+        topic_filters: Some(topic_filters.try_into().unwrap()),
+        sighash_filter: Some(sighash_filter),
+        row_group_index_offset: 0,
+    };
 
-        let mut to_address_filter = BFilter::new(8, to_addr_set.len());
-        for addr in to_addr_set.into_iter() {
-            to_address_filter.insert_hash(wyhash(addr, 0));
-        }
+    Ok((folder_index, rg_index))
+}
 
-        rg_index.transaction.push(TransactionRowGroupIndex {
-            min_block_num,
-            max_block_num,
-            to_address_filter: BloomFilter(to_address_filter),
-            from_address_filter: BloomFilter(from_address_filter),
-        });
-    }
+/// Distinct keys of all row groups in a folder.
+#[derive(Default)]
+pub(crate) struct FolderKeys {
+    address: BTreeSet<Vec<u8>>,
+    topics: [BTreeSet<Vec<u8>>; 4],
+    sighash: BTreeSet<Vec<u8>>,
+}
 
-    for chunk in logs {
-        let block_num = chunk.columns()[5]
-            .as_any()
-            .downcast_ref::<UInt64Array>()
-            .unwrap();
-        let mut min_block_num = u64::MAX;
-        let mut max_block_num = u64::MIN;
+pub(crate) fn build_block_row_group_index(chunk: &ArrowChunk) -> BlockRowGroupIndex {
+    let block_num = chunk.columns()[0]
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+
+    let (min_block_num, max_block_num) = min_max(block_num);
 
-        for b in block_num.iter().flatten() {
-            min_block_num = cmp::min(min_block_num, *b);
-            max_block_num = cmp::max(max_block_num, *b);
+    BlockRowGroupIndex {
+        min_block_num,
+        max_block_num,
+    }
+}
+
+/// Builds the index of a transaction row group and adds its keys to `folder_keys` if given.
+pub(crate) fn build_tx_row_group_index(
+    chunk: &ArrowChunk,
+    cfg: &BloomFilterConfig,
+    mut folder_keys: Option<&mut FolderKeys>,
+) -> TransactionRowGroupIndex {
+    let block_num = chunk.columns()[1]
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    let (min_block_num, max_block_num) = min_max(block_num);
+
+    let from = chunk.columns()[2]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap();
+    let mut from_addr_set = BTreeSet::new();
+
+    for f in from.iter().flatten() {
+        from_addr_set.insert(f);
+        if let Some(folder_keys) = folder_keys.as_deref_mut() {
+            folder_keys.address.insert(f.to_vec());
         }
+    }
 
-        let address = chunk.columns()[6]
-            .as_any()
-            .downcast_ref::<BinaryArray<i32>>()
-            .unwrap();
-        let mut addr_set = BTreeSet::new();
+    let to = chunk.columns()[8]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap();
+    let mut to_addr_set = BTreeSet::new();
 
-        for addr in address.iter().flatten() {
-            addr_set.insert(addr);
-            folder_addr_set.insert(addr.to_vec());
+    for t in to.iter().flatten() {
+        to_addr_set.insert(t);
+        if let Some(folder_keys) = folder_keys.as_deref_mut() {
+            folder_keys.address.insert(t.to_vec());
         }
+    }
 
-        let mut address_filter = BFilter::new(8, addr_set.len());
-        for addr in addr_set.into_iter() {
-            address_filter.insert_hash(wyhash(addr, 0));
+    let sighash = chunk.columns()[25]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap();
+    let mut sighash_set = BTreeSet::new();
+
+    for s in sighash.iter().flatten() {
+        sighash_set.insert(s);
+        if let Some(folder_keys) = folder_keys.as_deref_mut() {
+            folder_keys.sighash.insert(s.to_vec());
         }
+    }
 
-        let mut topic_filters = Vec::with_capacity(4);
+    TransactionRowGroupIndex {
+        min_block_num,
+        max_block_num,
+        from_address_filter: build_row_group_filter(&cfg.row_group_address, from_addr_set),
+        to_address_filter: build_row_group_filter(&cfg.row_group_address, to_addr_set),
+        sighash_filter: Some(build_row_group_filter(&cfg.row_group_sighash, sighash_set)),
+    }
+}
+
+/// Builds the index of a log row group and adds its keys to `folder_keys` if given.
+pub(crate) fn build_log_row_group_index(
+    chunk: &ArrowChunk,
+    cfg: &BloomFilterConfig,
+    mut folder_keys: Option<&mut FolderKeys>,
+) -> LogRowGroupIndex {
+    let block_num = chunk.columns()[5]
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    let (min_block_num, max_block_num) = min_max(block_num);
+
+    let address = chunk.columns()[6]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap();
+    let mut addr_set = BTreeSet::new();
+
+    for addr in address.iter().flatten() {
+        addr_set.insert(addr);
+        if let Some(folder_keys) = folder_keys.as_deref_mut() {
+            folder_keys.address.insert(addr.to_vec());
+        }
+    }
 
-        for col_idx in 8..12 {
+    let topic_filters = (8..12)
+        .enumerate()
+        .map(|(topic_idx, col_idx)| {
             let col = chunk.columns()[col_idx]
                 .as_any()
                 .downcast_ref::<BinaryArray<i32>>()
@@ -162,42 +225,186 @@ pub fn build_parquet_indices(path: &Path) -> Result<(FolderIndex, RowGroupIndex)
 
             for t in col.iter().flatten() {
                 topic_set.insert(t);
+                if let Some(folder_keys) = folder_keys.as_deref_mut() {
+                    folder_keys.topics[topic_idx].insert(t.to_vec());
+                }
             }
 
-            let mut topic_filter = BFilter::new(8, topic_set.len());
-            for topic in topic_set.into_iter() {
-                topic_filter.insert_hash(wyhash(topic, 0));
-            }
+            build_row_group_filter(&cfg.row_group_topic, topic_set)
+        })
+        .collect::<Vec<_>>();
 
-            topic_filters.push(BloomFilter(topic_filter));
-        }
+    LogRowGroupIndex {
+        min_block_num,
+        max_block_num,
+        address_filter: build_row_group_filter(&cfg.row_group_address, addr_set),
+        topic_filters: topic_filters.try_into().unwrap(),
+    }
+}
 
-        rg_index.log.push(LogRowGroupIndex {
-            min_block_num,
-            max_block_num,
-            address_filter: BloomFilter(address_filter),
-            topic_filters: topic_filters.try_into().unwrap(),
-        });
+fn min_max(block_num: &UInt64Array) -> (u64, u64) {
+    let mut min_block_num = u64::MAX;
+    let mut max_block_num = u64::MIN;
+
+    for b in block_num.iter().flatten() {
+        min_block_num = cmp::min(min_block_num, *b);
+        max_block_num = cmp::max(max_block_num, *b);
     }
 
-    let mut address_filter = BFilter::new(8, cmp::min(folder_addr_set.len(), 32 * 1024));
-    for addr in folder_addr_set.into_iter() {
-        address_filter.insert_hash(wyhash(&addr, 0));
+    (min_block_num, max_block_num)
+}
+
+fn build_row_group_filter(cfg: &FilterConfig, set: BTreeSet<&[u8]>) -> BloomFilter {
+    let mut filter = new_filter(cfg, set.len());
+    for val in set.into_iter() {
+        filter.insert_hash(wyhash(val, 0));
     }
 
-    let folder_index = FolderIndex {
-        block_range: BlockRange(folder_min_block_num, folder_max_block_num + 1),
-        address_filter: BloomFilter(address_filter),
-        row_group_index_offset: 0,
+    BloomFilter(filter)
+}
+
+fn build_folder_filter(cfg: &FilterConfig, set: BTreeSet<Vec<u8>>) -> BloomFilter {
+    let mut filter = new_filter(cfg, set.len());
+    for val in set.into_iter() {
+        filter.insert_hash(wyhash(&val, 0));
+    }
+
+    BloomFilter(filter)
+}
+
+fn new_filter(cfg: &FilterConfig, num_keys: usize) -> BFilter {
+    let num_keys = match cfg.max_keys {
+        Some(max_keys) => cmp::min(num_keys, max_keys),
+        None => num_keys,
     };
 
-    Ok((folder_index, rg_index))
+    BFilter::new(cfg.bits_per_key, num_keys)
+}
+
+pub fn build_hash_index(path: &Path) -> Result<HashIndex> {
+    let blocks = {
+        let mut path = path.to_owned();
+        path.push("blocks.parquet");
+
+        load_columns(&path, &["number", "hash"]).context("load blocks")?
+    };
+    let transactions = {
+        let mut path = path.to_owned();
+        path.push("transactions.parquet");
+
+        load_columns(&path, &["block_number", "hash", "transaction_index"])
+            .context("load transactions")?
+    };
+
+    let mut hash_index = HashIndex::default();
+
+    for chunk in blocks {
+        let number = chunk.columns()[0]
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        let hash = chunk.columns()[1]
+            .as_any()
+            .downcast_ref::<BinaryArray<i32>>()
+            .unwrap();
+
+        for (n, h) in number.values_iter().zip(hash.values_iter()) {
+            hash_index.blocks.push((h.to_vec(), *n));
+        }
+    }
+
+    for chunk in transactions {
+        let block_number = chunk.columns()[0]
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        let hash = chunk.columns()[1]
+            .as_any()
+            .downcast_ref::<BinaryArray<i32>>()
+            .unwrap();
+        let tx_index = chunk.columns()[2]
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+
+        for ((b, h), t) in block_number
+            .values_iter()
+            .zip(hash.values_iter())
+            .zip(tx_index.values_iter())
+        {
+            hash_index.transactions.push((h.to_vec(), *b, *t));
+        }
+    }
+
+    Ok(hash_index)
+}
+
+pub fn build_address_index(path: &Path) -> Result<AddressIndex> {
+    let transactions = {
+        let mut path = path.to_owned();
+        path.push("transactions.parquet");
+
+        load_columns(&path, &["from", "to"]).context("load transactions")?
+    };
+    let logs = {
+        let mut path = path.to_owned();
+        path.push("logs.parquet");
+
+        load_columns(&path, &["address"]).context("load logs")?
+    };
+
+    let mut address_index = AddressIndex::default();
+
+    for (rg_idx, chunk) in transactions.iter().enumerate() {
+        insert_postings(&mut address_index.transaction_from, chunk, 0, rg_idx);
+        insert_postings(&mut address_index.transaction_to, chunk, 1, rg_idx);
+    }
+
+    for (rg_idx, chunk) in logs.iter().enumerate() {
+        insert_postings(&mut address_index.log, chunk, 0, rg_idx);
+    }
+
+    Ok(address_index)
+}
+
+fn insert_postings(
+    postings: &mut BTreeMap<Vec<u8>, Vec<u32>>,
+    chunk: &ArrowChunk,
+    col_idx: usize,
+    rg_idx: usize,
+) {
+    let rg_idx = u32::try_from(rg_idx).unwrap();
+
+    let addresses = chunk.columns()[col_idx]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap();
+
+    for addr in addresses.iter().flatten() {
+        let row_groups = postings.entry(addr.to_vec()).or_default();
+        if row_groups.last() != Some(&rg_idx) {
+            row_groups.push(rg_idx);
+        }
+    }
 }
 
 fn load_file(path: &Path) -> Result<Vec<ArrowChunk>> {
+    load_file_impl(path, None)
+}
+
+// Columns in the returned chunks are in the same order as they are in the file schema.
+pub fn load_columns(path: &Path, columns: &[&str]) -> Result<Vec<ArrowChunk>> {
+    load_file_impl(path, Some(columns))
+}
+
+fn load_file_impl(path: &Path, columns: Option<&[&str]>) -> Result<Vec<ArrowChunk>> {
     let mut reader = File::open(path).context("open parquet file")?;
     let metadata = parquet::read::read_metadata(&mut reader).context("read metadata")?;
     let schema = parquet::read::infer_schema(&metadata).context("infer schema")?;
+    let schema = match columns {
+        Some(columns) => schema.filter(|_, field| columns.contains(&field.name.as_str())),
+        None => schema,
+    };
     let chunks = parquet::read::FileReader::new(
         reader,
         metadata.row_groups,
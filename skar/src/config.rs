@@ -13,6 +13,10 @@ pub struct Config {
     pub db: DbConfig,
     /// Config for the http server
     pub http_server: HttpServerConfig,
+    /// Config for the arrow flight server
+    ///
+    /// The flight server is not started if not given.
+    pub flight_server: Option<FlightServerConfig>,
     /// Config for query handler
     pub query: QueryConfig,
 }
@@ -24,6 +28,15 @@ pub struct QueryConfig {
     /// If this time limit is hit, the query will stop,
     /// and the data will be returned to the user.
     pub time_limit_ms: u64,
+    /// Maximum number of folders that are read and executed at the same time for a single query.
+    ///
+    /// The results are still returned in block order.
+    /// Defaults to 4 if not given.
+    pub max_concurrent_folders: Option<usize>,
+    /// Memory budget in megabytes for caching parquet metadata and row group indices between queries.
+    ///
+    /// Defaults to 64 if not given.
+    pub metadata_cache_mb: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,6 +48,137 @@ pub struct HttpServerConfig {
     /// If reponse payload reaches this size, the query will stop and
     /// the payload will be returned to client.
     pub response_size_limit_mb: usize,
+    /// Maximum number of open `/subscribe` streams.
+    ///
+    /// New subscriptions are rejected with `429 Too Many Requests` when this is reached.
+    /// Defaults to 256 if not given.
+    pub max_subscriptions: Option<usize>,
+    /// Limits on the number of queries that are executed at the same time.
+    ///
+    /// The limits are shared with the flight server.
+    /// Uses the defaults of `AdmissionConfig` for the limits that are not configured.
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    /// Api keys that are accepted as a bearer token in the `Authorization` header.
+    ///
+    /// If any keys are configured here or in `api_key_file`, requests without a valid key
+    /// are rejected with `401 Unauthorized`. The keys are also required by the flight server.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    /// Path to a toml file with more api keys in an `api_keys` array.
+    ///
+    /// Keys are required if this is given, even if the file has no keys.
+    pub api_key_file: Option<PathBuf>,
+    /// Thresholds of the `/health` and `/ready` endpoints.
+    ///
+    /// Uses the defaults of `HealthConfig` for the thresholds that are not configured.
+    #[serde(default)]
+    pub health: HealthConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// `/ready` fails if the archive is more than this many blocks behind the highest
+    /// block reported by the rpc endpoints.
+    pub max_lag_blocks: u64,
+    /// `/health` fails if the archive is behind the rpc endpoints and no blocks were
+    /// ingested for this many seconds.
+    pub max_stall_secs: u64,
+    /// Report ready during the initial sync if the lag is within `max_lag_blocks`.
+    pub ready_during_initial_sync: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_lag_blocks: 10,
+            max_stall_secs: 120,
+            ready_during_initial_sync: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    /// Name that the usage of the key is stored under in the database.
+    ///
+    /// Keeping the name when replacing the key keeps its usage for the day.
+    pub name: String,
+    /// Value of the bearer token.
+    pub key: String,
+    /// Maximum number of requests per second, bursts of up to this many requests are allowed.
+    ///
+    /// There is no rate limit if not given.
+    pub requests_per_second: Option<u32>,
+    /// Maximum number of requests per day.
+    ///
+    /// Days start at midnight UTC. There is no limit if not given.
+    pub daily_request_quota: Option<u64>,
+    /// Maximum size of all response bodies per day in megabytes.
+    ///
+    /// The response that reaches the quota is still sent in full.
+    /// There is no limit if not given.
+    pub daily_response_quota_mb: Option<u64>,
+    /// Time limit for the queries made with this key.
+    ///
+    /// Can only lower the `time_limit_ms` of the query config.
+    pub max_time_limit_ms: Option<u64>,
+    /// Response size limit for the queries made with this key.
+    ///
+    /// Can only lower the response size limit of the server.
+    pub max_response_size_mb: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FlightServerConfig {
+    /// Socket address to serve the arrow flight server from
+    pub addr: SocketAddr,
+    /// Size limit for the data that is returned from a single `DoGet` call.
+    ///
+    /// If the estimated size of the results reaches this, the query will stop
+    /// and the stream will be ended.
+    pub response_size_limit_mb: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    /// Maximum number of queries that are executed at the same time.
+    pub max_concurrent_queries: usize,
+    /// Maximum number of heavy queries that are executed at the same time.
+    ///
+    /// Heavy queries also count towards `max_concurrent_queries`.
+    pub max_concurrent_heavy_queries: usize,
+    /// Maximum number of queries that wait for a free slot.
+    ///
+    /// Queries that arrive while the queue is full are rejected with `429 Too Many Requests`.
+    pub max_queued_queries: usize,
+    /// Time limit for waiting in the queue.
+    ///
+    /// Queries that don't get a slot in time are rejected with `429 Too Many Requests`.
+    pub queue_timeout_ms: u64,
+    /// Queries that span at least this many parquet folders are heavy.
+    ///
+    /// Queries that select logs without an address or transactions without an
+    /// address or sighash are heavy if they span more than one folder, since
+    /// the indices can't rule out many row groups for them.
+    pub heavy_query_folders: usize,
+    /// Value of the `Retry-After` header of rejected queries in seconds.
+    pub retry_after_secs: u64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_queries: 32,
+            max_concurrent_heavy_queries: 4,
+            max_queued_queries: 128,
+            queue_timeout_ms: 5000,
+            heavy_query_folders: 16,
+            retry_after_secs: 1,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,6 +191,83 @@ pub struct ParquetConfig {
     pub transactions: TableConfig,
     /// config for log parquet files
     pub logs: TableConfig,
+    /// Memory budget for writing a parquet file in megabytes.
+    ///
+    /// This bounds the memory used for sorting and encoding the data on top of the
+    /// in memory data itself. Row groups are cut short if they don't fit in the budget.
+    /// Defaults to 256 if not given.
+    pub writer_memory_budget_mb: Option<usize>,
+    /// Maximum estimated size of the in memory data in megabytes.
+    ///
+    /// The in memory data is written to a new folder when it gets bigger than this,
+    /// even if none of the tables reached `max_file_size` rows.
+    /// There is no size limit if not given.
+    pub max_in_memory_mb: Option<usize>,
+    /// Maximum age of the oldest in memory data in seconds.
+    ///
+    /// The in memory data is written to a new folder when the oldest of it was ingested
+    /// longer ago than this, so it isn't kept only in memory for long on quiet chains.
+    /// There is no age limit if not given.
+    pub max_unflushed_age_secs: Option<u64>,
+    /// Sizing of the bloom filters that are built for each folder and row group.
+    ///
+    /// Uses the defaults of `BloomFilterConfig` for the filters that are not configured.
+    #[serde(default)]
+    pub bloom_filters: BloomFilterConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct BloomFilterConfig {
+    /// Filters of `from` and `to` addresses of a transaction row group and
+    /// the contract addresses of a log row group.
+    pub row_group_address: FilterConfig,
+    /// Filters of each topic position of a log row group.
+    pub row_group_topic: FilterConfig,
+    /// Filter of transaction sighashes of a transaction row group.
+    pub row_group_sighash: FilterConfig,
+    /// Filter of all addresses in a folder.
+    pub folder_address: FilterConfig,
+    /// Filters of each topic position in a folder.
+    pub folder_topic: FilterConfig,
+    /// Filter of all transaction sighashes in a folder.
+    pub folder_sighash: FilterConfig,
+}
+
+impl Default for BloomFilterConfig {
+    fn default() -> Self {
+        let row_group = FilterConfig {
+            bits_per_key: 8,
+            max_keys: None,
+        };
+        let folder = FilterConfig {
+            bits_per_key: 8,
+            max_keys: Some(32 * 1024),
+        };
+
+        Self {
+            row_group_address: row_group,
+            row_group_topic: row_group,
+            row_group_sighash: row_group,
+            folder_address: folder,
+            folder_topic: folder,
+            folder_sighash: folder,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct FilterConfig {
+    /// Number of bits allocated for each distinct key.
+    ///
+    /// Higher values give a lower false positive rate in exchange for bigger filters.
+    pub bits_per_key: usize,
+    /// Maximum number of keys the filter is sized for.
+    ///
+    /// The filter still contains all keys if there are more, but the false positive
+    /// rate goes up since they share the space allocated for `max_keys` keys.
+    /// There is no limit if not given.
+    pub max_keys: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -67,4 +288,18 @@ pub struct TableConfig {
 pub struct DbConfig {
     /// Path to the database directory
     pub path: PathBuf,
+    /// Maximum size of the embedded database in gigabytes.
+    ///
+    /// This is the upper bound of the memory map, the database file
+    /// grows in steps of one gigabyte until it reaches this size.
+    /// Defaults to 32 if not given.
+    pub max_size_gb: Option<usize>,
+    /// Build an exact index of the row groups that contain each log address and
+    /// transaction from/to address.
+    ///
+    /// This makes queries that select a few addresses over a long block range skip the
+    /// row groups that bloom filters can't rule out, in exchange for a bigger database.
+    /// Folders that were written while this was disabled are indexed on startup.
+    /// Defaults to false if not given.
+    pub address_index: Option<bool>,
 }
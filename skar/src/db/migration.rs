@@ -0,0 +1,453 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block_range_from_key, block_range_to_key, read_sized, write_sized, BlockRange,
+    BlockRowGroupIndex, BloomFilter, Db, FolderIndex, LogRowGroupIndex, RowGroupIndex,
+    TransactionRowGroupIndex, FOLDER_INDEX_TABLE, FORMAT_VERSION, FORMAT_VERSION_KEY,
+    METADATA_TABLE,
+};
+
+/// Folder index as it was written before offsets were widened to 64 bits.
+#[derive(Serialize, Deserialize)]
+struct FolderIndexV0 {
+    block_range: BlockRange,
+    address_filter: BloomFilter,
+    row_group_index_offset: u32,
+}
+
+/// Folder index as it was written before topic and sighash filters were added.
+#[derive(Serialize, Deserialize)]
+struct FolderIndexV1 {
+    block_range: BlockRange,
+    address_filter: BloomFilter,
+    row_group_index_offset: u64,
+}
+
+/// Row group index as it was written before sighash filters were added.
+#[derive(Serialize, Deserialize)]
+struct RowGroupIndexV1 {
+    block: Vec<BlockRowGroupIndex>,
+    transaction: Vec<TransactionRowGroupIndexV1>,
+    log: Vec<LogRowGroupIndex>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransactionRowGroupIndexV1 {
+    min_block_num: u64,
+    max_block_num: u64,
+    from_address_filter: BloomFilter,
+    to_address_filter: BloomFilter,
+}
+
+/// Rewrites the index files and the mdbx tables from `from_version` to `FORMAT_VERSION`.
+///
+/// The original index files are moved to `*.bak` before anything is written, so
+/// if the migration is interrupted it will be restarted from the backups on next open.
+pub(super) fn migrate(db: &Db, from_version: u32) -> Result<()> {
+    log::info!(
+        "migrating database from format version {} to {}",
+        from_version,
+        FORMAT_VERSION
+    );
+
+    let folder_index_backup = backup_path(&db.folder_index_path);
+    let row_group_index_backup = backup_path(&db.row_group_index_path);
+
+    move_to_backup(&db.folder_index_path, &folder_index_backup)
+        .context("backup folder index file")?;
+    move_to_backup(&db.row_group_index_path, &row_group_index_backup)
+        .context("backup row group index file")?;
+
+    let entries = read_entries(db, from_version).context("read folder index entries")?;
+
+    let new_entries = if entries.is_empty() {
+        Vec::new()
+    } else {
+        let mut folder_index_src =
+            BufReader::new(File::open(&folder_index_backup).context("open folder index backup")?);
+        let mut row_group_index_src = BufReader::new(
+            File::open(&row_group_index_backup).context("open row group index backup")?,
+        );
+
+        write_index_files(
+            &db.folder_index_path,
+            &db.row_group_index_path,
+            entries,
+            |block_range, offset| {
+                folder_index_src
+                    .seek(SeekFrom::Start(offset))
+                    .context("seek to folder index")?;
+                let buf = read_sized(&mut folder_index_src).context("read folder index")?;
+                let (folder_index, old_rg_offset) = decode_folder_index(from_version, &buf)
+                    .with_context(|| format!("decode folder index of {block_range:?}"))?;
+
+                row_group_index_src
+                    .seek(SeekFrom::Start(old_rg_offset))
+                    .context("seek to row group index")?;
+                let buf = read_sized(&mut row_group_index_src).context("read row group index")?;
+                let rg_index = decode_row_group_index(from_version, &buf)
+                    .with_context(|| format!("decode row group index of {block_range:?}"))?;
+
+                Ok((folder_index, rg_index))
+            },
+        )
+        .context("write index files")?
+    };
+
+    let txn = db.env.begin_rw_txn().context("begin read write txn")?;
+
+    let folder_table = txn
+        .create_db(Some(FOLDER_INDEX_TABLE), Default::default())
+        .context("create folder index table")?;
+    let metadata = txn
+        .create_db(Some(METADATA_TABLE), Default::default())
+        .context("create metadata table")?;
+
+    if from_version == 0 {
+        let default_table = txn.open_db(None).context("open default db from txn")?;
+        for (block_range, _) in new_entries.iter() {
+            txn.del(default_table.dbi(), block_range_to_key(*block_range), None)
+                .context("delete legacy folder index entry")?;
+        }
+    }
+
+    for (block_range, offset) in new_entries.iter() {
+        txn.put(
+            folder_table.dbi(),
+            block_range_to_key(*block_range),
+            offset.to_be_bytes(),
+            Default::default(),
+        )
+        .context("write folder idx to mdb")?;
+    }
+
+    txn.put(
+        metadata.dbi(),
+        FORMAT_VERSION_KEY,
+        FORMAT_VERSION.to_be_bytes(),
+        Default::default(),
+    )
+    .context("write format version")?;
+
+    txn.commit().context("commit txn")?;
+
+    remove_if_exists(&folder_index_backup).context("remove folder index backup")?;
+    remove_if_exists(&row_group_index_backup).context("remove row group index backup")?;
+
+    log::info!("finished migrating database");
+
+    Ok(())
+}
+
+/// Writes the indices that `get_indices` returns for each of the folder index table entries
+/// to new index files at the given paths.
+///
+/// Returns the folder index table entries that point into the new files.
+pub(super) fn write_index_files<F>(
+    folder_index_path: &Path,
+    row_group_index_path: &Path,
+    entries: Vec<(BlockRange, u64)>,
+    mut get_indices: F,
+) -> Result<Vec<(BlockRange, u64)>>
+where
+    F: FnMut(BlockRange, u64) -> Result<(FolderIndex, RowGroupIndex)>,
+{
+    let mut folder_index_dst =
+        BufWriter::new(File::create(folder_index_path).context("create folder index file")?);
+    let mut row_group_index_dst =
+        BufWriter::new(File::create(row_group_index_path).context("create row group index file")?);
+
+    let mut new_entries = Vec::with_capacity(entries.len());
+    let mut folder_index_offset = 0;
+    let mut row_group_index_offset = 0;
+
+    for (block_range, offset) in entries {
+        let (mut folder_index, rg_index) = get_indices(block_range, offset)?;
+
+        let rg_index = bincode::serialize(&rg_index).context("serialize rg index")?;
+        write_sized(&mut row_group_index_dst, &rg_index).context("write rg index")?;
+
+        folder_index.row_group_index_offset = row_group_index_offset;
+        row_group_index_offset += rg_index.len() as u64 + 4;
+
+        let folder_index = bincode::serialize(&folder_index).context("serialize folder index")?;
+        write_sized(&mut folder_index_dst, &folder_index).context("write folder index")?;
+
+        new_entries.push((block_range, folder_index_offset));
+        folder_index_offset += folder_index.len() as u64 + 4;
+    }
+
+    sync_writer(folder_index_dst).context("sync folder index file")?;
+    sync_writer(row_group_index_dst).context("sync row group index file")?;
+
+    Ok(new_entries)
+}
+
+pub(super) fn read_entries(db: &Db, version: u32) -> Result<Vec<(BlockRange, u64)>> {
+    let txn = db.env.begin_ro_txn().context("begin read only txn")?;
+
+    let mut entries = Vec::new();
+
+    if version == 0 {
+        let table = txn.open_db(None).context("open default db from txn")?;
+        let mut cursor = txn.cursor(&table).context("open cursor")?;
+
+        let mut entry = cursor
+            .first::<[u8; 16], [u8; 4]>()
+            .context("get first element")?;
+        while let Some((key, offset)) = entry {
+            entries.push((block_range_from_key(key), u32::from_be_bytes(offset).into()));
+            entry = cursor
+                .next::<[u8; 16], [u8; 4]>()
+                .context("get next element")?;
+        }
+    } else {
+        let table = txn
+            .open_db(Some(FOLDER_INDEX_TABLE))
+            .context("open folder index table from txn")?;
+        let mut cursor = txn.cursor(&table).context("open cursor")?;
+
+        let mut entry = cursor
+            .first::<[u8; 16], [u8; 8]>()
+            .context("get first element")?;
+        while let Some((key, offset)) = entry {
+            entries.push((block_range_from_key(key), u64::from_be_bytes(offset)));
+            entry = cursor
+                .next::<[u8; 16], [u8; 8]>()
+                .context("get next element")?;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Decodes a folder index written with the given format version.
+///
+/// Returns the index converted to the current format together with the
+/// offset of its row group index in the old row group index file.
+fn decode_folder_index(version: u32, buf: &[u8]) -> Result<(FolderIndex, u64)> {
+    let (block_range, address_filter, offset) = match version {
+        0 => {
+            let old: FolderIndexV0 = bincode::deserialize(buf).context("deserialize")?;
+            (
+                old.block_range,
+                old.address_filter,
+                old.row_group_index_offset.into(),
+            )
+        }
+        1 => {
+            let old: FolderIndexV1 = bincode::deserialize(buf).context("deserialize")?;
+            (
+                old.block_range,
+                old.address_filter,
+                old.row_group_index_offset,
+            )
+        }
+        FORMAT_VERSION => {
+            let folder_index: FolderIndex = bincode::deserialize(buf).context("deserialize")?;
+            let offset = folder_index.row_group_index_offset;
+            return Ok((folder_index, offset));
+        }
+        _ => return Err(anyhow!("unknown format version {}", version)),
+    };
+
+    Ok((
+        FolderIndex {
+            block_range,
+            address_filter,
+            topic_filters: None,
+            sighash_filter: None,
+            row_group_index_offset: offset,
+        },
+        offset,
+    ))
+}
+
+fn decode_row_group_index(version: u32, buf: &[u8]) -> Result<RowGroupIndex> {
+    match version {
+        0 | 1 => {
+            let old: RowGroupIndexV1 = bincode::deserialize(buf).context("deserialize")?;
+            Ok(RowGroupIndex {
+                block: old.block,
+                transaction: old
+                    .transaction
+                    .into_iter()
+                    .map(|tx| TransactionRowGroupIndex {
+                        min_block_num: tx.min_block_num,
+                        max_block_num: tx.max_block_num,
+                        from_address_filter: tx.from_address_filter,
+                        to_address_filter: tx.to_address_filter,
+                        sighash_filter: None,
+                    })
+                    .collect(),
+                log: old.log,
+            })
+        }
+        FORMAT_VERSION => bincode::deserialize(buf).context("deserialize"),
+        _ => Err(anyhow!("unknown format version {}", version)),
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".bak");
+    path.into()
+}
+
+// Moves the file to the backup path unless a backup is already there, which
+// means a previous migration was interrupted and the backup is the original data.
+fn move_to_backup(path: &Path, backup: &Path) -> Result<()> {
+    if backup.exists() {
+        return Ok(());
+    }
+
+    match fs::rename(path, backup) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow!("failed to rename file: {e}")),
+    }
+}
+
+pub(super) fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow!("failed to remove file: {e}")),
+    }
+}
+
+fn sync_writer(mut writer: BufWriter<File>) -> Result<()> {
+    writer.flush().context("flush writer")?;
+    let file = writer.into_inner().context("get file from writer")?;
+    file.sync_all().context("sync file to disk")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use sbbf_rs_safe::Filter;
+
+    use super::*;
+    use crate::{
+        config::DbConfig,
+        db::{open_env, DEFAULT_MAX_SIZE_GB, GIGABYTE},
+    };
+
+    #[test]
+    fn test_migrate_from_v0() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+
+        fs::create_dir_all(&path).unwrap();
+
+        let rg_index = bincode::serialize(&RowGroupIndexV1 {
+            block: Vec::new(),
+            transaction: vec![TransactionRowGroupIndexV1 {
+                min_block_num: 0,
+                max_block_num: 9,
+                from_address_filter: BloomFilter(Filter::new(8, 100)),
+                to_address_filter: BloomFilter(Filter::new(8, 100)),
+            }],
+            log: Vec::new(),
+        })
+        .unwrap();
+
+        let mut rg_index_file = Vec::new();
+        write_sized(&mut rg_index_file, &rg_index).unwrap();
+        write_sized(&mut rg_index_file, &rg_index).unwrap();
+        fs::write(path.join("row_group_index.bin"), rg_index_file).unwrap();
+
+        let mut folder_index_file = Vec::new();
+        let mut offsets = Vec::new();
+        for (block_range, rg_offset) in [
+            (BlockRange(0, 10), 0),
+            (BlockRange(10, 20), rg_index.len() + 4),
+        ] {
+            let folder_index = bincode::serialize(&FolderIndexV0 {
+                block_range,
+                address_filter: BloomFilter(Filter::new(8, 100)),
+                row_group_index_offset: rg_offset.try_into().unwrap(),
+            })
+            .unwrap();
+            offsets.push((block_range, folder_index_file.len() as u32));
+            write_sized(&mut folder_index_file, &folder_index).unwrap();
+        }
+        fs::write(path.join("folder_index.bin"), folder_index_file).unwrap();
+
+        {
+            let env = open_env(&path.join("mdbx"), DEFAULT_MAX_SIZE_GB * GIGABYTE).unwrap();
+            let txn = env.begin_rw_txn().unwrap();
+            let table = txn.open_db(None).unwrap();
+            for (block_range, offset) in offsets {
+                txn.put(
+                    table.dbi(),
+                    block_range_to_key(block_range),
+                    offset.to_be_bytes(),
+                    Default::default(),
+                )
+                .unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        let db = Db::new(&DbConfig {
+            path: path.clone(),
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+
+        assert_eq!(db.format_version().unwrap(), Some(FORMAT_VERSION));
+        assert!(!path.join("folder_index.bin.bak").exists());
+
+        let mut iter = db
+            .iterate_folder_indices(BlockRange(0, u64::MAX))
+            .unwrap()
+            .unwrap();
+        let folder_indices = iter.by_ref().map(|i| i.unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(
+            folder_indices
+                .iter()
+                .map(|i| i.block_range)
+                .collect::<Vec<_>>(),
+            vec![BlockRange(0, 10), BlockRange(10, 20)]
+        );
+        assert!(folder_indices[0].topic_filters.is_none());
+        assert!(folder_indices[0].sighash_filter.is_none());
+        assert_eq!(folder_indices[0].row_group_index_offset, 0);
+
+        let rg_index = iter
+            .read_row_group_index(folder_indices[1].row_group_index_offset)
+            .unwrap();
+        assert_eq!(rg_index.log.len(), 0);
+        assert_eq!(rg_index.transaction.len(), 1);
+        assert!(rg_index.transaction[0].sighash_filter.is_none());
+
+        drop(iter);
+        drop(db);
+
+        // opening again shouldn't run the migration again
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+        let folder_indices = db
+            .iterate_folder_indices(BlockRange(0, u64::MAX))
+            .unwrap()
+            .unwrap()
+            .map(|i| i.unwrap().block_range)
+            .collect::<Vec<_>>();
+        assert_eq!(folder_indices, vec![BlockRange(0, 10), BlockRange(10, 20)]);
+    }
+}
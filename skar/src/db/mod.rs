@@ -1,23 +1,60 @@
 use std::{
     cmp,
+    collections::BTreeMap,
     fs::File,
     io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Context, Result};
-use reth_libmdbx::{Environment, EnvironmentFlags, Geometry, Mode, NoWriteMap, PageSize, SyncMode};
+use reth_libmdbx::{
+    Environment, EnvironmentFlags, Error as MdbxError, Geometry, Mode, NoWriteMap, PageSize,
+    SyncMode, Transaction, TransactionKind, RW,
+};
 
 mod bloom_filter;
+mod migration;
+mod rebuild;
 mod types;
 
 pub use bloom_filter::BloomFilter;
 pub use types::{
-    BlockRange, BlockRowGroupIndex, FolderIndex, LogRowGroupIndex, RowGroupIndex,
-    TransactionRowGroupIndex,
+    AddressIndex, AddressKind, ApiKeyUsage, BlockRange, BlockRowGroupIndex, FolderIndex, HashIndex,
+    LogRowGroupIndex, RowGroupIndex, TransactionRowGroupIndex,
+};
+
+use crate::{
+    config::DbConfig,
+    open_file_reader::{open_file, open_file_reader},
 };
 
-use crate::open_file_reader::{open_file, open_file_reader};
+/// Version of the on-disk format of the database.
+///
+/// This should be bumped whenever the layout of the mdbx tables or the
+/// encoding of the index files change. A migration from the previous
+/// version should be added to the `migration` module at the same time.
+const FORMAT_VERSION: u32 = 2;
+
+const FOLDER_INDEX_TABLE: &str = "folder_index";
+const METADATA_TABLE: &str = "metadata";
+/// transaction hash -> (block_number, transaction_index)
+const TX_HASH_TABLE: &str = "tx_hash";
+/// block hash -> block_number
+const BLOCK_HASH_TABLE: &str = "block_hash";
+/// (address, folder to_block) -> (folder from_block, log row group indices)
+const LOG_ADDRESS_TABLE: &str = "log_address";
+/// (address, folder to_block) -> (folder from_block, transaction row group indices)
+const TX_FROM_ADDRESS_TABLE: &str = "tx_from_address";
+/// (address, folder to_block) -> (folder from_block, transaction row group indices)
+const TX_TO_ADDRESS_TABLE: &str = "tx_to_address";
+/// api key name -> usage of the key on its last active day
+const API_KEY_USAGE_TABLE: &str = "api_key_usage";
+
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+/// The block number that the hash tables are filled up to (exclusive).
+const HASH_INDEX_NEXT_BLOCK_KEY: &[u8] = b"hash_index_next_block";
+/// The block number that the address index tables are filled up to (exclusive).
+const ADDRESS_INDEX_NEXT_BLOCK_KEY: &[u8] = b"address_index_next_block";
 
 pub struct Db {
     env: Environment<NoWriteMap>,
@@ -26,30 +63,13 @@ pub struct Db {
 }
 
 impl Db {
-    pub fn new(path: &Path) -> Result<Self> {
-        let mut env = Environment::new();
-
-        env.set_geometry(Geometry {
-            size: Some(0..(32 * GIGABYTE)),
-            growth_step: Some(GIGABYTE as isize),
-            // The database never shrinks
-            shrink_threshold: None,
-            page_size: Some(PageSize::Set(default_page_size())),
-        });
-        env.set_flags(EnvironmentFlags {
-            mode: Mode::ReadWrite {
-                sync_mode: SyncMode::Durable,
-            },
-            no_rdahead: false,
-            coalesce: true,
-            ..Default::default()
-        });
-        env.set_max_readers(DEFAULT_MAX_READERS);
+    pub fn new(cfg: &DbConfig) -> Result<Self> {
+        let max_size = cfg.max_size_gb.unwrap_or(DEFAULT_MAX_SIZE_GB) * GIGABYTE;
 
-        let mut path = path.to_owned();
+        let mut path = cfg.path.clone();
 
         path.push("mdbx");
-        let env = env.open(&path).context("open mdbx database")?;
+        let env = open_env(&path, max_size).context("open mdbx database")?;
         path.pop();
 
         let mut folder_index_path = path.clone();
@@ -58,22 +78,122 @@ impl Db {
         let mut row_group_index_path = path.clone();
         row_group_index_path.push("row_group_index.bin");
 
-        Ok(Self {
+        let db = Self {
             env,
             folder_index_path,
             row_group_index_path,
-        })
+        };
+
+        db.init().context("initialize db")?;
+
+        Ok(db)
+    }
+
+    fn init(&self) -> Result<()> {
+        let version = match self.format_version().context("read format version")? {
+            Some(version) => Some(version),
+            None if self.has_legacy_data().context("check for legacy data")? => Some(0),
+            None => None,
+        };
+
+        if let Some(version) = version {
+            if version > FORMAT_VERSION {
+                return Err(anyhow!(
+                    "database format version ({}) is newer than the supported version ({})",
+                    version,
+                    FORMAT_VERSION
+                ));
+            }
+
+            if version < FORMAT_VERSION {
+                migration::migrate(self, version).context("migrate database")?;
+            }
+        }
+
+        // This also creates the tables that were added without changing the format
+        // of the existing ones, so older databases get them on first open.
+        self.create_tables().context("create tables")?;
+
+        rebuild::finish(self).context("finish interrupted index rebuild")
+    }
+
+    fn format_version(&self) -> Result<Option<u32>> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = match txn.open_db(Some(METADATA_TABLE)) {
+            Ok(db) => db,
+            Err(MdbxError::NotFound) => return Ok(None),
+            Err(e) => return Err(anyhow!("failed to open metadata table: {e}")),
+        };
+
+        let version = txn
+            .get::<[u8; 4]>(db.dbi(), FORMAT_VERSION_KEY)
+            .context("get format version")?;
+
+        Ok(version.map(u32::from_be_bytes))
+    }
+
+    // Databases created before the format version was introduced keep
+    // the folder index entries in the default table.
+    fn has_legacy_data(&self) -> Result<bool> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn.open_db(None).context("open default db from txn")?;
+
+        let mut cursor = txn.cursor(&db).context("open cursor")?;
+
+        let first = cursor
+            .first::<[u8; 16], [u8; 4]>()
+            .context("get first element from db")?;
+
+        Ok(first.is_some())
+    }
+
+    fn create_tables(&self) -> Result<()> {
+        let txn = self.env.begin_rw_txn().context("begin read write txn")?;
+
+        txn.create_db(Some(FOLDER_INDEX_TABLE), Default::default())
+            .context("create folder index table")?;
+        txn.create_db(Some(TX_HASH_TABLE), Default::default())
+            .context("create tx hash table")?;
+        txn.create_db(Some(BLOCK_HASH_TABLE), Default::default())
+            .context("create block hash table")?;
+        for kind in [
+            AddressKind::Log,
+            AddressKind::TransactionFrom,
+            AddressKind::TransactionTo,
+        ] {
+            txn.create_db(Some(address_table(kind)), Default::default())
+                .context("create address index table")?;
+        }
+        txn.create_db(Some(API_KEY_USAGE_TABLE), Default::default())
+            .context("create api key usage table")?;
+        let metadata = txn
+            .create_db(Some(METADATA_TABLE), Default::default())
+            .context("create metadata table")?;
+
+        txn.put(
+            metadata.dbi(),
+            FORMAT_VERSION_KEY,
+            FORMAT_VERSION.to_be_bytes(),
+            Default::default(),
+        )
+        .context("write format version")?;
+
+        txn.commit().context("commit txn")?;
+
+        Ok(())
     }
 
     pub async fn next_block_num(&self) -> Result<u64> {
         tokio::task::block_in_place(|| {
             let txn = self.env.begin_ro_txn().context("begin read only txn")?;
-            let db = txn.open_db(None).context("open default db from txn")?;
+            let db = txn
+                .open_db(Some(FOLDER_INDEX_TABLE))
+                .context("open folder index table from txn")?;
 
             let mut cursor = txn.cursor(&db).context("open cursor")?;
 
             let last = cursor
-                .last::<[u8; 16], [u8; 4]>()
+                .last::<[u8; 16], [u8; 8]>()
                 .context("get last element from db")?;
 
             let last = match last {
@@ -85,26 +205,80 @@ impl Db {
         })
     }
 
+    /// Returns the number of folders that overlap the given block range.
+    pub fn count_folders(&self, block_range: BlockRange) -> Result<usize> {
+        if block_range.0 >= block_range.1 {
+            return Ok(0);
+        }
+
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn
+            .open_db(Some(FOLDER_INDEX_TABLE))
+            .context("open folder index table from txn")?;
+
+        let key = block_range_to_key(BlockRange(block_range.0, 0));
+
+        // The folder that contains the start of the range is the last one that starts before it.
+        let mut cursor = txn.cursor(&db).context("open cursor")?;
+        let before = match cursor
+            .set_range::<[u8; 16], [u8; 8]>(&key)
+            .context("get start pos")?
+        {
+            Some(_) => cursor.prev::<[u8; 16], [u8; 8]>(),
+            None => cursor.last::<[u8; 16], [u8; 8]>(),
+        }
+        .context("get folder before start pos")?;
+
+        let mut count = match before {
+            Some((range, _)) if block_range_from_key(range).1 > block_range.0 => 1,
+            _ => 0,
+        };
+
+        let mut cursor = txn.cursor(&db).context("open cursor")?;
+        let mut item = cursor
+            .set_range::<[u8; 16], [u8; 8]>(&key)
+            .context("get start pos")?;
+        while let Some((range, _)) = item {
+            if block_range_from_key(range).0 >= block_range.1 {
+                break;
+            }
+            count += 1;
+            item = cursor
+                .next::<[u8; 16], [u8; 8]>()
+                .context("get next folder")?;
+        }
+
+        Ok(count)
+    }
+
     pub async fn insert_folder_index(
         &self,
         folder_index: FolderIndex,
         rg_index: RowGroupIndex,
+        hash_index: HashIndex,
+        address_index: Option<AddressIndex>,
     ) -> Result<()> {
-        tokio::task::block_in_place(|| self.insert_folder_index_impl(folder_index, rg_index))
+        tokio::task::block_in_place(|| {
+            self.insert_folder_index_impl(folder_index, rg_index, hash_index, address_index)
+        })
     }
 
     fn insert_folder_index_impl(
         &self,
         folder_index: FolderIndex,
         rg_index: RowGroupIndex,
+        hash_index: HashIndex,
+        address_index: Option<AddressIndex>,
     ) -> Result<()> {
         let txn = self.env.begin_rw_txn().context("begin read write txn")?;
-        let db = txn.open_db(None).context("open default db from txn")?;
+        let db = txn
+            .open_db(Some(FOLDER_INDEX_TABLE))
+            .context("open folder index table from txn")?;
 
         let mut cursor = txn.cursor(&db).context("open cursor")?;
 
         let last = cursor
-            .last::<[u8; 16], [u8; 4]>()
+            .last::<[u8; 16], [u8; 8]>()
             .context("get last element from db")?;
 
         let offset = match last {
@@ -119,7 +293,7 @@ impl Db {
                     ));
                 }
 
-                Some(u32::from_be_bytes(offset))
+                Some(u64::from_be_bytes(offset))
             }
             None => {
                 if folder_index.block_range.0 != 0 {
@@ -143,7 +317,7 @@ impl Db {
         let row_group_index_offset = match offset {
             Some(offset) => {
                 folder_index_f
-                    .seek(SeekFrom::Start(offset.into()))
+                    .seek(SeekFrom::Start(offset))
                     .context("seek to tip offset")?;
                 let fidx =
                     read_folder_index(&mut folder_index_f).context("read tip folder index")?;
@@ -163,42 +337,31 @@ impl Db {
 
         if let Some(row_group_index_offset) = row_group_index_offset {
             rg_index_f
-                .seek(SeekFrom::Start(row_group_index_offset.into()))
+                .seek(SeekFrom::Start(row_group_index_offset))
                 .context("seek to rg tip offset")?;
             let size = read_size(&mut rg_index_f).context("read rg idx size")?;
 
-            folder_index.row_group_index_offset = row_group_index_offset + size + 4;
+            folder_index.row_group_index_offset = row_group_index_offset + u64::from(size) + 4;
         } else {
             folder_index.row_group_index_offset = 0;
         }
 
         rg_index_f
-            .seek(SeekFrom::Start(folder_index.row_group_index_offset.into()))
+            .seek(SeekFrom::Start(folder_index.row_group_index_offset))
             .context("seek to tip of rg index file")?;
 
         let rg_index = bincode::serialize(&rg_index).context("serialize rg index")?;
-        let size: u32 = rg_index.len().try_into().unwrap();
-        rg_index_f
-            .write_all(&size.to_be_bytes())
-            .context("write size of rg index")?;
-        rg_index_f.write_all(&rg_index).context("write rg index")?;
+        write_sized(&mut rg_index_f, &rg_index).context("write rg index")?;
         rg_index_f.flush().context("sync file to disk")?;
 
         let block_range = folder_index.block_range;
         let folder_index = bincode::serialize(&folder_index).context("serialize folder index")?;
-        let size: u32 = folder_index.len().try_into().unwrap();
 
         let offset = folder_index_f
             .stream_position()
             .context("get folder idx offset")?;
-        let offset: u32 = offset.try_into().unwrap();
-
-        folder_index_f
-            .write_all(&size.to_be_bytes())
-            .context("write size of index")?;
-        folder_index_f
-            .write_all(&folder_index)
-            .context("write folder index")?;
+
+        write_sized(&mut folder_index_f, &folder_index).context("write folder index")?;
         folder_index_f.flush().context("sync file to disk")?;
 
         txn.put(
@@ -209,17 +372,242 @@ impl Db {
         )
         .context("write folder idx to mdb")?;
 
+        // Hash tables of an older database are backfilled separately, only extend
+        // them here if they are already filled up to this folder.
+        if read_next_block(&txn, HASH_INDEX_NEXT_BLOCK_KEY).context("read hash index next block")?
+            == block_range.0
+        {
+            write_hash_index(&txn, block_range, &hash_index).context("write hash index")?;
+        }
+
+        // Same as the hash tables, the address index is only extended if it is enabled
+        // and it is filled up to this folder.
+        if let Some(address_index) = address_index {
+            if read_next_block(&txn, ADDRESS_INDEX_NEXT_BLOCK_KEY)
+                .context("read address index next block")?
+                == block_range.0
+            {
+                write_address_index(&txn, block_range, &address_index)
+                    .context("write address index")?;
+            }
+        }
+
         txn.commit().context("commit txn")?;
 
         Ok(())
     }
 
+    /// Returns the block number that the hash lookup tables are filled up to (exclusive).
+    pub async fn hash_index_next_block(&self) -> Result<u64> {
+        tokio::task::block_in_place(|| {
+            let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+            read_next_block(&txn, HASH_INDEX_NEXT_BLOCK_KEY)
+        })
+    }
+
+    /// Fills the hash lookup tables for the given folder.
+    ///
+    /// This is used to backfill the tables for folders that were written before the tables existed.
+    pub async fn insert_hash_index(
+        &self,
+        block_range: BlockRange,
+        hash_index: HashIndex,
+    ) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let txn = self.env.begin_rw_txn().context("begin read write txn")?;
+
+            let next_block = read_next_block(&txn, HASH_INDEX_NEXT_BLOCK_KEY)
+                .context("read hash index next block")?;
+            if next_block != block_range.0 {
+                return Err(anyhow!(
+                    "hash index next block ({}) and folder_index.from ({}) don't match",
+                    next_block,
+                    block_range.0
+                ));
+            }
+
+            write_hash_index(&txn, block_range, &hash_index).context("write hash index")?;
+
+            txn.commit().context("commit txn")?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the block number that the address index tables are filled up to (exclusive).
+    pub fn address_index_next_block(&self) -> Result<u64> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        read_next_block(&txn, ADDRESS_INDEX_NEXT_BLOCK_KEY)
+    }
+
+    /// Fills the address index tables for the given folder.
+    ///
+    /// This is used to backfill the tables for folders that were written before the address index was enabled.
+    pub async fn insert_address_index(
+        &self,
+        block_range: BlockRange,
+        address_index: AddressIndex,
+    ) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let txn = self.env.begin_rw_txn().context("begin read write txn")?;
+
+            let next_block = read_next_block(&txn, ADDRESS_INDEX_NEXT_BLOCK_KEY)
+                .context("read address index next block")?;
+            if next_block != block_range.0 {
+                return Err(anyhow!(
+                    "address index next block ({}) and folder_index.from ({}) don't match",
+                    next_block,
+                    block_range.0
+                ));
+            }
+
+            write_address_index(&txn, block_range, &address_index)
+                .context("write address index")?;
+
+            txn.commit().context("commit txn")?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the indices of the row groups that contain the address, for each folder that overlaps the block range.
+    ///
+    /// The returned map is keyed by the first block of the folder.
+    pub fn address_postings(
+        &self,
+        kind: AddressKind,
+        address: &[u8],
+        block_range: BlockRange,
+    ) -> Result<BTreeMap<u64, Vec<u32>>> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn
+            .open_db(Some(address_table(kind)))
+            .context("open address index table from txn")?;
+
+        let mut cursor = txn.cursor(&db).context("open cursor")?;
+
+        let mut postings = BTreeMap::new();
+
+        // Keys are suffixed with the end of the folder, so this seeks to the first
+        // folder that ends after block_range.0
+        let start_key = address_key(address, block_range.0 + 1);
+
+        let mut entry = cursor
+            .set_range::<Vec<u8>, Vec<u8>>(&start_key)
+            .context("seek to start key")?;
+        while let Some((key, value)) = entry {
+            if key.len() != start_key.len() || !key.starts_with(address) {
+                break;
+            }
+
+            let folder_from = u64::from_be_bytes(value[..8].try_into().unwrap());
+            if folder_from >= block_range.1 {
+                break;
+            }
+
+            let row_groups = value[8..]
+                .chunks_exact(4)
+                .map(|rg| u32::from_be_bytes(rg.try_into().unwrap()))
+                .collect();
+            postings.insert(folder_from, row_groups);
+
+            entry = cursor
+                .next::<Vec<u8>, Vec<u8>>()
+                .context("get next element")?;
+        }
+
+        Ok(postings)
+    }
+
+    /// Replaces the folder and row group indices of all folders with the ones returned by `build`.
+    ///
+    /// This is meant to be run offline, the indices must not be read or inserted while it is running.
+    pub async fn rebuild_indices<F>(&self, build: F) -> Result<()>
+    where
+        F: FnMut(BlockRange) -> Result<(FolderIndex, RowGroupIndex)>,
+    {
+        tokio::task::block_in_place(|| rebuild::rebuild(self, build))
+    }
+
+    /// Returns (block_number, transaction_index) of the transaction with the given hash.
+    pub fn get_transaction_location(&self, hash: &[u8]) -> Result<Option<(u64, u64)>> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn
+            .open_db(Some(TX_HASH_TABLE))
+            .context("open tx hash table from txn")?;
+
+        let location = txn
+            .get::<[u8; 16]>(db.dbi(), hash)
+            .context("get tx location")?;
+
+        Ok(location.map(|location| {
+            (
+                u64::from_be_bytes(location[..8].try_into().unwrap()),
+                u64::from_be_bytes(location[8..].try_into().unwrap()),
+            )
+        }))
+    }
+
+    /// Returns the usage of the api key with the given name.
+    pub fn get_api_key_usage(&self, name: &str) -> Result<Option<ApiKeyUsage>> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn
+            .open_db(Some(API_KEY_USAGE_TABLE))
+            .context("open api key usage table from txn")?;
+
+        let usage = txn
+            .get::<[u8; ApiKeyUsage::ENCODED_LEN]>(db.dbi(), name.as_bytes())
+            .context("get api key usage")?;
+
+        Ok(usage.map(|usage| ApiKeyUsage::decode(&usage)))
+    }
+
+    /// Overwrites the usage of the given api keys.
+    pub async fn write_api_key_usage(&self, usage: &[(String, ApiKeyUsage)]) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let txn = self.env.begin_rw_txn().context("begin read write txn")?;
+            let db = txn
+                .open_db(Some(API_KEY_USAGE_TABLE))
+                .context("open api key usage table from txn")?;
+
+            for (name, usage) in usage {
+                txn.put(
+                    db.dbi(),
+                    name.as_bytes(),
+                    usage.encode(),
+                    Default::default(),
+                )
+                .context("write api key usage")?;
+            }
+
+            txn.commit().context("commit txn")?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the number of the block with the given hash.
+    pub fn get_block_number(&self, hash: &[u8]) -> Result<Option<u64>> {
+        let txn = self.env.begin_ro_txn().context("begin read only txn")?;
+        let db = txn
+            .open_db(Some(BLOCK_HASH_TABLE))
+            .context("open block hash table from txn")?;
+
+        let block_number = txn
+            .get::<[u8; 8]>(db.dbi(), hash)
+            .context("get block number")?;
+
+        Ok(block_number.map(u64::from_be_bytes))
+    }
+
     pub fn iterate_folder_indices(
         &self,
         block_range: BlockRange,
     ) -> Result<Option<FolderIndexIterator>> {
         let txn = self.env.begin_ro_txn().context("begin read only txn")?;
-        let db = txn.open_db(None).context("open default db from txn")?;
+        let db = txn
+            .open_db(Some(FOLDER_INDEX_TABLE))
+            .context("open folder index table from txn")?;
 
         let mut folder_index = match open_file(&self.folder_index_path) {
             Ok(folder_index) => BufReader::new(folder_index),
@@ -238,7 +626,7 @@ impl Db {
         let mut cursor = txn.cursor(&db).context("open cursor")?;
 
         let last = cursor
-            .last::<[u8; 16], [u8; 4]>()
+            .last::<[u8; 16], [u8; 8]>()
             .context("get last element from db")?;
 
         let last = match last {
@@ -251,7 +639,7 @@ impl Db {
         let key = block_range_to_key(BlockRange(block_range.0, 0));
 
         let offset = if let Some((range, offset)) = cursor
-            .set_range::<[u8; 16], [u8; 4]>(&key)
+            .set_range::<[u8; 16], [u8; 8]>(&key)
             .context("get start pos")?
         {
             let range = block_range_from_key(range);
@@ -261,7 +649,7 @@ impl Db {
                     return Ok(None);
                 }
 
-                Some(u32::from_be_bytes(offset))
+                Some(u64::from_be_bytes(offset))
             } else {
                 None
             }
@@ -271,7 +659,7 @@ impl Db {
 
         let offset = match offset {
             None => match cursor
-                .prev::<[u8; 16], [u8; 4]>()
+                .prev::<[u8; 16], [u8; 8]>()
                 .context("get start pos")?
             {
                 Some((range, offset)) => {
@@ -281,7 +669,7 @@ impl Db {
                         return Ok(None);
                     }
 
-                    u32::from_be_bytes(offset)
+                    u64::from_be_bytes(offset)
                 }
                 None => {
                     return Ok(None);
@@ -291,7 +679,7 @@ impl Db {
         };
 
         folder_index
-            .seek(SeekFrom::Start(offset as u64))
+            .seek(SeekFrom::Start(offset))
             .context("seek to the start of the folder index")?;
 
         let to_block = cmp::min(block_range.1, last);
@@ -305,16 +693,181 @@ impl Db {
     }
 }
 
+fn open_env(path: &Path, max_size: usize) -> Result<Environment<NoWriteMap>> {
+    let mut env = Environment::new();
+
+    env.set_geometry(Geometry {
+        size: Some(0..max_size),
+        growth_step: Some(GIGABYTE as isize),
+        // The database never shrinks
+        shrink_threshold: None,
+        page_size: Some(PageSize::Set(default_page_size())),
+    });
+    env.set_flags(EnvironmentFlags {
+        mode: Mode::ReadWrite {
+            sync_mode: SyncMode::Durable,
+        },
+        no_rdahead: false,
+        coalesce: true,
+        ..Default::default()
+    });
+    env.set_max_readers(DEFAULT_MAX_READERS);
+    env.set_max_dbs(MAX_TABLES);
+
+    env.open(path).context("open environment")
+}
+
+fn read_next_block<K: TransactionKind>(
+    txn: &Transaction<'_, K, NoWriteMap>,
+    key: &[u8],
+) -> Result<u64> {
+    let metadata = txn
+        .open_db(Some(METADATA_TABLE))
+        .context("open metadata table from txn")?;
+
+    let next_block = txn
+        .get::<[u8; 8]>(metadata.dbi(), key)
+        .context("get next block")?;
+
+    Ok(next_block.map(u64::from_be_bytes).unwrap_or(0))
+}
+
+fn write_hash_index(
+    txn: &Transaction<'_, RW, NoWriteMap>,
+    block_range: BlockRange,
+    hash_index: &HashIndex,
+) -> Result<()> {
+    let tx_hash = txn
+        .open_db(Some(TX_HASH_TABLE))
+        .context("open tx hash table from txn")?;
+    let block_hash = txn
+        .open_db(Some(BLOCK_HASH_TABLE))
+        .context("open block hash table from txn")?;
+    let metadata = txn
+        .open_db(Some(METADATA_TABLE))
+        .context("open metadata table from txn")?;
+
+    for (hash, block_number, tx_index) in hash_index.transactions.iter() {
+        let mut location = [0; 16];
+        location[..8].copy_from_slice(&block_number.to_be_bytes());
+        location[8..].copy_from_slice(&tx_index.to_be_bytes());
+
+        txn.put(tx_hash.dbi(), hash, location, Default::default())
+            .context("write tx location")?;
+    }
+
+    for (hash, block_number) in hash_index.blocks.iter() {
+        txn.put(
+            block_hash.dbi(),
+            hash,
+            block_number.to_be_bytes(),
+            Default::default(),
+        )
+        .context("write block number")?;
+    }
+
+    txn.put(
+        metadata.dbi(),
+        HASH_INDEX_NEXT_BLOCK_KEY,
+        block_range.1.to_be_bytes(),
+        Default::default(),
+    )
+    .context("write hash index next block")?;
+
+    Ok(())
+}
+
+fn write_address_index(
+    txn: &Transaction<'_, RW, NoWriteMap>,
+    block_range: BlockRange,
+    address_index: &AddressIndex,
+) -> Result<()> {
+    let metadata = txn
+        .open_db(Some(METADATA_TABLE))
+        .context("open metadata table from txn")?;
+
+    for (kind, postings) in [
+        (AddressKind::Log, &address_index.log),
+        (
+            AddressKind::TransactionFrom,
+            &address_index.transaction_from,
+        ),
+        (AddressKind::TransactionTo, &address_index.transaction_to),
+    ] {
+        let table = txn
+            .open_db(Some(address_table(kind)))
+            .context("open address index table from txn")?;
+
+        for (address, row_groups) in postings.iter() {
+            let mut value = Vec::with_capacity(8 + row_groups.len() * 4);
+            value.extend_from_slice(&block_range.0.to_be_bytes());
+            for rg in row_groups.iter() {
+                value.extend_from_slice(&rg.to_be_bytes());
+            }
+
+            txn.put(
+                table.dbi(),
+                address_key(address, block_range.1),
+                value,
+                Default::default(),
+            )
+            .context("write address postings")?;
+        }
+    }
+
+    txn.put(
+        metadata.dbi(),
+        ADDRESS_INDEX_NEXT_BLOCK_KEY,
+        block_range.1.to_be_bytes(),
+        Default::default(),
+    )
+    .context("write address index next block")?;
+
+    Ok(())
+}
+
+fn address_table(kind: AddressKind) -> &'static str {
+    match kind {
+        AddressKind::Log => LOG_ADDRESS_TABLE,
+        AddressKind::TransactionFrom => TX_FROM_ADDRESS_TABLE,
+        AddressKind::TransactionTo => TX_TO_ADDRESS_TABLE,
+    }
+}
+
+fn address_key(address: &[u8], to_block: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(address.len() + 8);
+    key.extend_from_slice(address);
+    key.extend_from_slice(&to_block.to_be_bytes());
+    key
+}
+
 fn read_size<R: Read>(reader: &mut R) -> Result<u32> {
     let mut size = [0u8; 4];
     reader.read_exact(&mut size).context("read size")?;
     Ok(u32::from_be_bytes(size))
 }
 
-fn read_folder_index<R: Read>(reader: &mut R) -> Result<FolderIndex> {
-    let size = read_size(reader).context("read size of folder index")?;
+fn read_sized<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let size = read_size(reader)?;
     let mut buf = vec![0u8; size.try_into().unwrap()];
-    reader.read_exact(&mut buf).context("read folder index")?;
+    reader.read_exact(&mut buf).context("read data")?;
+    Ok(buf)
+}
+
+fn write_sized<W: Write>(writer: &mut W, data: &[u8]) -> Result<()> {
+    let size: u32 = data
+        .len()
+        .try_into()
+        .context("data is too big to be prefixed with u32 size")?;
+    writer
+        .write_all(&size.to_be_bytes())
+        .context("write size")?;
+    writer.write_all(data).context("write data")?;
+    Ok(())
+}
+
+fn read_folder_index<R: Read>(reader: &mut R) -> Result<FolderIndex> {
+    let buf = read_sized(reader).context("read folder index")?;
     bincode::deserialize(&buf).context("deserialize folder index")
 }
 
@@ -326,16 +879,11 @@ pub struct FolderIndexIterator {
 }
 
 impl FolderIndexIterator {
-    pub fn read_row_group_index(&mut self, offset: u32) -> Result<RowGroupIndex> {
+    pub fn read_row_group_index(&mut self, offset: u64) -> Result<RowGroupIndex> {
         self.row_group_index
-            .seek(SeekFrom::Start(offset.into()))
+            .seek(SeekFrom::Start(offset))
             .context("seek to offset")?;
-        let size = read_size(&mut self.row_group_index).context("read size of row group index")?;
-
-        let mut buf = vec![0u8; size.try_into().unwrap()];
-        self.row_group_index
-            .read_exact(&mut buf)
-            .context("read folder index")?;
+        let buf = read_sized(&mut self.row_group_index).context("read row group index")?;
 
         bincode::deserialize(&buf).context("deserialize row group index")
     }
@@ -377,6 +925,11 @@ pub(crate) fn default_page_size() -> usize {
 /// MDBX allows up to 32767 readers (`MDBX_READERS_LIMIT`), but we limit it to slightly below that
 const DEFAULT_MAX_READERS: u64 = 32_000;
 
+/// Maximum number of named tables in the environment
+const MAX_TABLES: usize = 16;
+
+const DEFAULT_MAX_SIZE_GB: usize = 32;
+
 const GIGABYTE: usize = 1024 * 1024 * 1024;
 
 fn block_range_to_key(block_range: BlockRange) -> [u8; 16] {
@@ -403,31 +956,24 @@ mod tests {
 
     #[test]
     fn test_iter() {
-        let mut tmp = temp_dir();
-        tmp.push(format!("{}", uuid::Uuid::new_v4()));
-
-        let folder_index_path = tmp.clone();
-
-        tmp.pop();
-        tmp.push(format!("{}", uuid::Uuid::new_v4()));
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
 
-        let row_group_index_path = tmp.clone();
+        std::fs::create_dir_all(&path).unwrap();
 
-        tmp.pop();
-        tmp.push(format!("{}", uuid::Uuid::new_v4()));
-
-        let db_path = tmp;
-
-        let db = Db {
-            folder_index_path,
-            row_group_index_path,
-            env: Environment::new().open(&db_path).unwrap(),
-        };
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
 
         let err_res = db.insert_folder_index_impl(
             FolderIndex {
                 block_range: BlockRange(1, 123456),
                 address_filter: BloomFilter(Filter::new(8, 10000)),
+                topic_filters: None,
+                sighash_filter: None,
                 row_group_index_offset: 0,
             },
             RowGroupIndex {
@@ -435,6 +981,8 @@ mod tests {
                 transaction: Vec::new(),
                 log: Vec::new(),
             },
+            HashIndex::default(),
+            None,
         );
 
         assert!(err_res.is_err());
@@ -443,6 +991,8 @@ mod tests {
             FolderIndex {
                 block_range: BlockRange(0, 123456),
                 address_filter: BloomFilter(Filter::new(8, 10000)),
+                topic_filters: None,
+                sighash_filter: None,
                 row_group_index_offset: 0,
             },
             RowGroupIndex {
@@ -450,6 +1000,8 @@ mod tests {
                 transaction: Vec::new(),
                 log: Vec::new(),
             },
+            HashIndex::default(),
+            None,
         )
         .unwrap();
 
@@ -489,6 +1041,8 @@ mod tests {
             FolderIndex {
                 block_range: BlockRange(123456, 1234567),
                 address_filter: BloomFilter(Filter::new(8, 10000)),
+                topic_filters: None,
+                sighash_filter: None,
                 row_group_index_offset: 0,
             },
             RowGroupIndex {
@@ -496,6 +1050,8 @@ mod tests {
                 transaction: Vec::new(),
                 log: Vec::new(),
             },
+            HashIndex::default(),
+            None,
         )
         .unwrap();
 
@@ -526,6 +1082,12 @@ mod tests {
             vec![BlockRange(0, 123456), BlockRange(123456, 1234567)]
         );
 
+        assert_eq!(db.count_folders(BlockRange(0, u64::MAX)).unwrap(), 2);
+        assert_eq!(db.count_folders(BlockRange(1, 123456)).unwrap(), 1);
+        assert_eq!(db.count_folders(BlockRange(123455, 123457)).unwrap(), 2);
+        assert_eq!(db.count_folders(BlockRange(1234567, u64::MAX)).unwrap(), 0);
+        assert_eq!(db.count_folders(BlockRange(5, 5)).unwrap(), 0);
+
         let indices = db
             .iterate_folder_indices(BlockRange(1, 123))
             .unwrap()
@@ -559,8 +1121,202 @@ mod tests {
         assert_eq!(folder_indices.len(), 2);
         assert_eq!(folder_indices[0].row_group_index_offset, 0);
         assert_eq!(
-            folder_indices[1].row_group_index_offset as usize,
-            len_rg_index_first.len() + 4
+            folder_indices[1].row_group_index_offset,
+            len_rg_index_first.len() as u64 + 4
+        );
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+
+        std::fs::create_dir_all(&path).unwrap();
+
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+
+        let insert = |block_range: BlockRange, hash_index: HashIndex| {
+            db.insert_folder_index_impl(
+                FolderIndex {
+                    block_range,
+                    address_filter: BloomFilter(Filter::new(8, 10000)),
+                    topic_filters: None,
+                    sighash_filter: None,
+                    row_group_index_offset: 0,
+                },
+                RowGroupIndex {
+                    block: Vec::new(),
+                    transaction: Vec::new(),
+                    log: Vec::new(),
+                },
+                hash_index,
+                None,
+            )
+            .unwrap();
+        };
+
+        insert(
+            BlockRange(0, 10),
+            HashIndex {
+                blocks: vec![(vec![1; 32], 3)],
+                transactions: vec![(vec![2; 32], 3, 7)],
+            },
+        );
+        insert(
+            BlockRange(10, 20),
+            HashIndex {
+                blocks: vec![(vec![3; 32], 15)],
+                transactions: vec![(vec![4; 32], 15, 0)],
+            },
+        );
+
+        assert_eq!(db.get_block_number(&[1; 32]).unwrap(), Some(3));
+        assert_eq!(db.get_block_number(&[3; 32]).unwrap(), Some(15));
+        assert_eq!(db.get_block_number(&[2; 32]).unwrap(), None);
+
+        assert_eq!(db.get_transaction_location(&[2; 32]).unwrap(), Some((3, 7)));
+        assert_eq!(
+            db.get_transaction_location(&[4; 32]).unwrap(),
+            Some((15, 0))
+        );
+        assert_eq!(db.get_transaction_location(&[1; 32]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_address_index() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+
+        std::fs::create_dir_all(&path).unwrap();
+
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+
+        let insert = |block_range: BlockRange, address_index: Option<AddressIndex>| {
+            db.insert_folder_index_impl(
+                FolderIndex {
+                    block_range,
+                    address_filter: BloomFilter(Filter::new(8, 10000)),
+                    topic_filters: None,
+                    sighash_filter: None,
+                    row_group_index_offset: 0,
+                },
+                RowGroupIndex {
+                    block: Vec::new(),
+                    transaction: Vec::new(),
+                    log: Vec::new(),
+                },
+                HashIndex::default(),
+                address_index,
+            )
+            .unwrap();
+        };
+
+        let addr = [1; 20];
+        let other_addr = [2; 20];
+
+        insert(
+            BlockRange(0, 10),
+            Some(AddressIndex {
+                log: [(addr.to_vec(), vec![0, 3])].into_iter().collect(),
+                transaction_from: [(other_addr.to_vec(), vec![1])].into_iter().collect(),
+                transaction_to: BTreeMap::new(),
+            }),
+        );
+        insert(
+            BlockRange(10, 20),
+            Some(AddressIndex {
+                log: [(addr.to_vec(), vec![2]), (other_addr.to_vec(), vec![0])]
+                    .into_iter()
+                    .collect(),
+                transaction_from: BTreeMap::new(),
+                transaction_to: BTreeMap::new(),
+            }),
+        );
+
+        assert_eq!(db.address_index_next_block().unwrap(), 20);
+
+        let postings = db
+            .address_postings(AddressKind::Log, &addr, BlockRange(0, u64::MAX))
+            .unwrap();
+        assert_eq!(
+            postings,
+            [(0, vec![0, 3]), (10, vec![2])].into_iter().collect()
+        );
+
+        let postings = db
+            .address_postings(AddressKind::Log, &addr, BlockRange(9, 10))
+            .unwrap();
+        assert_eq!(postings, [(0, vec![0, 3])].into_iter().collect());
+
+        let postings = db
+            .address_postings(AddressKind::Log, &addr, BlockRange(10, 11))
+            .unwrap();
+        assert_eq!(postings, [(10, vec![2])].into_iter().collect());
+
+        let postings = db
+            .address_postings(AddressKind::Log, &other_addr, BlockRange(0, 10))
+            .unwrap();
+        assert!(postings.is_empty());
+
+        let postings = db
+            .address_postings(AddressKind::TransactionFrom, &other_addr, BlockRange(0, 20))
+            .unwrap();
+        assert_eq!(postings, [(0, vec![1])].into_iter().collect());
+
+        // The index stops being extended once a folder is inserted without it
+        insert(BlockRange(20, 30), None);
+        insert(
+            BlockRange(30, 40),
+            Some(AddressIndex {
+                log: [(addr.to_vec(), vec![0])].into_iter().collect(),
+                transaction_from: BTreeMap::new(),
+                transaction_to: BTreeMap::new(),
+            }),
         );
+
+        assert_eq!(db.address_index_next_block().unwrap(), 20);
+        let postings = db
+            .address_postings(AddressKind::Log, &addr, BlockRange(20, 40))
+            .unwrap();
+        assert!(postings.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_key_usage() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+
+        std::fs::create_dir_all(&path).unwrap();
+
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+
+        assert_eq!(db.get_api_key_usage("a").unwrap(), None);
+
+        let usage = ApiKeyUsage {
+            day: 19_000,
+            num_requests: 3,
+            num_bytes: 1 << 40,
+        };
+        db.write_api_key_usage(&[("a".to_owned(), usage)])
+            .await
+            .unwrap();
+
+        assert_eq!(db.get_api_key_usage("a").unwrap(), Some(usage));
+        assert_eq!(db.get_api_key_usage("b").unwrap(), None);
     }
 }
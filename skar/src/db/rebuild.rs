@@ -0,0 +1,257 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{
+    block_range_to_key,
+    migration::{read_entries, remove_if_exists, write_index_files},
+    BlockRange, Db, FolderIndex, RowGroupIndex, FOLDER_INDEX_TABLE, FORMAT_VERSION, METADATA_TABLE,
+};
+
+/// Set after the offsets of the rebuilt index files are committed and
+/// cleared after the files are moved in place of the old ones.
+const INDEX_REBUILD_KEY: &[u8] = b"index_rebuild";
+
+/// Replaces the folder and row group indices of all folders in the database with the ones returned by `build`.
+///
+/// The new index files are written next to the existing ones and are only moved into place after the new
+/// offsets are committed, so an interrupted rebuild either leaves the old indices intact or is finished on next open.
+pub(super) fn rebuild<F>(db: &Db, mut build: F) -> Result<()>
+where
+    F: FnMut(BlockRange) -> Result<(FolderIndex, RowGroupIndex)>,
+{
+    let entries = read_entries(db, FORMAT_VERSION).context("read folder index entries")?;
+
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let folder_index_path = rebuild_path(&db.folder_index_path);
+    let row_group_index_path = rebuild_path(&db.row_group_index_path);
+
+    let new_entries = write_index_files(
+        &folder_index_path,
+        &row_group_index_path,
+        entries,
+        |block_range, _| {
+            build(block_range).with_context(|| format!("build indices of {block_range:?}"))
+        },
+    )
+    .context("write index files")?;
+
+    let txn = db.env.begin_rw_txn().context("begin read write txn")?;
+    let folder_table = txn
+        .open_db(Some(FOLDER_INDEX_TABLE))
+        .context("open folder index table from txn")?;
+    let metadata = txn
+        .open_db(Some(METADATA_TABLE))
+        .context("open metadata table from txn")?;
+
+    for (block_range, offset) in new_entries.iter() {
+        txn.put(
+            folder_table.dbi(),
+            block_range_to_key(*block_range),
+            offset.to_be_bytes(),
+            Default::default(),
+        )
+        .context("write folder idx to mdb")?;
+    }
+
+    txn.put(metadata.dbi(), INDEX_REBUILD_KEY, [1], Default::default())
+        .context("write index rebuild flag")?;
+
+    txn.commit().context("commit txn")?;
+
+    finish(db)
+}
+
+/// Finishes a rebuild that was interrupted after its offsets were committed,
+/// or removes the leftover files of a rebuild that was interrupted before that.
+pub(super) fn finish(db: &Db) -> Result<()> {
+    let folder_index_path = rebuild_path(&db.folder_index_path);
+    let row_group_index_path = rebuild_path(&db.row_group_index_path);
+
+    let txn = db.env.begin_rw_txn().context("begin read write txn")?;
+    let metadata = txn
+        .open_db(Some(METADATA_TABLE))
+        .context("open metadata table from txn")?;
+
+    let in_progress = txn
+        .get::<[u8; 1]>(metadata.dbi(), INDEX_REBUILD_KEY)
+        .context("get index rebuild flag")?
+        .is_some();
+
+    if !in_progress {
+        remove_if_exists(&folder_index_path).context("remove rebuilt folder index file")?;
+        remove_if_exists(&row_group_index_path).context("remove rebuilt row group index file")?;
+        return Ok(());
+    }
+
+    rename_if_exists(&folder_index_path, &db.folder_index_path)
+        .context("move rebuilt folder index file")?;
+    rename_if_exists(&row_group_index_path, &db.row_group_index_path)
+        .context("move rebuilt row group index file")?;
+
+    txn.del(metadata.dbi(), INDEX_REBUILD_KEY, None)
+        .context("delete index rebuild flag")?;
+
+    txn.commit().context("commit txn")?;
+
+    Ok(())
+}
+
+fn rebuild_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".rebuild");
+    path.into()
+}
+
+// The file might already be moved if a previous attempt was interrupted.
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow!("failed to rename file: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use sbbf_rs_safe::Filter;
+
+    use super::*;
+    use crate::{
+        config::DbConfig,
+        db::{BloomFilter, HashIndex, TransactionRowGroupIndex},
+    };
+
+    fn empty_folder_index(block_range: BlockRange) -> FolderIndex {
+        FolderIndex {
+            block_range,
+            address_filter: BloomFilter(Filter::new(8, 10)),
+            topic_filters: None,
+            sighash_filter: None,
+            row_group_index_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+
+        fs::create_dir_all(&path).unwrap();
+
+        let cfg = DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        };
+
+        let db = Db::new(&cfg).unwrap();
+
+        for block_range in [BlockRange(0, 10), BlockRange(10, 20)] {
+            db.insert_folder_index_impl(
+                empty_folder_index(block_range),
+                RowGroupIndex {
+                    block: Vec::new(),
+                    transaction: Vec::new(),
+                    log: Vec::new(),
+                },
+                HashIndex::default(),
+                None,
+            )
+            .unwrap();
+        }
+
+        rebuild(&db, |block_range| {
+            Ok((
+                FolderIndex {
+                    sighash_filter: Some(BloomFilter(Filter::new(8, 1000))),
+                    ..empty_folder_index(block_range)
+                },
+                RowGroupIndex {
+                    block: Vec::new(),
+                    transaction: vec![TransactionRowGroupIndex {
+                        min_block_num: block_range.0,
+                        max_block_num: block_range.1 - 1,
+                        from_address_filter: BloomFilter(Filter::new(8, 1000)),
+                        to_address_filter: BloomFilter(Filter::new(8, 1000)),
+                        sighash_filter: Some(BloomFilter(Filter::new(8, 1000))),
+                    }],
+                    log: Vec::new(),
+                },
+            ))
+        })
+        .unwrap();
+
+        assert!(!rebuild_path(&db.folder_index_path).exists());
+        assert!(!rebuild_path(&db.row_group_index_path).exists());
+
+        {
+            let mut iter = db
+                .iterate_folder_indices(BlockRange(0, u64::MAX))
+                .unwrap()
+                .unwrap();
+            let folder_indices = iter.by_ref().map(|i| i.unwrap()).collect::<Vec<_>>();
+
+            assert_eq!(
+                folder_indices
+                    .iter()
+                    .map(|i| i.block_range)
+                    .collect::<Vec<_>>(),
+                vec![BlockRange(0, 10), BlockRange(10, 20)]
+            );
+
+            for folder_index in folder_indices {
+                assert!(folder_index.sighash_filter.is_some());
+
+                let rg_index = iter
+                    .read_row_group_index(folder_index.row_group_index_offset)
+                    .unwrap();
+                assert_eq!(
+                    rg_index.transaction[0].max_block_num,
+                    folder_index.block_range.1 - 1
+                );
+            }
+        }
+
+        // Folders can still be appended after a rebuild
+        db.insert_folder_index_impl(
+            empty_folder_index(BlockRange(20, 30)),
+            RowGroupIndex {
+                block: Vec::new(),
+                transaction: Vec::new(),
+                log: Vec::new(),
+            },
+            HashIndex::default(),
+            None,
+        )
+        .unwrap();
+
+        // A failed rebuild leaves the old indices in place
+        let res = rebuild(&db, |_| Err(anyhow!("failed to build")));
+        assert!(res.is_err());
+
+        drop(db);
+        let db = Db::new(&cfg).unwrap();
+
+        assert!(!rebuild_path(&db.folder_index_path).exists());
+        assert_eq!(
+            db.iterate_folder_indices(BlockRange(20, 30))
+                .unwrap()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .block_range,
+            BlockRange(20, 30)
+        );
+    }
+}
@@ -1,14 +1,24 @@
+use std::collections::BTreeMap;
+
 use super::bloom_filter::BloomFilter;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct BlockRange(pub u64, pub u64);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FolderIndex {
     pub block_range: BlockRange,
     pub address_filter: BloomFilter,
-    pub row_group_index_offset: u32,
+    /// Filters for topic0-3 of all logs in the folder.
+    ///
+    /// This is `None` for folders that were written before these filters existed.
+    pub topic_filters: Option<[BloomFilter; 4]>,
+    /// Filter for the sighash of all transactions in the folder.
+    ///
+    /// This is `None` for folders that were written before this filter existed.
+    pub sighash_filter: Option<BloomFilter>,
+    pub row_group_index_offset: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,6 +40,8 @@ pub struct TransactionRowGroupIndex {
     pub max_block_num: u64,
     pub from_address_filter: BloomFilter,
     pub to_address_filter: BloomFilter,
+    /// This is `None` for row groups that were written before this filter existed.
+    pub sighash_filter: Option<BloomFilter>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,3 +51,66 @@ pub struct LogRowGroupIndex {
     pub address_filter: BloomFilter,
     pub topic_filters: [BloomFilter; 4],
 }
+
+/// Hashes of the blocks and transactions in a folder.
+///
+/// This is used to fill the point lookup tables in the database.
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    /// (hash, block_number) for each block
+    pub blocks: Vec<(Vec<u8>, u64)>,
+    /// (hash, block_number, transaction_index) for each transaction
+    pub transactions: Vec<(Vec<u8>, u64, u64)>,
+}
+
+/// Row groups of a folder that contain each address.
+///
+/// This is used to fill the address index tables in the database.
+#[derive(Debug, Default)]
+pub struct AddressIndex {
+    /// address -> indices of the log row groups that have logs emitted by the address
+    pub log: BTreeMap<Vec<u8>, Vec<u32>>,
+    /// address -> indices of the transaction row groups that have transactions sent from the address
+    pub transaction_from: BTreeMap<Vec<u8>, Vec<u32>>,
+    /// address -> indices of the transaction row groups that have transactions sent to the address
+    pub transaction_to: BTreeMap<Vec<u8>, Vec<u32>>,
+}
+
+/// Address columns that are indexed by the address index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+    Log,
+    TransactionFrom,
+    TransactionTo,
+}
+
+/// Usage counters of an api key for a single day.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApiKeyUsage {
+    /// Number of days since the unix epoch in UTC.
+    pub day: u32,
+    pub num_requests: u64,
+    pub num_bytes: u64,
+}
+
+impl ApiKeyUsage {
+    pub const ENCODED_LEN: usize = 20;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0; Self::ENCODED_LEN];
+
+        out[..4].copy_from_slice(&self.day.to_be_bytes());
+        out[4..12].copy_from_slice(&self.num_requests.to_be_bytes());
+        out[12..].copy_from_slice(&self.num_bytes.to_be_bytes());
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8; Self::ENCODED_LEN]) -> Self {
+        Self {
+            day: u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+            num_requests: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            num_bytes: u64::from_be_bytes(bytes[12..].try_into().unwrap()),
+        }
+    }
+}
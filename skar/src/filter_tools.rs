@@ -0,0 +1,347 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use arrow2::array::BinaryArray;
+use wyhash::wyhash;
+
+use crate::{
+    build_parquet_idx::{build_parquet_indices, load_columns},
+    config::ParquetConfig,
+    db::{BlockRange, BloomFilter, Db},
+    state::ArrowChunk,
+};
+
+/// Measures the false positive rates of the bloom filters of all folders in the database
+/// and prints a report per filter kind.
+///
+/// Each filter is probed with `num_probes` random keys that are not in the folder it was built for.
+pub async fn report_false_positives(db: &Db, parquet_path: &Path, num_probes: usize) -> Result<()> {
+    tokio::task::block_in_place(|| {
+        let mut report = Report::default();
+
+        let mut folder_indices = match db
+            .iterate_folder_indices(BlockRange(0, u64::MAX))
+            .context("start folder index iterator")?
+        {
+            Some(folder_indices) => folder_indices,
+            None => {
+                println!("there are no folders in the database");
+                return Ok(());
+            }
+        };
+
+        while let Some(folder_index) = folder_indices.next() {
+            let folder_index = folder_index.context("read folder index")?;
+            let rg_index = folder_indices
+                .read_row_group_index(folder_index.row_group_index_offset)
+                .context("read row group index")?;
+
+            let mut path = parquet_path.to_owned();
+            path.push(format!(
+                "{}-{}",
+                folder_index.block_range.0, folder_index.block_range.1
+            ));
+
+            let keys = FolderKeys::load(&path)
+                .with_context(|| format!("load keys of folder {:?}", folder_index.block_range))?;
+
+            if keys.transactions.len() != rg_index.transaction.len()
+                || keys.logs.len() != rg_index.log.len()
+            {
+                return Err(anyhow!(
+                    "row groups of folder {:?} don't match its row group index",
+                    folder_index.block_range
+                ));
+            }
+
+            let mut folder_address = HashSet::new();
+            let mut folder_topics = vec![HashSet::new(); 4];
+            let mut folder_sighash = HashSet::new();
+
+            for (keys, rg) in keys.transactions.iter().zip(rg_index.transaction.iter()) {
+                report.row_group_address.measure(
+                    Some(&rg.from_address_filter),
+                    &keys.from,
+                    num_probes,
+                );
+                report
+                    .row_group_address
+                    .measure(Some(&rg.to_address_filter), &keys.to, num_probes);
+                report.row_group_sighash.measure(
+                    rg.sighash_filter.as_ref(),
+                    &keys.sighash,
+                    num_probes,
+                );
+
+                folder_address.extend(keys.from.iter().chain(keys.to.iter()));
+                folder_sighash.extend(keys.sighash.iter());
+            }
+
+            for (keys, rg) in keys.logs.iter().zip(rg_index.log.iter()) {
+                report.row_group_address.measure(
+                    Some(&rg.address_filter),
+                    &keys.address,
+                    num_probes,
+                );
+                folder_address.extend(keys.address.iter());
+
+                for ((filter, topic), folder_topic) in rg
+                    .topic_filters
+                    .iter()
+                    .zip(keys.topics.iter())
+                    .zip(folder_topics.iter_mut())
+                {
+                    report
+                        .row_group_topic
+                        .measure(Some(filter), topic, num_probes);
+                    folder_topic.extend(topic.iter());
+                }
+            }
+
+            report.folder_address.measure(
+                Some(&folder_index.address_filter),
+                &folder_address,
+                num_probes,
+            );
+            for (i, topic) in folder_topics.iter().enumerate() {
+                report.folder_topic.measure(
+                    folder_index.topic_filters.as_ref().map(|f| &f[i]),
+                    topic,
+                    num_probes,
+                );
+            }
+            report.folder_sighash.measure(
+                folder_index.sighash_filter.as_ref(),
+                &folder_sighash,
+                num_probes,
+            );
+        }
+
+        report.print();
+
+        Ok(())
+    })
+}
+
+/// Rebuilds the bloom filters of all folders in the database using the current config.
+///
+/// The parquet files are only read, so this is much cheaper than rewriting the folders.
+pub async fn rebuild_filters(db: &Db, cfg: &ParquetConfig) -> Result<()> {
+    log::info!("rebuilding bloom filters");
+
+    db.rebuild_indices(|block_range| {
+        let mut path = cfg.path.clone();
+        path.push(format!("{}-{}", block_range.0, block_range.1));
+
+        let (folder_index, rg_index) =
+            build_parquet_indices(&path, &cfg.bloom_filters).context("build parquet indices")?;
+
+        if folder_index.block_range != block_range {
+            return Err(anyhow!(
+                "block range of folder contents ({:?}) doesn't match the index",
+                folder_index.block_range
+            ));
+        }
+
+        log::info!("rebuilt bloom filters of folder {:?}", block_range);
+
+        Ok((folder_index, rg_index))
+    })
+    .await
+    .context("rebuild indices")?;
+
+    log::info!("finished rebuilding bloom filters");
+
+    Ok(())
+}
+
+// Hashes of the keys that are inserted into the filters of each row group.
+struct FolderKeys {
+    transactions: Vec<TransactionKeys>,
+    logs: Vec<LogKeys>,
+}
+
+struct TransactionKeys {
+    from: HashSet<u64>,
+    to: HashSet<u64>,
+    sighash: HashSet<u64>,
+}
+
+struct LogKeys {
+    address: HashSet<u64>,
+    topics: Vec<HashSet<u64>>,
+}
+
+impl FolderKeys {
+    fn load(path: &Path) -> Result<Self> {
+        let transactions = {
+            let mut path = path.to_owned();
+            path.push("transactions.parquet");
+
+            load_columns(&path, &["from", "to", "sighash"]).context("load transactions")?
+        };
+        let logs = {
+            let mut path = path.to_owned();
+            path.push("logs.parquet");
+
+            load_columns(&path, &["address", "topic0", "topic1", "topic2", "topic3"])
+                .context("load logs")?
+        };
+
+        let transactions = transactions
+            .iter()
+            .map(|chunk| TransactionKeys {
+                from: hash_column(chunk, 0),
+                to: hash_column(chunk, 1),
+                sighash: hash_column(chunk, 2),
+            })
+            .collect();
+
+        let logs = logs
+            .iter()
+            .map(|chunk| LogKeys {
+                address: hash_column(chunk, 0),
+                topics: (1..5).map(|i| hash_column(chunk, i)).collect(),
+            })
+            .collect();
+
+        Ok(Self { transactions, logs })
+    }
+}
+
+fn hash_column(chunk: &ArrowChunk, col_idx: usize) -> HashSet<u64> {
+    chunk.columns()[col_idx]
+        .as_any()
+        .downcast_ref::<BinaryArray<i32>>()
+        .unwrap()
+        .iter()
+        .flatten()
+        .map(|key| wyhash(key, 0))
+        .collect()
+}
+
+#[derive(Default)]
+struct Report {
+    row_group_address: FilterStats,
+    row_group_topic: FilterStats,
+    row_group_sighash: FilterStats,
+    folder_address: FilterStats,
+    folder_topic: FilterStats,
+    folder_sighash: FilterStats,
+}
+
+impl Report {
+    fn print(&self) {
+        println!(
+            "{:<20}{:>10}{:>10}{:>14}{:>14}{:>12}{:>10}",
+            "filter", "count", "missing", "avg keys", "avg bytes", "probes", "fp rate"
+        );
+
+        let kinds = [
+            ("row_group_address", &self.row_group_address),
+            ("row_group_topic", &self.row_group_topic),
+            ("row_group_sighash", &self.row_group_sighash),
+            ("folder_address", &self.folder_address),
+            ("folder_topic", &self.folder_topic),
+            ("folder_sighash", &self.folder_sighash),
+        ];
+
+        for (name, stats) in kinds {
+            let count = stats.num_filters.max(1) as f64;
+            println!(
+                "{:<20}{:>10}{:>10}{:>14.1}{:>14.1}{:>12}{:>9.4}%",
+                name,
+                stats.num_filters,
+                stats.num_missing,
+                stats.num_keys as f64 / count,
+                stats.num_bytes as f64 / count,
+                stats.num_probes,
+                stats.false_positive_rate() * 100.0,
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct FilterStats {
+    num_filters: usize,
+    // Filters that don't exist because the folder was written before they were introduced
+    num_missing: usize,
+    num_keys: usize,
+    num_bytes: usize,
+    num_probes: usize,
+    num_false_positives: usize,
+}
+
+impl FilterStats {
+    fn measure(&mut self, filter: Option<&BloomFilter>, keys: &HashSet<u64>, num_probes: usize) {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => {
+                self.num_missing += 1;
+                return;
+            }
+        };
+
+        // Seed the probes differently for each filter so the same keys aren't probed every time.
+        let seed = self.num_filters as u64;
+
+        for i in 0..num_probes {
+            let hash = wyhash(&i.to_be_bytes(), seed);
+            if keys.contains(&hash) {
+                continue;
+            }
+
+            self.num_probes += 1;
+            if filter.0.contains_hash(hash) {
+                self.num_false_positives += 1;
+            }
+        }
+
+        self.num_filters += 1;
+        self.num_keys += keys.len();
+        self.num_bytes += filter.0.as_bytes().len();
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        if self.num_probes == 0 {
+            return 0.0;
+        }
+
+        self.num_false_positives as f64 / self.num_probes as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sbbf_rs_safe::Filter;
+
+    use super::*;
+
+    #[test]
+    fn test_filter_stats() {
+        let keys = (0..1000u64)
+            .map(|i| wyhash(&i.to_be_bytes(), 1234))
+            .collect::<HashSet<_>>();
+
+        let mut small = Filter::new(1, 10);
+        let mut big = Filter::new(16, keys.len());
+        for &key in keys.iter() {
+            small.insert_hash(key);
+            big.insert_hash(key);
+        }
+
+        let mut small_stats = FilterStats::default();
+        small_stats.measure(Some(&BloomFilter(small)), &keys, 10_000);
+        let mut big_stats = FilterStats::default();
+        big_stats.measure(Some(&BloomFilter(big)), &keys, 10_000);
+        big_stats.measure(None, &keys, 10_000);
+
+        assert_eq!(small_stats.num_probes, 10_000);
+        assert!(small_stats.false_positive_rate() > 0.5);
+        assert!(big_stats.false_positive_rate() < 0.01);
+        assert_eq!(big_stats.num_filters, 1);
+        assert_eq!(big_stats.num_missing, 1);
+        assert_eq!(big_stats.num_keys, 1000);
+    }
+}
@@ -0,0 +1,568 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::{Context, Result};
+use arrow2::{
+    array::new_empty_array,
+    datatypes::{Schema, SchemaRef},
+    io::{
+        flight::{serialize_batch, serialize_schema},
+        ipc::{
+            write::{default_ipc_fields, WriteOptions},
+            IpcField,
+        },
+    },
+};
+use arrow_format::flight::{
+    data::{
+        Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightInfo,
+        HandshakeRequest, HandshakeResponse, PutResult, Result as ActionResult, SchemaResult,
+        Ticket,
+    },
+    service::flight_service_server::{FlightService, FlightServiceServer},
+};
+use futures::{channel::mpsc, stream::BoxStream, SinkExt};
+use serde::{Deserialize, Serialize};
+use tonic::{metadata::MetadataMap, Request, Response, Status, Streaming};
+
+use crate::{
+    admission::{Admission, AdmissionPermit, QueryCost},
+    api_keys::{ApiKey, ApiKeys, Rejection},
+    config::FlightServerConfig,
+    query::{ArrowBatch, Handler, QueryLimits, QueryResultReceiver},
+    state::ArrowChunk,
+    types::{Query, QueryResultData},
+    write_parquet::MEGABYTE,
+};
+
+pub(crate) async fn run(
+    cfg: FlightServerConfig,
+    handler: Arc<Handler>,
+    admission: Arc<Admission>,
+    api_keys: Option<Arc<ApiKeys>>,
+) -> Result<()> {
+    let addr = cfg.addr;
+    let service = SkarFlightService {
+        cfg,
+        handler,
+        admission,
+        api_keys,
+    };
+
+    tonic::transport::Server::builder()
+        .add_service(FlightServiceServer::new(service))
+        .serve(addr)
+        .await
+        .context("run flight server")
+}
+
+/// Contents of the ticket of a `DoGet` call.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlightTicket {
+    query: Query,
+}
+
+/// Table that a schema message or a batch of the stream belongs to.
+///
+/// The query is executed once for all tables, so the stream has a schema message for each
+/// table and the app metadata of each message has the `table` it belongs to.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Table {
+    Logs,
+    Transactions,
+    Blocks,
+}
+
+impl Table {
+    const ALL: [Table; 3] = [Table::Logs, Table::Transactions, Table::Blocks];
+
+    fn take_batches(self, data: &mut QueryResultData) -> Vec<ArrowBatch> {
+        std::mem::take(match self {
+            Table::Logs => &mut data.logs,
+            Table::Transactions => &mut data.transactions,
+            Table::Blocks => &mut data.blocks,
+        })
+    }
+}
+
+struct SkarFlightService {
+    cfg: FlightServerConfig,
+    handler: Arc<Handler>,
+    admission: Arc<Admission>,
+    api_keys: Option<Arc<ApiKeys>>,
+}
+
+#[tonic::async_trait]
+impl FlightService for SkarFlightService {
+    type HandshakeStream = BoxStream<'static, Result<HandshakeResponse, Status>>;
+    type ListFlightsStream = BoxStream<'static, Result<FlightInfo, Status>>;
+    type DoGetStream = BoxStream<'static, Result<FlightData, Status>>;
+    type DoPutStream = BoxStream<'static, Result<PutResult, Status>>;
+    type DoExchangeStream = BoxStream<'static, Result<FlightData, Status>>;
+    type DoActionStream = BoxStream<'static, Result<ActionResult, Status>>;
+    type ListActionsStream = BoxStream<'static, Result<ActionType, Status>>;
+
+    /// Runs the query in the ticket once and streams the batches of all tables as they are produced.
+    ///
+    /// Each table starts with a schema message that has the `table` in its app metadata. The app
+    /// metadata of each batch is a json object with the `table` and the `next_block` of the
+    /// folder the batch came from. The stream ends with an empty batch that has the final
+    /// `next_block` and the `archive_height` in its app metadata.
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let key = authenticate(self.api_keys.as_deref(), request.metadata())?;
+
+        let size_limit = self.cfg.response_size_limit_mb * MEGABYTE;
+        let limits = match &key {
+            Some(key) => key.query_limits(size_limit),
+            None => QueryLimits::new(size_limit),
+        };
+
+        let ticket: FlightTicket = serde_json::from_slice(&request.into_inner().ticket)
+            .map_err(|e| Status::invalid_argument(format!("failed to parse ticket: {e}")))?;
+        ticket
+            .query
+            .validate()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let num_folders = self
+            .handler
+            .count_folders(&ticket.query)
+            .await
+            .map_err(internal_error)?;
+        let cost = QueryCost::estimate(self.admission.cfg(), &ticket.query, num_folders);
+
+        let permit = self.admission.admit(cost).await.map_err(|overloaded| {
+            Status::resource_exhausted(format!(
+                "too many queries are running, retry in {} seconds",
+                overloaded.retry_after.as_secs()
+            ))
+        })?;
+
+        let rx = self
+            .handler
+            .clone()
+            .handle(ticket.query, limits)
+            .map_err(internal_error)?;
+
+        let (tx, data_rx) = mpsc::channel(1);
+
+        tokio::spawn(send_tables(rx, self.handler.clone(), tx, permit, key));
+
+        Ok(Response::new(Box::pin(data_rx)))
+    }
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("handshake is not supported"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("list_flights is not supported"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        Err(Status::unimplemented("get_flight_info is not supported"))
+    }
+
+    async fn get_schema(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        Err(Status::unimplemented("get_schema is not supported"))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("do_put is not supported"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("do_exchange is not supported"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("do_action is not supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Err(Status::unimplemented("list_actions is not supported"))
+    }
+}
+
+// Takes the api key from the `authorization` metadata in the same format as the http server.
+fn authenticate(
+    api_keys: Option<&ApiKeys>,
+    metadata: &MetadataMap,
+) -> Result<Option<Arc<ApiKey>>, Status> {
+    let api_keys = match api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(None),
+    };
+
+    let authorization = metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+
+    api_keys
+        .authenticate(authorization)
+        .map(Some)
+        .map_err(rejection_status)
+}
+
+// The details are only logged so file paths and other internals don't leak to clients.
+fn internal_error(e: anyhow::Error) -> Status {
+    log::error!("failed to handle flight request: {:?}", e);
+    Status::internal("internal error")
+}
+
+fn rejection_status(rejection: Rejection) -> Status {
+    match rejection {
+        Rejection::Unauthorized => Status::unauthenticated("missing or unknown api key"),
+        Rejection::RateLimited { retry_after } => Status::resource_exhausted(format!(
+            "rate limit of the api key is exceeded, retry in {} ms",
+            retry_after.as_millis()
+        )),
+        Rejection::QuotaExceeded { retry_after } => Status::resource_exhausted(format!(
+            "daily quota of the api key is used up, retry in {} seconds",
+            retry_after.as_secs()
+        )),
+    }
+}
+
+type FlightDataSender = mpsc::Sender<Result<FlightData, Status>>;
+
+// The admission permit is held until the stream is finished.
+async fn send_tables(
+    mut rx: QueryResultReceiver,
+    handler: Arc<Handler>,
+    mut tx: FlightDataSender,
+    _permit: AdmissionPermit,
+    key: Option<Arc<ApiKey>>,
+) {
+    let res = stream_tables(&mut rx, &mut tx, key.as_deref(), || {
+        handler.archive_height()
+    })
+    .await;
+    if let Err(e) = res {
+        tx.send(Err(internal_error(e))).await.ok();
+    }
+}
+
+struct TableStream {
+    schema: SchemaRef,
+    ipc_fields: Vec<IpcField>,
+}
+
+impl TableStream {
+    fn new(schema: SchemaRef, table: Option<Table>) -> (Self, FlightData) {
+        let ipc_fields = default_ipc_fields(&schema.fields);
+        let mut data = serialize_schema(&schema, Some(&ipc_fields));
+        data.app_metadata = serde_json::to_vec(&serde_json::json!({
+            "table": table,
+        }))
+        .unwrap();
+
+        (Self { schema, ipc_fields }, data)
+    }
+}
+
+// Stops without an error if the client is gone, dropping the receiver cancels the query.
+async fn stream_tables<F, Fut>(
+    rx: &mut QueryResultReceiver,
+    tx: &mut FlightDataSender,
+    key: Option<&ApiKey>,
+    archive_height: F,
+) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<u64>>>,
+{
+    let options = WriteOptions { compression: None };
+
+    // The schema message of a table is sent before its first batch.
+    let mut streams: [Option<TableStream>; 3] = Default::default();
+    let mut last_table = None;
+    let mut next_block = 0;
+
+    while let Some(res) = rx.recv().await {
+        let mut query_result = res.context("execute query")?;
+        next_block = query_result.next_block;
+
+        for (table, stream) in Table::ALL.into_iter().zip(streams.iter_mut()) {
+            let app_metadata = serde_json::to_vec(&serde_json::json!({
+                "table": table,
+                "next_block": next_block,
+            }))
+            .unwrap();
+
+            for batch in table.take_batches(&mut query_result.data) {
+                if stream.is_none() {
+                    let (new_stream, schema) = TableStream::new(batch.schema.clone(), Some(table));
+                    if tx.send(Ok(schema)).await.is_err() {
+                        return Ok(());
+                    }
+                    *stream = Some(new_stream);
+                }
+                let ipc_fields = &stream.as_ref().unwrap().ipc_fields;
+                last_table = Some(table);
+
+                let (dictionaries, mut data) = serialize_batch(&batch.chunk, ipc_fields, &options)
+                    .context("serialize batch")?;
+                data.app_metadata = app_metadata.clone();
+
+                for data in dictionaries.into_iter().chain(std::iter::once(data)) {
+                    if let Some(key) = key {
+                        key.add_response_bytes(data.data_header.len() + data.data_body.len());
+                    }
+                    if tx.send(Ok(data)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // The last batch uses the schema of the table that was streamed last, or an empty schema
+    // with a `null` table if the query didn't return any rows.
+    let stream = match last_table {
+        Some(table) => streams[table as usize].take().unwrap(),
+        None => {
+            let (stream, schema) = TableStream::new(Schema::default().into(), None);
+            if tx.send(Ok(schema)).await.is_err() {
+                return Ok(());
+            }
+            stream
+        }
+    };
+
+    let height = archive_height().await.context("get archive height")?;
+
+    let empty = ArrowChunk::new(
+        stream
+            .schema
+            .fields
+            .iter()
+            .map(|field| new_empty_array(field.data_type().clone()))
+            .collect(),
+    );
+    let (_, mut data) =
+        serialize_batch(&empty, &stream.ipc_fields, &options).context("serialize last batch")?;
+    data.app_metadata = serde_json::to_vec(&serde_json::json!({
+        "table": last_table,
+        "next_block": next_block,
+        "archive_height": height,
+    }))
+    .unwrap();
+
+    tx.send(Ok(data)).await.ok();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use arrow2::io::{
+        flight::{deserialize_batch, deserialize_schemas},
+        ipc::read::Dictionaries,
+    };
+    use futures::StreamExt;
+    use tonic::Code;
+
+    use crate::{
+        config::{DbConfig, HttpServerConfig},
+        db::Db,
+        schema::{self, data_to_batches},
+        tests::{batch_data, FIXTURE_BLOCK},
+        types::QueryResult,
+    };
+
+    use super::*;
+
+    fn app_metadata(data: &FlightData) -> serde_json::Value {
+        serde_json::from_slice(&data.app_metadata).unwrap()
+    }
+
+    async fn stream(results: Vec<Result<QueryResult>>) -> Vec<FlightData> {
+        let mut rx = QueryResultReceiver::from_results(results);
+        let (mut tx, data_rx) = mpsc::channel(16);
+
+        stream_tables(&mut rx, &mut tx, None, || async { Ok(Some(12911679)) })
+            .await
+            .unwrap();
+        drop(tx);
+
+        data_rx.map(|data| data.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_stream_tables() {
+        let batches = data_to_batches(batch_data(FIXTURE_BLOCK));
+        let num_logs = batches.logs.len();
+        let num_txs = batches.transactions.len();
+
+        let messages = stream(vec![
+            Ok(QueryResult {
+                data: QueryResultData {
+                    logs: vec![ArrowBatch {
+                        chunk: Arc::new(batches.logs),
+                        schema: schema::log(),
+                    }],
+                    transactions: vec![ArrowBatch {
+                        chunk: Arc::new(batches.transactions),
+                        schema: schema::transaction(),
+                    }],
+                    blocks: Vec::new(),
+                },
+                next_block: 12911680,
+            }),
+            Ok(QueryResult {
+                data: QueryResultData::default(),
+                next_block: 12911690,
+            }),
+        ])
+        .await;
+        assert_eq!(messages.len(), 5);
+
+        // Each table starts with its schema.
+        let (log_schema, log_ipc_schema) = deserialize_schemas(&messages[0].data_header).unwrap();
+        assert_eq!(log_schema.fields, schema::log().fields);
+        assert_eq!(
+            app_metadata(&messages[0]),
+            serde_json::json!({"table": "logs"})
+        );
+
+        let logs = deserialize_batch(
+            &messages[1],
+            &log_schema.fields,
+            &log_ipc_schema,
+            &Dictionaries::default(),
+        )
+        .unwrap();
+        assert_eq!(logs.len(), num_logs);
+        assert_eq!(
+            app_metadata(&messages[1]),
+            serde_json::json!({"table": "logs", "next_block": 12911680})
+        );
+
+        let (tx_schema, tx_ipc_schema) = deserialize_schemas(&messages[2].data_header).unwrap();
+        assert_eq!(tx_schema.fields, schema::transaction().fields);
+        assert_eq!(
+            app_metadata(&messages[2]),
+            serde_json::json!({"table": "transactions"})
+        );
+
+        let txs = deserialize_batch(
+            &messages[3],
+            &tx_schema.fields,
+            &tx_ipc_schema,
+            &Dictionaries::default(),
+        )
+        .unwrap();
+        assert_eq!(txs.len(), num_txs);
+
+        // The final batch is empty and has the metadata of the whole query.
+        let last = deserialize_batch(
+            &messages[4],
+            &tx_schema.fields,
+            &tx_ipc_schema,
+            &Dictionaries::default(),
+        )
+        .unwrap();
+        assert_eq!(last.len(), 0);
+        assert_eq!(
+            app_metadata(&messages[4]),
+            serde_json::json!({
+                "table": "transactions",
+                "next_block": 12911690,
+                "archive_height": 12911679,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_tables_empty() {
+        let messages = stream(vec![Ok(QueryResult {
+            data: QueryResultData::default(),
+            next_block: 100,
+        })])
+        .await;
+        assert_eq!(messages.len(), 2);
+
+        let (schema, _) = deserialize_schemas(&messages[0].data_header).unwrap();
+        assert!(schema.fields.is_empty());
+        assert_eq!(
+            app_metadata(&messages[0]),
+            serde_json::json!({"table": null})
+        );
+        assert_eq!(
+            app_metadata(&messages[1]),
+            serde_json::json!({
+                "table": null,
+                "next_block": 100,
+                "archive_height": 12911679,
+            })
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let mut path = temp_dir();
+        path.push(format!("{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        let db = Db::new(&DbConfig {
+            path,
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+        let cfg: HttpServerConfig = serde_json::from_value(serde_json::json!({
+            "addr": "127.0.0.1:0",
+            "response_size_limit_mb": 1,
+            "api_keys": [{"name": "test", "key": "secret"}],
+        }))
+        .unwrap();
+        let api_keys = ApiKeys::load(&cfg, Arc::new(db)).unwrap().unwrap();
+
+        let mut metadata = MetadataMap::new();
+        assert!(authenticate(None, &metadata).unwrap().is_none());
+        assert_eq!(
+            authenticate(Some(&api_keys), &metadata).unwrap_err().code(),
+            Code::Unauthenticated
+        );
+
+        metadata.insert("authorization", "Bearer wrong".parse().unwrap());
+        assert_eq!(
+            authenticate(Some(&api_keys), &metadata).unwrap_err().code(),
+            Code::Unauthenticated
+        );
+
+        metadata.insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(authenticate(Some(&api_keys), &metadata).unwrap().is_some());
+    }
+}
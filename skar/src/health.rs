@@ -0,0 +1,199 @@
+use std::time::Instant;
+
+use serde::Serialize;
+use skar_ingest::ChainTip;
+
+use crate::config::HealthConfig;
+use crate::state::WriteStatus;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestState {
+    /// Blocks are downloaded in big batches until the ingester reaches the tip of the chain.
+    InitialSync,
+    /// New blocks are ingested as they are produced.
+    FollowingTip,
+}
+
+/// Report that is served from `/health` and `/ready`.
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub ready: bool,
+    /// Reasons for not being healthy or ready.
+    pub problems: Vec<String>,
+    pub ingest_state: IngestState,
+    /// Last block that can be queried.
+    pub archive_height: Option<u64>,
+    /// Highest block reported by the health checks of the rpc endpoints.
+    pub chain_tip: Option<u64>,
+    /// Number of blocks between `chain_tip` and `archive_height`.
+    ///
+    /// This is `None` if none of the rpc endpoints are healthy.
+    pub lag_blocks: Option<u64>,
+    pub healthy_endpoints: usize,
+    pub num_endpoints: usize,
+    /// Block range of the data that is not written to parquet yet.
+    pub in_memory: Option<InMemoryRange>,
+    pub last_flush: Option<LastFlush>,
+    /// Seconds since a block was last appended, or since the process started if none were.
+    pub secs_since_last_append: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InMemoryRange {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LastFlush {
+    /// End of the block range of the folder (exclusive).
+    pub to_block: u64,
+    pub secs_ago: u64,
+}
+
+impl HealthReport {
+    pub fn new(
+        following_tip: bool,
+        archive_height: Option<u64>,
+        chain_tip: ChainTip,
+        in_memory: Option<InMemoryRange>,
+        write_status: WriteStatus,
+        now: Instant,
+    ) -> Self {
+        let lag_blocks = chain_tip.block_number.map(|tip| match archive_height {
+            Some(height) => tip.saturating_sub(height),
+            None => tip + 1,
+        });
+
+        Self {
+            healthy: true,
+            ready: true,
+            problems: Vec::new(),
+            ingest_state: if following_tip {
+                IngestState::FollowingTip
+            } else {
+                IngestState::InitialSync
+            },
+            archive_height,
+            chain_tip: chain_tip.block_number,
+            lag_blocks,
+            healthy_endpoints: chain_tip.healthy_endpoints,
+            num_endpoints: chain_tip.num_endpoints,
+            in_memory,
+            last_flush: write_status.last_flush.map(|(time, to_block)| LastFlush {
+                to_block,
+                secs_ago: now.saturating_duration_since(time).as_secs(),
+            }),
+            secs_since_last_append: now
+                .saturating_duration_since(write_status.last_append)
+                .as_secs(),
+        }
+    }
+
+    /// Sets `healthy`, `ready` and `problems` according to the thresholds in the config.
+    pub fn check(mut self, cfg: &HealthConfig) -> Self {
+        // Not getting new blocks is fine if the archive is at the tip, the chain might be halted.
+        let behind = self.lag_blocks.map(|lag| lag > 0).unwrap_or(true);
+        if behind && self.secs_since_last_append > cfg.max_stall_secs {
+            self.healthy = false;
+            self.problems.push(format!(
+                "no blocks were ingested for {} seconds",
+                self.secs_since_last_append
+            ));
+        }
+
+        self.ready = self.healthy;
+
+        // The lag can't be checked without an endpoint, and the archive falls behind anyway.
+        if self.healthy_endpoints == 0 {
+            self.ready = false;
+            self.problems
+                .push("none of the rpc endpoints are healthy".to_owned());
+        }
+
+        if self.ingest_state == IngestState::InitialSync && !cfg.ready_during_initial_sync {
+            self.ready = false;
+            self.problems.push("initial sync is in progress".to_owned());
+        }
+
+        if let Some(lag) = self.lag_blocks {
+            if lag > cfg.max_lag_blocks {
+                self.ready = false;
+                self.problems
+                    .push(format!("archive is {} blocks behind the chain tip", lag));
+            }
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn new_report(following_tip: bool, tip: Option<u64>, idle_secs: u64) -> HealthReport {
+        let now = Instant::now();
+
+        HealthReport::new(
+            following_tip,
+            Some(100),
+            ChainTip {
+                block_number: tip,
+                healthy_endpoints: tip.map(|_| 1).unwrap_or(0),
+                num_endpoints: 1,
+            },
+            None,
+            WriteStatus {
+                last_append: now - Duration::from_secs(idle_secs),
+                last_flush: None,
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn test_check() {
+        let cfg = HealthConfig {
+            max_lag_blocks: 10,
+            max_stall_secs: 60,
+            ready_during_initial_sync: false,
+        };
+
+        let report = new_report(true, Some(105), 1).check(&cfg);
+        assert_eq!(report.lag_blocks, Some(5));
+        assert!(report.healthy && report.ready);
+        assert!(report.problems.is_empty());
+
+        let report = new_report(true, Some(111), 1).check(&cfg);
+        assert!(report.healthy && !report.ready);
+
+        let report = new_report(false, Some(100), 1).check(&cfg);
+        assert!(report.healthy && !report.ready);
+        let report = new_report(false, Some(100), 1).check(&HealthConfig {
+            ready_during_initial_sync: true,
+            ..cfg
+        });
+        assert!(report.ready);
+
+        // The chain might be halted.
+        let report = new_report(true, Some(100), 120).check(&cfg);
+        assert!(report.healthy && report.ready);
+
+        let report = new_report(true, Some(101), 120).check(&cfg);
+        assert!(!report.healthy && !report.ready);
+
+        let report = new_report(true, None, 1).check(&cfg);
+        assert_eq!(report.lag_blocks, None);
+        assert!(report.healthy && !report.ready);
+        assert_eq!(report.problems.len(), 1);
+
+        let report = new_report(true, None, 120).check(&cfg);
+        assert!(!report.healthy && !report.ready);
+        assert_eq!(report.problems.len(), 2);
+    }
+}
@@ -1,7 +1,14 @@
+mod admission;
+mod api_keys;
 mod args;
 mod build_parquet_idx;
 mod config;
 mod db;
+mod filter_tools;
+mod flight;
+mod health;
+mod metadata_cache;
+mod metrics;
 mod open_file_reader;
 mod query;
 mod schema;
@@ -14,5 +21,5 @@ mod types;
 mod validate_parquet;
 mod write_parquet;
 
-pub use args::Args;
+pub use args::{Args, Command};
 pub use skar_runner::SkarRunner;
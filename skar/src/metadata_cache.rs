@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use arrow2::{datatypes::Schema, io::parquet::read::FileMetaData};
+use prometheus::{IntCounter, IntGauge};
+use serde::Serialize;
+
+use crate::{
+    db::{BlockRange, RowGroupIndex},
+    metrics::Metrics,
+};
+
+// Rough size of the metadata of a single column chunk in a parquet footer.
+const COLUMN_CHUNK_METADATA_SIZE: usize = 512;
+// Rough size of a field in an inferred arrow schema.
+const FIELD_SIZE: usize = 128;
+
+/// Footer and arrow schema of a parquet file.
+pub struct ParquetMetadata {
+    pub metadata: FileMetaData,
+    pub schema: Schema,
+}
+
+/// LRU cache of parquet metadata and row group indices that are shared between queries.
+///
+/// Parquet metadata is keyed by the path, modification time and length of the file, and
+/// row group indices are keyed by the block range of the folder and their offset in the
+/// index file. Replacing a folder or rebuilding its indices changes the key, so stale
+/// entries are never returned and are evicted when they become the least recently used.
+pub struct MetadataCache {
+    inner: Mutex<Lru>,
+    budget: usize,
+    parquet_metadata: CacheCounters,
+    row_group_index: CacheCounters,
+    size: IntGauge,
+}
+
+impl MetadataCache {
+    /// Creates a cache that holds up to `budget` bytes of estimated entry size.
+    ///
+    /// The lookups and evictions are counted in the given metrics.
+    pub fn new(budget: usize, metrics: &Metrics) -> Self {
+        Self {
+            inner: Mutex::new(Lru::default()),
+            budget,
+            parquet_metadata: CacheCounters::new(metrics, "parquet_metadata"),
+            row_group_index: CacheCounters::new(metrics, "row_group_index"),
+            size: metrics.cache_size.clone(),
+        }
+    }
+
+    /// Returns the metadata of the parquet file that `reader` was opened from.
+    ///
+    /// The footer is read from `reader` if it isn't in the cache.
+    pub fn parquet_metadata(
+        &self,
+        path: &Path,
+        reader: &mut BufReader<File>,
+    ) -> Result<Arc<ParquetMetadata>> {
+        let file_metadata = reader.get_ref().metadata().context("stat parquet file")?;
+        let key = CacheKey::ParquetMetadata(
+            path.to_owned(),
+            file_metadata.modified().context("get modification time")?,
+            file_metadata.len(),
+        );
+
+        if let Some(CacheValue::ParquetMetadata(value)) = self.get(&key) {
+            self.parquet_metadata.hit();
+            return Ok(value);
+        }
+        self.parquet_metadata.miss();
+
+        let metadata =
+            arrow2::io::parquet::read::read_metadata(reader).context("read parquet metadata")?;
+        let schema =
+            arrow2::io::parquet::read::infer_schema(&metadata).context("infer parquet schema")?;
+
+        let size = metadata
+            .row_groups
+            .iter()
+            .map(|rg| rg.columns().len() * COLUMN_CHUNK_METADATA_SIZE)
+            .sum::<usize>()
+            + schema.fields.len() * FIELD_SIZE;
+
+        let value = Arc::new(ParquetMetadata { metadata, schema });
+        self.insert(key, CacheValue::ParquetMetadata(value.clone()), size);
+
+        Ok(value)
+    }
+
+    /// Returns the row group index of the folder, calling `load` to read it if it isn't in the cache.
+    pub fn row_group_index<F>(
+        &self,
+        block_range: BlockRange,
+        offset: u64,
+        load: F,
+    ) -> Result<Arc<RowGroupIndex>>
+    where
+        F: FnOnce() -> Result<RowGroupIndex>,
+    {
+        let key = CacheKey::RowGroupIndex(block_range, offset);
+
+        if let Some(CacheValue::RowGroupIndex(value)) = self.get(&key) {
+            self.row_group_index.hit();
+            return Ok(value);
+        }
+        self.row_group_index.miss();
+
+        let value = Arc::new(load()?);
+        let size = row_group_index_size(&value);
+        self.insert(key, CacheValue::RowGroupIndex(value.clone()), size);
+
+        Ok(value)
+    }
+
+    pub fn stats(&self) -> MetadataCacheStats {
+        let inner = self.inner.lock().unwrap();
+
+        MetadataCacheStats {
+            num_entries: inner.entries.len(),
+            size_bytes: inner.size,
+            budget_bytes: self.budget,
+            parquet_metadata: self.parquet_metadata.stats(),
+            row_group_index: self.row_group_index.stats(),
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<CacheValue> {
+        self.inner.lock().unwrap().get(key)
+    }
+
+    fn insert(&self, key: CacheKey, value: CacheValue, size: usize) {
+        // Entries that don't fit in the whole budget are not cached.
+        if size > self.budget {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        for evicted in inner.insert(key, value, size, self.budget) {
+            match evicted {
+                CacheValue::ParquetMetadata(_) => self.parquet_metadata.evictions.inc(),
+                CacheValue::RowGroupIndex(_) => self.row_group_index.evictions.inc(),
+            }
+        }
+
+        self.size.set(inner.size as i64);
+    }
+}
+
+#[derive(Serialize)]
+pub struct MetadataCacheStats {
+    pub num_entries: usize,
+    /// Estimated size of the cached entries.
+    pub size_bytes: usize,
+    pub budget_bytes: usize,
+    pub parquet_metadata: CacheStats,
+    pub row_group_index: CacheStats,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries that were evicted to stay in the budget.
+    pub evictions: u64,
+    /// Ratio of the lookups that were hits, zero if there were no lookups.
+    pub hit_rate: f64,
+}
+
+/// Counters of a kind of entry, these are exported from `/metrics` labeled by `cache`.
+struct CacheCounters {
+    hits: IntCounter,
+    misses: IntCounter,
+    evictions: IntCounter,
+}
+
+impl CacheCounters {
+    fn new(metrics: &Metrics, cache: &str) -> Self {
+        Self {
+            hits: metrics.cache_hits.with_label_values(&[cache]),
+            misses: metrics.cache_misses.with_label_values(&[cache]),
+            evictions: metrics.cache_evictions.with_label_values(&[cache]),
+        }
+    }
+
+    fn hit(&self) {
+        self.hits.inc();
+    }
+
+    fn miss(&self) {
+        self.misses.inc();
+    }
+
+    fn stats(&self) -> CacheStats {
+        let hits = self.hits.get();
+        let misses = self.misses.get();
+        let total = hits + misses;
+
+        CacheStats {
+            hits,
+            misses,
+            evictions: self.evictions.get(),
+            hit_rate: if total == 0 {
+                0.0
+            } else {
+                hits as f64 / total as f64
+            },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    ParquetMetadata(PathBuf, SystemTime, u64),
+    RowGroupIndex(BlockRange, u64),
+}
+
+#[derive(Clone)]
+enum CacheValue {
+    ParquetMetadata(Arc<ParquetMetadata>),
+    RowGroupIndex(Arc<RowGroupIndex>),
+}
+
+struct Entry {
+    value: CacheValue,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, Entry>,
+    // last_used -> key, the first element is the least recently used entry
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<CacheValue> {
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.last_used);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.order.insert(self.tick, key.clone());
+
+        Some(entry.value.clone())
+    }
+
+    // Returns the values that were evicted to make room for the new entry.
+    fn insert(
+        &mut self,
+        key: CacheKey,
+        value: CacheValue,
+        size: usize,
+        budget: usize,
+    ) -> Vec<CacheValue> {
+        // Another query might have loaded the same entry in the meantime.
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&old.last_used);
+            self.size -= old.size;
+        }
+
+        let mut evicted = Vec::new();
+        while self.size + size > budget {
+            let (_, oldest) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.size -= entry.size;
+            evicted.push(entry.value);
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                last_used: self.tick,
+            },
+        );
+        self.size += size;
+
+        evicted
+    }
+}
+
+fn row_group_index_size(rg_index: &RowGroupIndex) -> usize {
+    let block = rg_index.block.len() * 16;
+    let transaction = rg_index
+        .transaction
+        .iter()
+        .map(|rg| {
+            16 + rg.from_address_filter.0.as_bytes().len()
+                + rg.to_address_filter.0.as_bytes().len()
+                + rg.sighash_filter
+                    .as_ref()
+                    .map_or(0, |filter| filter.0.as_bytes().len())
+        })
+        .sum::<usize>();
+    let log = rg_index
+        .log
+        .iter()
+        .map(|rg| {
+            16 + rg.address_filter.0.as_bytes().len()
+                + rg.topic_filters
+                    .iter()
+                    .map(|filter| filter.0.as_bytes().len())
+                    .sum::<usize>()
+        })
+        .sum::<usize>();
+
+    block + transaction + log
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_rg_index() -> RowGroupIndex {
+        RowGroupIndex {
+            block: Vec::new(),
+            transaction: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let metrics = Metrics::new().unwrap();
+        let cache = MetadataCache::new(2, &metrics);
+
+        for _ in 0..2 {
+            cache
+                .row_group_index(BlockRange(0, 10), 0, || Ok(empty_rg_index()))
+                .unwrap();
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.row_group_index.hits, 1);
+        assert_eq!(stats.row_group_index.misses, 1);
+        assert_eq!(stats.row_group_index.hit_rate, 0.5);
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(text.contains(r#"skar_metadata_cache_hits_total{cache="row_group_index"} 1"#));
+        assert!(text.contains(r#"skar_metadata_cache_misses_total{cache="row_group_index"} 1"#));
+
+        // Insert entries with a size of one so the budget holds two of them.
+        let mut lru = Lru::default();
+        let value = CacheValue::RowGroupIndex(Arc::new(empty_rg_index()));
+        let key = |i| CacheKey::RowGroupIndex(BlockRange(i, i + 1), 0);
+
+        lru.insert(key(0), value.clone(), 1, 2);
+        lru.insert(key(1), value.clone(), 1, 2);
+        assert!(lru.get(&key(0)).is_some());
+        assert_eq!(lru.insert(key(2), value, 1, 2).len(), 1);
+
+        assert!(lru.get(&key(0)).is_some());
+        assert!(lru.get(&key(1)).is_none());
+        assert!(lru.get(&key(2)).is_some());
+        assert_eq!(lru.size, 2);
+    }
+}
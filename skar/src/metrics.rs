@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use skar_ingest::IngestMetrics;
+
+/// Metrics that are served from `/metrics` in the prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    pub ingest: IngestMetrics,
+    /// Last block number that was appended to the in memory data.
+    pub latest_block: IntGauge,
+    /// Distance between the tip reported by the rpc endpoints and `latest_block`.
+    pub tip_lag: IntGauge,
+    pub flush_duration: Histogram,
+    pub folders: IntGauge,
+    pub queries: IntCounter,
+    pub query_duration: Histogram,
+    /// Folders that were read for a query.
+    pub folders_scanned: IntCounter,
+    /// Folders that were skipped for a query because of the filters and indices of the folder.
+    pub folders_pruned: IntCounter,
+    /// Estimated size of the query results.
+    pub result_bytes: IntCounter,
+    /// Lookups of the parquet metadata and row group index caches, labeled by `cache`.
+    pub cache_hits: IntCounterVec,
+    pub cache_misses: IntCounterVec,
+    /// Entries that were evicted from the caches to stay in the budget.
+    pub cache_evictions: IntCounterVec,
+    /// Estimated size of the cached entries.
+    pub cache_size: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let ingest = IngestMetrics::new(&registry).context("create ingest metrics")?;
+
+        let metrics = Self {
+            ingest,
+            latest_block: IntGauge::new(
+                "skar_ingest_latest_block",
+                "Last block number that was appended to the in memory data",
+            )?,
+            tip_lag: IntGauge::new(
+                "skar_ingest_tip_lag_blocks",
+                "Number of blocks between the tip of the chain and the last ingested block",
+            )?,
+            flush_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "skar_flush_duration_seconds",
+                    "Duration of writing the in memory data to a parquet folder",
+                )
+                .buckets(prometheus::exponential_buckets(0.1, 2.0, 10)?),
+            )?,
+            folders: IntGauge::new("skar_folders", "Number of parquet folders")?,
+            queries: IntCounter::new("skar_queries_total", "Queries that were started")?,
+            query_duration: Histogram::with_opts(HistogramOpts::new(
+                "skar_query_duration_seconds",
+                "Duration of executing a query",
+            ))?,
+            folders_scanned: IntCounter::new(
+                "skar_query_folders_scanned_total",
+                "Parquet folders that were read for queries",
+            )?,
+            folders_pruned: IntCounter::new(
+                "skar_query_folders_pruned_total",
+                "Parquet folders that were skipped for queries because of their filters and indices",
+            )?,
+            result_bytes: IntCounter::new(
+                "skar_query_result_bytes_total",
+                "Estimated size of the arrow data that was returned for queries",
+            )?,
+            cache_hits: IntCounterVec::new(
+                Opts::new(
+                    "skar_metadata_cache_hits_total",
+                    "Lookups that were served from the metadata cache",
+                ),
+                &["cache"],
+            )?,
+            cache_misses: IntCounterVec::new(
+                Opts::new(
+                    "skar_metadata_cache_misses_total",
+                    "Lookups that had to read the entry from disk",
+                ),
+                &["cache"],
+            )?,
+            cache_evictions: IntCounterVec::new(
+                Opts::new(
+                    "skar_metadata_cache_evictions_total",
+                    "Entries that were evicted from the metadata cache to stay in its budget",
+                ),
+                &["cache"],
+            )?,
+            cache_size: IntGauge::new(
+                "skar_metadata_cache_size_bytes",
+                "Estimated size of the entries in the metadata cache",
+            )?,
+            registry,
+        };
+
+        metrics.register().context("register metrics")?;
+
+        Ok(metrics)
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.latest_block.clone()))?;
+        self.registry.register(Box::new(self.tip_lag.clone()))?;
+        self.registry
+            .register(Box::new(self.flush_duration.clone()))?;
+        self.registry.register(Box::new(self.folders.clone()))?;
+        self.registry.register(Box::new(self.queries.clone()))?;
+        self.registry
+            .register(Box::new(self.query_duration.clone()))?;
+        self.registry
+            .register(Box::new(self.folders_scanned.clone()))?;
+        self.registry
+            .register(Box::new(self.folders_pruned.clone()))?;
+        self.registry
+            .register(Box::new(self.result_bytes.clone()))?;
+        self.registry.register(Box::new(self.cache_hits.clone()))?;
+        self.registry
+            .register(Box::new(self.cache_misses.clone()))?;
+        self.registry
+            .register(Box::new(self.cache_evictions.clone()))?;
+        self.registry.register(Box::new(self.cache_size.clone()))?;
+
+        Ok(())
+    }
+
+    /// Sets the last ingested block and the lag behind the tip of the chain.
+    pub fn set_latest_block(&self, block_number: u64) {
+        let block_number = block_number as i64;
+
+        self.latest_block.set(block_number);
+        self.tip_lag
+            .set((self.ingest.tip_block.get() - block_number).max(0));
+    }
+
+    /// Encodes all metrics in the prometheus text format.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .context("encode metrics")?;
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.ingest.tip_block.set(120);
+        metrics.set_latest_block(100);
+        metrics.folders_pruned.inc_by(3);
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+
+        assert!(text.contains("skar_ingest_latest_block 100"));
+        assert!(text.contains("skar_ingest_tip_lag_blocks 20"));
+        assert!(text.contains("skar_query_folders_pruned_total 3"));
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    db::{AddressKind, BlockRange, Db},
+    types::Query,
+};
+
+use super::data_provider::RowGroupSelection;
+
+// folder from_block -> indices of the row groups that can match a selection
+type Postings = BTreeMap<u64, BTreeSet<usize>>;
+
+/// Row groups that can match each selection of a query according to the address index.
+pub struct QueryPostings {
+    /// Folders that end at or before this block are covered by the index.
+    covered_to: u64,
+    /// Postings of each log selection, `None` if the selection doesn't select any addresses.
+    logs: Vec<Option<Postings>>,
+    /// Postings of each transaction selection, `None` if the selection doesn't select any addresses.
+    transactions: Vec<Option<Postings>>,
+}
+
+impl QueryPostings {
+    /// Reads the postings of the addresses in the query from the database.
+    ///
+    /// Returns `None` if the index doesn't cover any of the query's block range.
+    pub fn load(db: &Db, query: &Query) -> Result<Option<Self>> {
+        let covered_to = db
+            .address_index_next_block()
+            .context("get address index next block")?;
+
+        let block_range = BlockRange(
+            query.from_block,
+            cmp::min(query.to_block.unwrap_or(u64::MAX), covered_to),
+        );
+
+        if block_range.0 >= block_range.1 {
+            return Ok(None);
+        }
+
+        let logs = query
+            .logs
+            .iter()
+            .map(|selection| {
+                if selection.address.is_empty() {
+                    return Ok(None);
+                }
+
+                load_union(db, AddressKind::Log, &selection.address, block_range).map(Some)
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("load log postings")?;
+
+        let transactions = query
+            .transactions
+            .iter()
+            .map(|selection| {
+                let from = if selection.from.is_empty() {
+                    None
+                } else {
+                    Some(load_union(
+                        db,
+                        AddressKind::TransactionFrom,
+                        &selection.from,
+                        block_range,
+                    )?)
+                };
+                let to = if selection.to.is_empty() {
+                    None
+                } else {
+                    Some(load_union(
+                        db,
+                        AddressKind::TransactionTo,
+                        &selection.to,
+                        block_range,
+                    )?)
+                };
+
+                Ok(match (from, to) {
+                    (Some(from), Some(to)) => Some(intersect(from, to)),
+                    (Some(postings), None) | (None, Some(postings)) => Some(postings),
+                    (None, None) => None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("load transaction postings")?;
+
+        Ok(Some(Self {
+            covered_to,
+            logs,
+            transactions,
+        }))
+    }
+
+    /// Removes the selections that can't match anything in the folder and returns
+    /// the row groups that can match the remaining ones.
+    ///
+    /// `query` should be the query that the postings were loaded for. It is returned as is
+    /// if the folder isn't covered by the index.
+    pub fn prune(&self, query: &Query, block_range: BlockRange) -> (Query, RowGroupSelection) {
+        if block_range.1 > self.covered_to {
+            return (query.clone(), RowGroupSelection::default());
+        }
+
+        let (logs, log_row_groups) = prune_selections(&query.logs, &self.logs, block_range.0);
+        let (transactions, tx_row_groups) =
+            prune_selections(&query.transactions, &self.transactions, block_range.0);
+
+        let query = Query {
+            logs,
+            transactions,
+            from_block: query.from_block,
+            to_block: query.to_block,
+            field_selection: query.field_selection.clone(),
+            include_all_blocks: query.include_all_blocks,
+        };
+
+        let row_groups = RowGroupSelection {
+            log: log_row_groups,
+            transaction: tx_row_groups,
+        };
+
+        (query, row_groups)
+    }
+}
+
+// Returns the selections that can match something in the folder and the union of their row groups.
+//
+// The row groups are `None` if any of the remaining selections doesn't select any addresses.
+fn prune_selections<T: Clone>(
+    selections: &[T],
+    postings: &[Option<Postings>],
+    folder_from: u64,
+) -> (Vec<T>, Option<BTreeSet<usize>>) {
+    let mut out = Vec::new();
+    let mut row_groups = Some(BTreeSet::new());
+
+    for (selection, postings) in selections.iter().zip(postings.iter()) {
+        match postings {
+            Some(postings) => {
+                let folder_row_groups = match postings.get(&folder_from) {
+                    Some(folder_row_groups) => folder_row_groups,
+                    None => continue,
+                };
+
+                if let Some(row_groups) = row_groups.as_mut() {
+                    row_groups.extend(folder_row_groups.iter().copied());
+                }
+            }
+            None => row_groups = None,
+        }
+
+        out.push(selection.clone());
+    }
+
+    (out, row_groups)
+}
+
+fn load_union<A: AsRef<[u8]>>(
+    db: &Db,
+    kind: AddressKind,
+    addresses: &[A],
+    block_range: BlockRange,
+) -> Result<Postings> {
+    let mut union = Postings::new();
+
+    for address in addresses {
+        let postings = db
+            .address_postings(kind, address.as_ref(), block_range)
+            .context("get address postings")?;
+
+        for (folder_from, row_groups) in postings {
+            union
+                .entry(folder_from)
+                .or_default()
+                .extend(row_groups.into_iter().map(|rg| rg as usize));
+        }
+    }
+
+    Ok(union)
+}
+
+fn intersect(a: Postings, mut b: Postings) -> Postings {
+    a.into_iter()
+        .filter_map(|(folder_from, row_groups)| {
+            let other = b.remove(&folder_from)?;
+            let row_groups = row_groups
+                .intersection(&other)
+                .copied()
+                .collect::<BTreeSet<_>>();
+
+            if row_groups.is_empty() {
+                None
+            } else {
+                Some((folder_from, row_groups))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{LogSelection, TransactionSelection};
+
+    fn postings(folders: &[(u64, &[usize])]) -> Postings {
+        folders
+            .iter()
+            .map(|(folder_from, row_groups)| (*folder_from, row_groups.iter().copied().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_intersect() {
+        let a = postings(&[(0, &[0, 1]), (10, &[2]), (20, &[1])]);
+        let b = postings(&[(0, &[1, 2]), (10, &[3]), (30, &[1])]);
+
+        assert_eq!(intersect(a, b), postings(&[(0, &[1])]));
+    }
+
+    #[test]
+    fn test_prune() {
+        let log_selection = LogSelection {
+            address: vec![[1; 20].into()],
+            topics: Default::default(),
+        };
+        let tx_selection = TransactionSelection {
+            from: vec![],
+            to: vec![],
+            sighash: vec![],
+            status: None,
+        };
+
+        let query = Query {
+            from_block: 0,
+            to_block: None,
+            field_selection: Default::default(),
+            include_all_blocks: false,
+            logs: vec![log_selection.clone(), log_selection],
+            transactions: vec![tx_selection],
+        };
+
+        let query_postings = QueryPostings {
+            covered_to: 20,
+            logs: vec![
+                Some(postings(&[(0, &[0, 1])])),
+                Some(postings(&[(0, &[3]), (10, &[2])])),
+            ],
+            transactions: vec![None],
+        };
+
+        let (pruned, row_groups) = query_postings.prune(&query, BlockRange(0, 10));
+        assert_eq!(pruned.logs.len(), 2);
+        assert_eq!(row_groups.log, Some([0, 1, 3].into_iter().collect()));
+        assert_eq!(pruned.transactions.len(), 1);
+        assert_eq!(row_groups.transaction, None);
+
+        let (pruned, row_groups) = query_postings.prune(&query, BlockRange(10, 20));
+        assert_eq!(pruned.logs.len(), 1);
+        assert_eq!(row_groups.log, Some([2].into_iter().collect()));
+
+        let (pruned, row_groups) = query_postings.prune(&query, BlockRange(20, 30));
+        assert_eq!(pruned.logs.len(), 2);
+        assert_eq!(row_groups.log, None);
+        assert_eq!(row_groups.transaction, None);
+    }
+}
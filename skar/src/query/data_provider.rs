@@ -4,18 +4,23 @@ use anyhow::{anyhow, Context, Result};
 use arrow2::{
     array::Array,
     chunk::Chunk,
-    datatypes::SchemaRef,
+    compute::aggregate::estimated_bytes_size,
+    datatypes::{Schema, SchemaRef},
     io::parquet::{self, read::ArrayIter},
 };
 use rayon::prelude::*;
+use tokio_util::sync::CancellationToken;
 use wyhash::wyhash;
 
+use super::execution::check_cancelled;
+use super::explain::{TablePlan, TablePlans};
 use crate::{
     db::{BlockRowGroupIndex, LogRowGroupIndex, RowGroupIndex, TransactionRowGroupIndex},
+    metadata_cache::MetadataCache,
     open_file_reader::open_file_reader,
     schema,
     state::{ArrowChunk, InMemory},
-    types::QueryContext,
+    types::{Query, QueryContext},
 };
 
 type Data = Vec<ArrowBatch>;
@@ -57,45 +62,45 @@ pub struct InMemDataProvider<'in_mem> {
 }
 
 impl<'in_mem> DataProvider for InMemDataProvider<'in_mem> {
-    fn load_logs(&self, _ctx: &QueryContext) -> Result<Data> {
+    fn load_logs(&self, ctx: &QueryContext) -> Result<Data> {
         let schema_ref = schema::log();
 
         Ok(self
             .in_mem
             .logs
-            .data
-            .iter()
-            .map(|chunk| ArrowBatch {
+            .iter_indexed()
+            .filter(|(_, index)| !index.map_or(false, |index| can_skip_log_row_group(ctx, index)))
+            .map(|(chunk, _)| ArrowBatch {
                 chunk: chunk.clone(),
                 schema: schema_ref.clone(),
             })
             .collect())
     }
 
-    fn load_transactions(&self, _ctx: &QueryContext) -> Result<Data> {
+    fn load_transactions(&self, ctx: &QueryContext) -> Result<Data> {
         let schema_ref = schema::transaction();
 
         Ok(self
             .in_mem
             .transactions
-            .data
-            .iter()
-            .map(|chunk| ArrowBatch {
+            .iter_indexed()
+            .filter(|(_, index)| !index.map_or(false, |index| can_skip_tx_row_group(ctx, index)))
+            .map(|(chunk, _)| ArrowBatch {
                 chunk: chunk.clone(),
                 schema: schema_ref.clone(),
             })
             .collect())
     }
 
-    fn load_blocks(&self, _ctx: &QueryContext) -> Result<Data> {
+    fn load_blocks(&self, ctx: &QueryContext) -> Result<Data> {
         let schema_ref = schema::block_header();
 
         Ok(self
             .in_mem
             .blocks
-            .data
-            .iter()
-            .map(|chunk| ArrowBatch {
+            .iter_indexed()
+            .filter(|(_, index)| !index.map_or(false, |index| can_skip_block_row_group(ctx, index)))
+            .map(|(chunk, _)| ArrowBatch {
                 chunk: chunk.clone(),
                 schema: schema_ref.clone(),
             })
@@ -103,9 +108,93 @@ impl<'in_mem> DataProvider for InMemDataProvider<'in_mem> {
     }
 }
 
+impl<'in_mem> InMemDataProvider<'in_mem> {
+    /// Returns the chunks of each table that the query reads and their estimated size.
+    ///
+    /// See [`plan_row_groups`] for how the chunks of the joined tables are counted.
+    pub fn plan(&self, query: &Query) -> TablePlans {
+        let logs = self.in_mem.logs.iter_indexed().collect::<Vec<_>>();
+        let transactions = self.in_mem.transactions.iter_indexed().collect::<Vec<_>>();
+        let blocks = self.in_mem.blocks.iter_indexed().collect::<Vec<_>>();
+
+        let read = plan_row_groups(
+            query,
+            &logs.iter().map(|(_, index)| *index).collect::<Vec<_>>(),
+            &transactions
+                .iter()
+                .map(|(_, index)| *index)
+                .collect::<Vec<_>>(),
+            &blocks.iter().map(|(_, index)| *index).collect::<Vec<_>>(),
+            &RowGroupSelection::default(),
+        );
+
+        let mut log_fields = query.field_selection.log.clone();
+        log_fields.extend(LOG_QUERY_FIELDS.iter().map(|s| s.to_string()));
+        let mut tx_fields = query.field_selection.transaction.clone();
+        tx_fields.extend(TX_QUERY_FIELDS.iter().map(|s| s.to_string()));
+        let mut block_fields = query.field_selection.block.clone();
+        block_fields.extend(BLOCK_QUERY_FIELDS.iter().map(|s| s.to_string()));
+
+        TablePlans::new(
+            chunk_plan(&logs, &read.log, &schema::log(), &log_fields),
+            chunk_plan(
+                &transactions,
+                &read.transaction,
+                &schema::transaction(),
+                &tx_fields,
+            ),
+            chunk_plan(&blocks, &read.block, &schema::block_header(), &block_fields),
+        )
+    }
+}
+
+// Sums the estimated size of the selected columns of the chunks that are read.
+fn chunk_plan<I>(
+    chunks: &[(&Arc<ArrowChunk>, Option<&I>)],
+    read: &[usize],
+    schema: &Schema,
+    field_selection: &BTreeSet<String>,
+) -> TablePlan {
+    let estimated_bytes = read
+        .iter()
+        .flat_map(|&i| chunks[i].0.columns().iter().zip(schema.fields.iter()))
+        .filter(|(_, field)| field_selection.contains(&field.name))
+        .map(|(col, _)| estimated_bytes_size(col.as_ref()) as u64)
+        .sum();
+
+    TablePlan {
+        row_groups_read: read.len(),
+        row_groups_skipped: chunks.len() - read.len(),
+        estimated_bytes,
+    }
+}
+
 pub struct ParquetDataProvider {
     pub path: PathBuf,
-    pub rg_index: RowGroupIndex,
+    pub rg_index: Arc<RowGroupIndex>,
+    pub row_groups: RowGroupSelection,
+    pub metadata_cache: Arc<MetadataCache>,
+}
+
+/// Row groups that can match the selections of the query according to the address index.
+///
+/// `None` means all row groups of that table can match.
+#[derive(Default, Debug)]
+pub struct RowGroupSelection {
+    pub log: Option<BTreeSet<usize>>,
+    pub transaction: Option<BTreeSet<usize>>,
+}
+
+impl RowGroupSelection {
+    fn contains_log(&self, rg_idx: usize) -> bool {
+        self.log.as_ref().map_or(true, |log| log.contains(&rg_idx))
+    }
+
+    fn contains_transaction(&self, rg_idx: usize) -> bool {
+        self.transaction
+            .as_ref()
+            .map_or(true, |transaction| transaction.contains(&rg_idx))
+    }
 }
 
 fn deserialize_parallel(iters: &mut [ArrayIter<'static>]) -> Result<Chunk<Box<dyn Array>>> {
@@ -120,6 +209,7 @@ fn deserialize_parallel(iters: &mut [ArrayIter<'static>]) -> Result<Chunk<Box<dy
 impl ParquetDataProvider {
     fn load_table(
         &self,
+        cancel: &CancellationToken,
         field_selection: &BTreeSet<String>,
         row_groups: &[usize],
         table_name: &str,
@@ -129,15 +219,20 @@ impl ParquetDataProvider {
 
         let mut reader = open_file_reader(&path).context("open parquet file")?;
 
-        let metadata =
-            parquet::read::read_metadata(&mut reader).context("read parquet metadata")?;
-        let schema = parquet::read::infer_schema(&metadata).context("infer parquet schema")?;
+        let metadata = self
+            .metadata_cache
+            .parquet_metadata(&path, &mut reader)
+            .context("get parquet metadata")?;
 
-        let schema = schema.filter(|_index, field| field_selection.contains(&field.name));
+        let schema = metadata
+            .schema
+            .clone()
+            .filter(|_index, field| field_selection.contains(&field.name));
 
         let row_groups = metadata
+            .metadata
             .row_groups
-            .into_iter()
+            .iter()
             .enumerate()
             .filter(|(index, _)| row_groups.contains(index))
             .map(|(_, row_group)| row_group)
@@ -146,11 +241,13 @@ impl ParquetDataProvider {
         let mut chunks = Vec::new();
 
         for rg in row_groups {
+            check_cancelled(cancel)?;
+
             let chunk_size = usize::MAX;
 
             let mut columns = parquet::read::read_columns_many(
                 &mut reader,
-                &rg,
+                rg,
                 schema.fields.clone(),
                 Some(chunk_size),
                 None,
@@ -177,6 +274,100 @@ impl ParquetDataProvider {
     }
 }
 
+impl ParquetDataProvider {
+    /// Returns the row groups of each table that the query reads and the compressed size of
+    /// their selected columns.
+    ///
+    /// Only the footers of the parquet files are read. See [`plan_row_groups`] for how the
+    /// row groups of the joined tables are counted.
+    pub fn plan(&self, query: &Query) -> Result<TablePlans> {
+        let read = plan_row_groups(
+            query,
+            &self.rg_index.log.iter().map(Some).collect::<Vec<_>>(),
+            &self
+                .rg_index
+                .transaction
+                .iter()
+                .map(Some)
+                .collect::<Vec<_>>(),
+            &self.rg_index.block.iter().map(Some).collect::<Vec<_>>(),
+            &self.row_groups,
+        );
+
+        let mut log_fields = query.field_selection.log.clone();
+        log_fields.extend(LOG_QUERY_FIELDS.iter().map(|s| s.to_string()));
+        let mut tx_fields = query.field_selection.transaction.clone();
+        tx_fields.extend(TX_QUERY_FIELDS.iter().map(|s| s.to_string()));
+        let mut block_fields = query.field_selection.block.clone();
+        block_fields.extend(BLOCK_QUERY_FIELDS.iter().map(|s| s.to_string()));
+
+        let logs = self
+            .table_plan(&read.log, self.rg_index.log.len(), &log_fields, "logs")
+            .context("plan logs")?;
+        let transactions = self
+            .table_plan(
+                &read.transaction,
+                self.rg_index.transaction.len(),
+                &tx_fields,
+                "transactions",
+            )
+            .context("plan transactions")?;
+        let blocks = self
+            .table_plan(
+                &read.block,
+                self.rg_index.block.len(),
+                &block_fields,
+                "blocks",
+            )
+            .context("plan blocks")?;
+
+        Ok(TablePlans::new(logs, transactions, blocks))
+    }
+
+    fn table_plan(
+        &self,
+        row_groups: &[usize],
+        num_row_groups: usize,
+        field_selection: &BTreeSet<String>,
+        table_name: &str,
+    ) -> Result<TablePlan> {
+        let mut plan = TablePlan {
+            row_groups_read: row_groups.len(),
+            row_groups_skipped: num_row_groups - row_groups.len(),
+            estimated_bytes: 0,
+        };
+
+        if row_groups.is_empty() {
+            return Ok(plan);
+        }
+
+        let mut path = self.path.clone();
+        path.push(format!("{table_name}.parquet"));
+
+        let mut reader = open_file_reader(&path).context("open parquet file")?;
+
+        let metadata = self
+            .metadata_cache
+            .parquet_metadata(&path, &mut reader)
+            .context("get parquet metadata")?;
+
+        plan.estimated_bytes = row_groups
+            .iter()
+            .filter_map(|&i| metadata.metadata.row_groups.get(i))
+            .flat_map(|rg| rg.columns().iter())
+            .filter(|col| {
+                col.descriptor()
+                    .path_in_schema
+                    .first()
+                    .map_or(false, |name| field_selection.contains(name))
+            })
+            .map(|col| col.compressed_size() as u64)
+            .sum();
+
+        Ok(plan)
+    }
+}
+
 impl DataProvider for ParquetDataProvider {
     fn load_logs(&self, ctx: &QueryContext) -> Result<Data> {
         let row_groups = self
@@ -185,7 +376,7 @@ impl DataProvider for ParquetDataProvider {
             .iter()
             .enumerate()
             .filter_map(|(i, rg_index)| {
-                if can_skip_log_row_group(ctx, rg_index) {
+                if can_skip_log_row_group(ctx, rg_index) || !self.row_groups.contains_log(i) {
                     None
                 } else {
                     Some(i)
@@ -196,7 +387,7 @@ impl DataProvider for ParquetDataProvider {
         let mut field_selection = ctx.query.field_selection.log.clone();
         field_selection.extend(LOG_QUERY_FIELDS.iter().map(|s| s.to_string()));
 
-        self.load_table(&field_selection, &row_groups, "logs")
+        self.load_table(&ctx.cancel, &field_selection, &row_groups, "logs")
     }
 
     fn load_transactions(&self, ctx: &QueryContext) -> Result<Data> {
@@ -206,7 +397,12 @@ impl DataProvider for ParquetDataProvider {
             .iter()
             .enumerate()
             .filter_map(|(i, rg_index)| {
-                if can_skip_tx_row_group(ctx, rg_index) {
+                // Transactions that are joined to the selected logs are loaded even if the address
+                // index rules out the row group for the transaction selections.
+                if can_skip_tx_row_group(ctx, rg_index)
+                    || (!self.row_groups.contains_transaction(i)
+                        && !contains_joined_tx(ctx, rg_index))
+                {
                     None
                 } else {
                     Some(i)
@@ -217,7 +413,7 @@ impl DataProvider for ParquetDataProvider {
         let mut field_selection = ctx.query.field_selection.transaction.clone();
         field_selection.extend(TX_QUERY_FIELDS.iter().map(|s| s.to_string()));
 
-        self.load_table(&field_selection, &row_groups, "transactions")
+        self.load_table(&ctx.cancel, &field_selection, &row_groups, "transactions")
     }
 
     fn load_blocks(&self, ctx: &QueryContext) -> Result<Data> {
@@ -238,10 +434,119 @@ impl DataProvider for ParquetDataProvider {
         let mut field_selection = ctx.query.field_selection.block.clone();
         field_selection.extend(BLOCK_QUERY_FIELDS.iter().map(|s| s.to_string()));
 
-        self.load_table(&field_selection, &row_groups, "blocks")
+        self.load_table(&ctx.cancel, &field_selection, &row_groups, "blocks")
+    }
+}
+
+/// Indices of the row groups of each table that a query reads.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReadRowGroups {
+    pub log: Vec<usize>,
+    pub transaction: Vec<usize>,
+    pub block: Vec<usize>,
+}
+
+/// Decides which row groups the query reads using the same checks as the execution.
+///
+/// Transactions and blocks that are joined to the selected logs and transactions are only known
+/// after the data is decoded, so a row group of a joined table is counted as read if it overlaps
+/// the block range of a row group that is read from the tables before it. This makes the result
+/// an upper bound for the transactions and blocks.
+///
+/// Row groups without an index are always read, same as the chunks of the in memory data
+/// that are not sealed yet.
+pub fn plan_row_groups(
+    query: &Query,
+    log_index: &[Option<&LogRowGroupIndex>],
+    tx_index: &[Option<&TransactionRowGroupIndex>],
+    block_index: &[Option<&BlockRowGroupIndex>],
+    selection: &RowGroupSelection,
+) -> ReadRowGroups {
+    // Without the joined sets, the checks only consider the selections of the query.
+    let ctx = QueryContext {
+        query: query.clone(),
+        cancel: CancellationToken::new(),
+        transaction_set: BTreeSet::new(),
+        block_set: BTreeSet::new(),
+    };
+
+    let log = if query.logs.is_empty() {
+        Vec::new()
+    } else {
+        read_row_groups(log_index, |i, index| {
+            !can_skip_log_row_group(&ctx, index) && selection.contains_log(i)
+        })
+    };
+
+    // Block ranges of the row groups that are read, `None` if the row group has no index.
+    let mut joined = log
+        .iter()
+        .map(|&i| log_index[i].map(|index| (index.min_block_num, index.max_block_num)))
+        .collect::<Vec<_>>();
+
+    let transaction = if query.transactions.is_empty() && joined.is_empty() {
+        Vec::new()
+    } else {
+        read_row_groups(tx_index, |i, index| {
+            (!can_skip_tx_row_group(&ctx, index) && selection.contains_transaction(i))
+                || overlaps_joined(query, &joined, index.min_block_num, index.max_block_num)
+        })
+    };
+
+    joined.extend(
+        transaction
+            .iter()
+            .map(|&i| tx_index[i].map(|index| (index.min_block_num, index.max_block_num))),
+    );
+
+    let block = if query.field_selection.block.is_empty()
+        || (!query.include_all_blocks && joined.is_empty())
+    {
+        Vec::new()
+    } else {
+        read_row_groups(block_index, |_, index| {
+            !can_skip_block_row_group(&ctx, index)
+                || overlaps_joined(query, &joined, index.min_block_num, index.max_block_num)
+        })
+    };
+
+    ReadRowGroups {
+        log,
+        transaction,
+        block,
     }
 }
 
+// Returns the indices of the row groups that `read` returns true for, along with the ones
+// that don't have an index.
+fn read_row_groups<I>(index: &[Option<&I>], read: impl Fn(usize, &I) -> bool) -> Vec<usize> {
+    index
+        .iter()
+        .enumerate()
+        .filter(|(i, index)| index.map_or(true, |index| read(*i, index)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Returns true if the block range is in the range of the query and overlaps one of the
+// joined ranges. Joined row groups without an index overlap everything.
+fn overlaps_joined(
+    query: &Query,
+    joined: &[Option<(u64, u64)>],
+    min_block_num: u64,
+    max_block_num: u64,
+) -> bool {
+    if query.from_block > max_block_num || query.to_block.map_or(false, |to| to <= min_block_num) {
+        return false;
+    }
+
+    joined.iter().any(|range| {
+        range.map_or(true, |(min, max)| {
+            min <= max_block_num && min_block_num <= max
+        })
+    })
+}
+
 fn can_skip_block_row_group(ctx: &QueryContext, rg_index: &BlockRowGroupIndex) -> bool {
     let from_block = ctx.query.from_block;
     let to_block = ctx.query.to_block;
@@ -287,8 +592,19 @@ fn can_skip_tx_row_group(ctx: &QueryContext, rg_index: &TransactionRowGroupIndex
                 let hash = wyhash(addr.as_slice(), 0);
                 rg_index.to_address_filter.0.contains_hash(hash)
             });
-        contains_from && contains_to
-    }) && !ctx.transaction_set.iter().any(|&(block_num, _)| {
+        let contains_sighash = tx.sighash.is_empty()
+            || rg_index.sighash_filter.as_ref().map_or(true, |filter| {
+                tx.sighash.iter().any(|sighash| {
+                    let hash = wyhash(sighash.as_slice(), 0);
+                    filter.0.contains_hash(hash)
+                })
+            });
+        contains_from && contains_to && contains_sighash
+    }) && !contains_joined_tx(ctx, rg_index)
+}
+
+fn contains_joined_tx(ctx: &QueryContext, rg_index: &TransactionRowGroupIndex) -> bool {
+    ctx.transaction_set.iter().any(|&(block_num, _)| {
         block_num >= rg_index.min_block_num && block_num <= rg_index.max_block_num
     })
 }
@@ -356,7 +672,11 @@ mod tests {
     use sbbf_rs_safe::Filter;
 
     use crate::{
+        config::{BloomFilterConfig, FilterConfig},
         db::BloomFilter,
+        schema::data_to_batches,
+        state::MAX_TAIL_CHUNKS,
+        tests::{batch_data, FIXTURE_BLOCK},
         types::{FieldSelection, LogSelection, Query, TransactionSelection},
     };
 
@@ -371,6 +691,7 @@ mod tests {
          -> bool {
             can_skip_block_row_group(
                 &QueryContext {
+                    cancel: Default::default(),
                     query: Query {
                         logs: Vec::new(),
                         transactions: Vec::new(),
@@ -407,6 +728,7 @@ mod tests {
          -> bool {
             can_skip_block_row_group(
                 &QueryContext {
+                    cancel: Default::default(),
                     query: Query {
                         logs: Vec::new(),
                         transactions: Vec::new(),
@@ -442,6 +764,7 @@ mod tests {
     ) -> bool {
         can_skip_tx_row_group(
             &QueryContext {
+                cancel: Default::default(),
                 query: Query {
                     logs: Vec::new(),
                     transactions,
@@ -470,10 +793,56 @@ mod tests {
                         filter
                     },
                 )),
+                sighash_filter: None,
             },
         )
     }
 
+    #[test]
+    fn test_skip_tx_row_group_sighash() {
+        let sighash = hex_literal::hex!("a9059cbb");
+
+        let can_skip = |query_sighash: [u8; 4], sighash_filter: Option<BloomFilter>| -> bool {
+            can_skip_tx_row_group(
+                &QueryContext {
+                    cancel: Default::default(),
+                    query: Query {
+                        logs: Vec::new(),
+                        transactions: vec![TransactionSelection {
+                            from: vec![],
+                            to: vec![],
+                            sighash: vec![query_sighash.into()],
+                            status: None,
+                        }],
+                        include_all_blocks: false,
+                        field_selection: FieldSelection::default(),
+                        from_block: 0,
+                        to_block: None,
+                    },
+                    transaction_set: BTreeSet::new(),
+                    block_set: BTreeSet::new(),
+                },
+                &TransactionRowGroupIndex {
+                    min_block_num: 9,
+                    max_block_num: 23,
+                    from_address_filter: BloomFilter(Filter::new(100, 0)),
+                    to_address_filter: BloomFilter(Filter::new(100, 0)),
+                    sighash_filter,
+                },
+            )
+        };
+
+        let filter = || {
+            let mut filter = Filter::new(100, 1);
+            filter.insert_hash(wyhash(&sighash, 0));
+            Some(BloomFilter(filter))
+        };
+
+        assert!(!can_skip(sighash, filter()));
+        assert!(can_skip(hex_literal::hex!("12345678"), filter()));
+        assert!(!can_skip(hex_literal::hex!("12345678"), None));
+    }
+
     #[test]
     fn test_skip_tx_row_group() {
         assert!(can_skip_tx_rg_test(
@@ -646,6 +1015,7 @@ mod tests {
     ) -> bool {
         can_skip_log_row_group(
             &QueryContext {
+                cancel: Default::default(),
                 query: Query {
                     logs,
                     transactions: Vec::new(),
@@ -820,4 +1190,177 @@ mod tests {
             ],
         ));
     }
+
+    #[test]
+    fn test_plan_row_groups() {
+        let address = hex_literal::hex!("48bBf1c68037BF35b0eB090f1B5E0fa52F690502");
+        let filter = |addrs: &[&[u8]]| {
+            BloomFilter(
+                addrs
+                    .iter()
+                    .fold(Filter::new(100, addrs.len()), |mut filter, addr| {
+                        filter.insert_hash(wyhash(addr, 0));
+                        filter
+                    }),
+            )
+        };
+        let log_rg = |min_block_num, max_block_num, addrs: &[&[u8]]| LogRowGroupIndex {
+            min_block_num,
+            max_block_num,
+            address_filter: filter(addrs),
+            topic_filters: [filter(&[]), filter(&[]), filter(&[]), filter(&[])],
+        };
+        let tx_rg = |min_block_num, max_block_num| TransactionRowGroupIndex {
+            min_block_num,
+            max_block_num,
+            from_address_filter: filter(&[]),
+            to_address_filter: filter(&[]),
+            sighash_filter: None,
+        };
+
+        let logs = [log_rg(0, 9, &[&address]), log_rg(10, 19, &[])];
+        let transactions = [tx_rg(0, 9), tx_rg(10, 19)];
+        let blocks = [
+            BlockRowGroupIndex {
+                min_block_num: 0,
+                max_block_num: 9,
+            },
+            BlockRowGroupIndex {
+                min_block_num: 10,
+                max_block_num: 19,
+            },
+        ];
+
+        let mut query = Query {
+            from_block: 0,
+            to_block: None,
+            logs: vec![LogSelection {
+                address: vec![address.try_into().unwrap()],
+                topics: Default::default(),
+            }],
+            transactions: Vec::new(),
+            field_selection: FieldSelection::default(),
+            include_all_blocks: false,
+        };
+
+        let plan = |query: &Query, unsealed_log: Option<&LogRowGroupIndex>| {
+            let mut log_index = logs.iter().map(Some).collect::<Vec<_>>();
+            log_index.push(unsealed_log);
+
+            plan_row_groups(
+                query,
+                &log_index,
+                &transactions.iter().map(Some).collect::<Vec<_>>(),
+                &blocks.iter().map(Some).collect::<Vec<_>>(),
+                &RowGroupSelection::default(),
+            )
+        };
+
+        // The block ranges of the unsealed chunk are not known so it is read and it overlaps
+        // everything, but no blocks are read without a block field selection.
+        assert_eq!(
+            plan(&query, None),
+            ReadRowGroups {
+                log: vec![0, 2],
+                transaction: vec![0, 1],
+                block: Vec::new(),
+            }
+        );
+
+        query.field_selection.block.insert("number".to_owned());
+        let log_index = log_rg(20, 29, &[]);
+        assert_eq!(
+            plan(&query, Some(&log_index)),
+            ReadRowGroups {
+                log: vec![0],
+                transaction: vec![0],
+                block: vec![0],
+            }
+        );
+
+        query.logs[0].address = Vec::new();
+        query.from_block = 10;
+        assert_eq!(
+            plan(&query, Some(&log_index)),
+            ReadRowGroups {
+                log: vec![1, 2],
+                transaction: vec![1],
+                block: vec![1],
+            }
+        );
+    }
+
+    #[test]
+    fn test_in_mem_skip_sealed_log_chunk() {
+        let batches = data_to_batches(batch_data(FIXTURE_BLOCK));
+        let logs: Arc<ArrowChunk> = Arc::new(batches.logs);
+
+        let cfg = BloomFilterConfig {
+            row_group_address: FilterConfig {
+                bits_per_key: 32,
+                max_keys: None,
+            },
+            ..Default::default()
+        };
+
+        let in_mem_with = |cfg: &BloomFilterConfig| {
+            let mut in_mem = InMemory {
+                from_block: 12911679,
+                to_block: 12911680,
+                ..Default::default()
+            };
+            // Fill the tail so it is sealed and indexed.
+            for _ in 0..MAX_TAIL_CHUNKS {
+                in_mem.logs.extend(logs.clone(), cfg).unwrap();
+            }
+            in_mem
+        };
+
+        let in_mem = in_mem_with(&cfg);
+        let default_in_mem = in_mem_with(&BloomFilterConfig::default());
+
+        let index = |in_mem: &InMemory| {
+            let chunks = in_mem.logs.iter_indexed().collect::<Vec<_>>();
+            assert_eq!(chunks.len(), 1);
+            chunks[0].1.unwrap().address_filter.0.as_bytes().len()
+        };
+        // The filters of the sealed chunk are sized with the given config.
+        assert!(index(&in_mem) > index(&default_in_mem));
+
+        let load = |address: [u8; 20], topic0: [u8; 32]| {
+            InMemDataProvider { in_mem: &in_mem }
+                .load_logs(&QueryContext {
+                    cancel: Default::default(),
+                    query: Query {
+                        logs: vec![LogSelection {
+                            address: vec![address.try_into().unwrap()],
+                            topics: [vec![topic0.try_into().unwrap()]].into_iter().collect(),
+                        }],
+                        transactions: Vec::new(),
+                        include_all_blocks: false,
+                        field_selection: FieldSelection::default(),
+                        from_block: 12911679,
+                        to_block: None,
+                    },
+                    transaction_set: BTreeSet::new(),
+                    block_set: BTreeSet::new(),
+                })
+                .unwrap()
+                .len()
+        };
+
+        let address = hex_literal::hex!("1f573d6fb3f13d689ff844b4ce37794d79a7ff1c");
+        let transfer =
+            hex_literal::hex!("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
+
+        assert_eq!(load(address, transfer), 1);
+        assert_eq!(
+            load(
+                hex_literal::hex!("48bBf1c68037BF35b0eB090f1B5E0fa52F690502"),
+                transfer
+            ),
+            0
+        );
+        assert_eq!(load(address, [7; 32]), 0);
+    }
 }
@@ -4,7 +4,7 @@ use crate::{
     state::ArrowChunk,
     types::{LogSelection, Query, QueryContext, QueryResultData, TransactionSelection},
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use arrow2::{
     array::{Array, BinaryArray, BooleanArray, MutableBooleanArray, UInt64Array, UInt8Array},
     bitmap::{Bitmap, MutableBitmap},
@@ -14,16 +14,24 @@ use arrow2::{
     scalar::PrimitiveScalar,
 };
 use rayon::prelude::*;
+use tokio_util::sync::CancellationToken;
 
 use super::data_provider::{ArrowBatch, DataProvider};
 
-pub fn execute_query(provider: &dyn DataProvider, query: &Query) -> Result<QueryResultData> {
+pub fn execute_query(
+    provider: &dyn DataProvider,
+    query: &Query,
+    cancel: &CancellationToken,
+) -> Result<QueryResultData> {
     let mut ctx = QueryContext {
         query: query.clone(),
+        cancel: cancel.clone(),
         block_set: BTreeSet::<u64>::new(),
         transaction_set: BTreeSet::<(u64, u64)>::new(),
     };
 
+    check_cancelled(cancel)?;
+
     let logs = if !query.logs.is_empty() {
         let log_data = provider.load_logs(&ctx).context("load log data")?;
         query_logs(
@@ -37,6 +45,8 @@ pub fn execute_query(provider: &dyn DataProvider, query: &Query) -> Result<Query
         Vec::new()
     };
 
+    check_cancelled(cancel)?;
+
     let transactions = if !query.transactions.is_empty() || !ctx.transaction_set.is_empty() {
         let tx_data = provider
             .load_transactions(&ctx)
@@ -47,6 +57,8 @@ pub fn execute_query(provider: &dyn DataProvider, query: &Query) -> Result<Query
         Vec::new()
     };
 
+    check_cancelled(cancel)?;
+
     let blocks = if !query.field_selection.block.is_empty()
         && (query.include_all_blocks || !ctx.block_set.is_empty())
     {
@@ -63,6 +75,15 @@ pub fn execute_query(provider: &dyn DataProvider, query: &Query) -> Result<Query
     })
 }
 
+/// Returns an error if the query was cancelled.
+pub fn check_cancelled(cancel: &CancellationToken) -> Result<()> {
+    if cancel.is_cancelled() {
+        return Err(anyhow!("query was cancelled"));
+    }
+
+    Ok(())
+}
+
 fn query_logs(
     data: Vec<ArrowBatch>,
     query: &Query,
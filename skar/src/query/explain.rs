@@ -0,0 +1,182 @@
+use std::{
+    cmp,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{db::BlockRange, state::State, types::Query};
+
+use super::{
+    address_index::QueryPostings,
+    data_provider::{InMemDataProvider, ParquetDataProvider},
+    handler::{next_block, prune_folder},
+};
+
+/// Plan of a query that is returned from `/query/explain`.
+#[derive(Serialize, Debug)]
+pub struct QueryPlan {
+    pub folders: Vec<FolderPlan>,
+    /// Plan of the in memory data, `None` if the query doesn't reach it.
+    pub in_memory: Option<TablePlans>,
+    pub num_folders: usize,
+    /// Folders that are skipped because their filters or the address index rule out the query.
+    pub num_pruned_folders: usize,
+    /// Sum of the estimated bytes of the folders and the in memory data.
+    pub estimated_bytes: u64,
+    /// The plan covers the blocks before this one.
+    pub next_block: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FolderPlan {
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Nothing is read from the folder.
+    pub pruned: bool,
+    #[serde(flatten)]
+    pub tables: TablePlans,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct TablePlans {
+    pub logs: TablePlan,
+    pub transactions: TablePlan,
+    pub blocks: TablePlan,
+    pub estimated_bytes: u64,
+}
+
+/// Row groups of a table that are read or skipped.
+///
+/// For the in memory data, these are the chunks of the table.
+#[derive(Serialize, Debug, Default)]
+pub struct TablePlan {
+    pub row_groups_read: usize,
+    pub row_groups_skipped: usize,
+    /// Compressed size of the selected columns of the row groups that are read.
+    ///
+    /// For the in memory data, this is the estimated size of the selected columns.
+    pub estimated_bytes: u64,
+}
+
+impl TablePlans {
+    pub fn new(logs: TablePlan, transactions: TablePlan, blocks: TablePlan) -> Self {
+        Self {
+            estimated_bytes: logs.estimated_bytes
+                + transactions.estimated_bytes
+                + blocks.estimated_bytes,
+            logs,
+            transactions,
+            blocks,
+        }
+    }
+}
+
+/// Runs the planning part of the query on each folder and the in memory data.
+///
+/// Uses the same pruning and row group checks as the execution, the plan of the transactions and
+/// blocks is an upper bound since the rows that are joined to the logs and transactions are
+/// only known after the data is decoded.
+pub fn explain_query(
+    state: &State,
+    parquet_path: &Path,
+    query: &Query,
+    time_limit: Duration,
+) -> Result<QueryPlan> {
+    let start_time = Instant::now();
+    let to_block = query.to_block.unwrap_or(u64::MAX);
+
+    // Folders are only planned up to the in memory data, so the blocks of a folder that is
+    // being written are not counted twice.
+    let in_mem = state.in_mem.load_full();
+    let folders_to = cmp::min(to_block, in_mem.from_block);
+
+    let mut plan = QueryPlan {
+        folders: Vec::new(),
+        in_memory: None,
+        num_folders: 0,
+        num_pruned_folders: 0,
+        estimated_bytes: 0,
+        next_block: query.from_block,
+    };
+
+    let folder_index_iterator = if query.from_block < folders_to {
+        state
+            .db
+            .iterate_folder_indices(BlockRange(query.from_block, folders_to))
+            .context("start folder index iterator")?
+    } else {
+        None
+    };
+
+    if let Some(mut folder_index_iterator) = folder_index_iterator {
+        let postings = QueryPostings::load(&state.db, query).context("load address postings")?;
+
+        while let Some(folder_index) = folder_index_iterator.next() {
+            if start_time.elapsed() >= time_limit {
+                return Ok(plan);
+            }
+
+            let folder_index = folder_index.context("failed to read folder index")?;
+            let block_range = folder_index.block_range;
+
+            let folder = match prune_folder(query, postings.as_ref(), &folder_index) {
+                Some((pruned_query, row_groups)) => {
+                    let rg_index = state
+                        .metadata_cache
+                        .row_group_index(block_range, folder_index.row_group_index_offset, || {
+                            folder_index_iterator
+                                .read_row_group_index(folder_index.row_group_index_offset)
+                        })
+                        .context("read row group index")?;
+
+                    let mut path = parquet_path.to_owned();
+                    path.push(format!("{}-{}", block_range.0, block_range.1));
+
+                    let data_provider = ParquetDataProvider {
+                        path,
+                        rg_index,
+                        row_groups,
+                        metadata_cache: state.metadata_cache.clone(),
+                    };
+
+                    FolderPlan {
+                        from_block: block_range.0,
+                        to_block: block_range.1,
+                        pruned: false,
+                        tables: data_provider.plan(&pruned_query).with_context(|| {
+                            format!("plan folder {}-{}", block_range.0, block_range.1)
+                        })?,
+                    }
+                }
+                None => FolderPlan {
+                    from_block: block_range.0,
+                    to_block: block_range.1,
+                    pruned: true,
+                    tables: TablePlans::default(),
+                },
+            };
+
+            plan.num_folders += 1;
+            if folder.pruned {
+                plan.num_pruned_folders += 1;
+            }
+            plan.estimated_bytes += folder.tables.estimated_bytes;
+            plan.next_block = next_block(block_range.1, query.to_block);
+            plan.folders.push(folder);
+        }
+    }
+
+    if to_block > in_mem.from_block && query.from_block < in_mem.to_block {
+        let data_provider = InMemDataProvider { in_mem: &in_mem };
+        let tables = data_provider.plan(query);
+
+        plan.estimated_bytes += tables.estimated_bytes;
+        plan.next_block = next_block(in_mem.to_block, query.to_block);
+        plan.in_memory = Some(tables);
+    }
+
+    Ok(plan)
+}
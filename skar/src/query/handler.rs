@@ -1,28 +1,41 @@
 use std::{
     cmp,
+    collections::VecDeque,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Instant,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc as std_mpsc, Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use sbbf_rs_safe::Filter as SbbfFilter;
-use skar_format::Address;
-use tokio::sync::mpsc;
+use arrayvec::ArrayVec;
+use arrow2::compute::aggregate::estimated_bytes_size;
+use skar_format::Hash;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::{CancellationToken, DropGuard};
 use wyhash::wyhash;
 
 use crate::{
-    config::QueryConfig,
-    db::{BlockRange, FolderIndexIterator},
-    state::State,
+    config::{HealthConfig, QueryConfig},
+    db::{BlockRange, BloomFilter, FolderIndex, FolderIndexIterator, RowGroupIndex},
+    health::{HealthReport, InMemoryRange},
+    metadata_cache::MetadataCacheStats,
+    state::{InMemory, InMemoryUsage, IngestEvent, State},
     types::{LogSelection, Query, QueryResult, QueryResultData, TransactionSelection},
 };
 
 use super::{
-    data_provider::{InMemDataProvider, ParquetDataProvider},
+    address_index::QueryPostings,
+    data_provider::{ArrowBatch, InMemDataProvider, ParquetDataProvider, RowGroupSelection},
     execution::execute_query,
+    explain::{self, QueryPlan},
+    lookup,
 };
 
+const DEFAULT_MAX_CONCURRENT_FOLDERS: usize = 4;
+
 pub struct Handler {
     state: Arc<State>,
     cfg: QueryConfig,
@@ -58,9 +71,143 @@ impl Handler {
         }
     }
 
-    pub fn handle(self: Arc<Self>, query: Query) -> Result<mpsc::Receiver<Result<QueryResult>>> {
+    pub async fn health(&self, cfg: &HealthConfig) -> Result<HealthReport> {
+        let archive_height = self.archive_height().await.context("get archive height")?;
+        let chain_tip = self.state.ingest.chain_tip().await;
+        let write_status = *self.state.write_status.lock().unwrap();
+
+        let in_mem = self.state.in_mem.load();
+        let in_memory = (in_mem.from_block < in_mem.to_block).then(|| InMemoryRange {
+            from_block: in_mem.from_block,
+            to_block: in_mem.to_block,
+        });
+
+        let report = HealthReport::new(
+            self.state.ingest.is_following_tip(),
+            archive_height,
+            chain_tip,
+            in_memory,
+            write_status,
+            Instant::now(),
+        );
+
+        Ok(report.check(cfg))
+    }
+
+    pub fn in_memory_usage(&self) -> InMemoryUsage {
+        self.state.in_mem.load().usage()
+    }
+
+    pub fn metadata_cache_stats(&self) -> MetadataCacheStats {
+        self.state.metadata_cache.stats()
+    }
+
+    /// Returns all metrics in the prometheus text format.
+    pub fn encode_metrics(&self) -> Result<Vec<u8>> {
+        self.state.metrics.encode()
+    }
+
+    /// Returns a receiver of the events about the data that enters memory.
+    pub fn ingest_events(&self) -> broadcast::Receiver<IngestEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// Runs the query on the in memory data without going through the folders.
+    ///
+    /// This is for live subscriptions, each call only reads the blocks that were appended since
+    /// the previous one so it isn't subject to admission. Returns `None` if the query starts
+    /// before the in memory data, then the missing blocks are in a folder and the query has to
+    /// go through [`Handler::handle`].
+    pub async fn query_in_memory(&self, query: Query) -> Result<Option<QueryResult>> {
+        let in_mem = self.state.in_mem.load_full();
+        let metrics = self.state.metrics.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let res = query_in_memory(&in_mem, &query, &CancellationToken::new())?;
+            if let Some(query_result) = &res {
+                let size = result_size(&query_result.data);
+                metrics.result_bytes.inc_by(size as u64);
+            }
+            Ok(res)
+        })
+        .await
+        .context("join in memory query task")?
+    }
+
+    /// Returns the number of parquet folders that the block range of the query overlaps.
+    pub async fn count_folders(&self, query: &Query) -> Result<usize> {
+        let block_range = BlockRange(query.from_block, query.to_block.unwrap_or(u64::MAX));
+
+        tokio::task::block_in_place(|| self.state.db.count_folders(block_range))
+    }
+
+    pub async fn transaction_by_hash(self: Arc<Self>, hash: Hash) -> Result<Vec<ArrowBatch>> {
+        tokio::task::spawn_blocking(move || {
+            lookup::transaction_by_hash(&self.state, &self.parquet_path, hash.as_slice())
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    pub async fn receipt_by_hash(self: Arc<Self>, hash: Hash) -> Result<QueryResultData> {
+        tokio::task::spawn_blocking(move || {
+            lookup::receipt_by_hash(&self.state, &self.parquet_path, hash.as_slice())
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    pub async fn block_by_hash(self: Arc<Self>, hash: Hash) -> Result<Vec<ArrowBatch>> {
+        tokio::task::spawn_blocking(move || {
+            lookup::block_by_hash(&self.state, &self.parquet_path, hash.as_slice())
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    pub async fn block_by_number(self: Arc<Self>, block_number: u64) -> Result<Vec<ArrowBatch>> {
+        tokio::task::spawn_blocking(move || {
+            lookup::block_by_number(&self.state, &self.parquet_path, block_number)
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    pub async fn block_with_transactions(
+        self: Arc<Self>,
+        block_number: u64,
+    ) -> Result<QueryResultData> {
+        tokio::task::spawn_blocking(move || {
+            lookup::block_with_transactions(&self.state, &self.parquet_path, block_number)
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    pub async fn block_receipts(self: Arc<Self>, block_number: u64) -> Result<QueryResultData> {
+        tokio::task::spawn_blocking(move || {
+            lookup::block_receipts(&self.state, &self.parquet_path, block_number)
+        })
+        .await
+        .context("join lookup task")?
+    }
+
+    /// Runs the query and sends the results in block order.
+    ///
+    /// Execution stops at a row group boundary when the time limit is hit or the estimated
+    /// size of the results reaches the size limit, so the `next_block` of the last result
+    /// might point into the middle of a folder.
+    ///
+    /// The query is cancelled when the returned receiver is dropped.
+    pub fn handle(
+        self: Arc<Self>,
+        query: Query,
+        limits: QueryLimits,
+    ) -> Result<QueryResultReceiver> {
+        let time_limit = self.time_limit(limits);
         let handler = self.clone();
         let (tx, rx) = mpsc::channel(1);
+        let cancel = CancellationToken::new();
 
         let folder_index_iterator = self
             .state
@@ -71,13 +218,44 @@ impl Handler {
             ))
             .context("start folder index iterator")?;
 
+        let receiver = QueryResultReceiver {
+            rx,
+            _cancel: cancel.clone().drop_guard(),
+        };
+
+        let metrics = self.state.metrics.clone();
+        metrics.queries.inc();
+
         tokio::task::spawn_blocking(move || {
+            let start_time = Instant::now();
+
+            let postings = if folder_index_iterator.is_some() {
+                match QueryPostings::load(&handler.state.db, &query) {
+                    Ok(postings) => postings,
+                    Err(e) => {
+                        tx.blocking_send(Err(e.context("load address postings")))
+                            .ok();
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+
             let iter = QueryResultIterator {
                 finished: false,
-                start_time: Instant::now(),
+                start_time,
+                time_limit,
                 handler,
+                folders_to: query.from_block,
                 query,
                 folder_index_iterator,
+                postings,
+                pending: VecDeque::new(),
+                in_mem: None,
+                size_limit: limits.size_limit,
+                result_size: Arc::new(AtomicUsize::new(0)),
+                cancel,
             };
 
             for res in iter {
@@ -89,18 +267,134 @@ impl Handler {
                     break;
                 }
             }
+
+            metrics
+                .query_duration
+                .observe(start_time.elapsed().as_secs_f64());
         });
 
-        Ok(rx)
+        Ok(receiver)
+    }
+
+    /// Plans the query without reading any data from the parquet files except their footers.
+    ///
+    /// Planning stops at a folder boundary when the time limit is hit, the `next_block` of the
+    /// plan is where it stopped.
+    pub async fn explain(self: Arc<Self>, query: Query, limits: QueryLimits) -> Result<QueryPlan> {
+        let time_limit = self.time_limit(limits);
+
+        tokio::task::spawn_blocking(move || {
+            explain::explain_query(&self.state, &self.parquet_path, &query, time_limit)
+        })
+        .await
+        .context("join explain task")?
+    }
+
+    fn time_limit(&self, limits: QueryLimits) -> Duration {
+        Duration::from_millis(match limits.time_limit_ms {
+            Some(time_limit_ms) => time_limit_ms.min(self.cfg.time_limit_ms),
+            None => self.cfg.time_limit_ms,
+        })
+    }
+}
+
+/// Limits of a single query that was started with [`Handler::handle`].
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLimits {
+    /// Limit for the estimated size of the results in bytes.
+    pub size_limit: usize,
+    /// Time limit for the query.
+    ///
+    /// The `time_limit_ms` of the query config is used if this is not given or if it is lower.
+    pub time_limit_ms: Option<u64>,
+}
+
+impl QueryLimits {
+    pub fn new(size_limit: usize) -> Self {
+        Self {
+            size_limit,
+            time_limit_ms: None,
+        }
+    }
+}
+
+/// Receives the results of a query that was started with [`Handler::handle`].
+///
+/// Dropping this cancels the query, so the execution stops soon after the client goes away.
+pub struct QueryResultReceiver {
+    rx: mpsc::Receiver<Result<QueryResult>>,
+    _cancel: DropGuard,
+}
+
+impl QueryResultReceiver {
+    pub async fn recv(&mut self) -> Option<Result<QueryResult>> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+impl QueryResultReceiver {
+    /// Receiver that returns the given results and then ends.
+    pub fn from_results(results: Vec<Result<QueryResult>>) -> Self {
+        let (tx, rx) = mpsc::channel(results.len().max(1));
+        for res in results {
+            assert!(tx.try_send(res).is_ok());
+        }
+
+        Self {
+            rx,
+            _cancel: CancellationToken::new().drop_guard(),
+        }
     }
 }
 
 pub struct QueryResultIterator {
     finished: bool,
     start_time: Instant,
+    time_limit: Duration,
     handler: Arc<Handler>,
     query: Query,
+    /// Blocks before this one were already read from parquet folders.
+    folders_to: u64,
     folder_index_iterator: Option<FolderIndexIterator>,
+    postings: Option<QueryPostings>,
+    /// Results of the folders that are being executed, in block order.
+    pending: VecDeque<PendingResult>,
+    /// Snapshot of the in memory data that is queried after the folders.
+    ///
+    /// This is set when there are no more folders to read.
+    in_mem: Option<Arc<InMemory>>,
+    size_limit: usize,
+    /// Estimated size of the results of the folders that were executed so far.
+    ///
+    /// The folders add to this while they are running, so the folders that run at the same
+    /// time share the size limit.
+    result_size: Arc<AtomicUsize>,
+    cancel: CancellationToken,
+}
+
+enum PendingResult {
+    Ready(Result<QueryResult>),
+    /// Receives the result and whether the execution stopped before the end of the folder.
+    Running {
+        rx: std_mpsc::Receiver<Result<(QueryResult, bool)>>,
+        cancel: CancellationToken,
+    },
+}
+
+impl PendingResult {
+    fn wait(self) -> Result<(QueryResult, bool)> {
+        match self {
+            Self::Ready(res) => res.map(|res| (res, false)),
+            Self::Running { rx, .. } => rx.recv().context("receive folder query result")?,
+        }
+    }
+
+    fn cancel(&self) {
+        if let Self::Running { cancel, .. } = self {
+            cancel.cancel();
+        }
+    }
 }
 
 impl Iterator for QueryResultIterator {
@@ -111,66 +405,164 @@ impl Iterator for QueryResultIterator {
             return None;
         }
 
-        if self.start_time.elapsed().as_millis() >= self.handler.cfg.time_limit_ms as u128 {
-            self.finished = true;
+        if self.cancel.is_cancelled() || self.start_time.elapsed() >= self.time_limit {
+            self.finish();
             return None;
         }
 
+        let max_concurrent_folders = self
+            .handler
+            .cfg
+            .max_concurrent_folders
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_FOLDERS)
+            .max(1);
+
+        // Keep the next folders running while the results of the previous ones are being sent.
+        while self.in_mem.is_none() && self.pending.len() < max_concurrent_folders {
+            match self.next_folder() {
+                Some(pending) => self.pending.push_back(pending),
+                None => break,
+            }
+        }
+
+        if let Some(pending) = self.pending.pop_front() {
+            let (query_result, truncated) = match pending.wait() {
+                Ok(res) => res,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let size = result_size(&query_result.data);
+            self.handler.state.metrics.result_bytes.inc_by(size as u64);
+
+            // The results of the next folders would leave a gap after a truncated folder.
+            if truncated || self.result_size.load(Ordering::Relaxed) >= self.size_limit {
+                self.finish();
+            }
+
+            return Some(Ok(query_result));
+        }
+
+        self.finished = true;
+
+        let in_mem = self.in_mem.take()?;
+
+        let to_block = self.query.to_block.unwrap_or(u64::MAX);
+
+        if to_block <= in_mem.from_block {
+            return None;
+        }
+
+        // Skip the in memory data that was already read from a folder before it was dropped from memory.
+        let from_block = cmp::max(self.query.from_block, self.folders_to);
+
+        if from_block >= in_mem.to_block {
+            return None;
+        }
+
+        let query = Query {
+            from_block,
+            ..self.query.clone()
+        };
+
+        let data_provider = InMemDataProvider { in_mem: &in_mem };
+
+        let query_res = execute_query(&data_provider, &query, &self.cancel)
+            .map(|data| {
+                let size = result_size(&data);
+                self.handler.state.metrics.result_bytes.inc_by(size as u64);
+                data
+            })
+            .map(|data| QueryResult {
+                data,
+                next_block: next_block(in_mem.to_block, self.query.to_block),
+            })
+            .context("execute in memory query");
+
+        Some(query_res)
+    }
+}
+
+impl QueryResultIterator {
+    // Stops the iteration and cancels the folders that are still running.
+    fn finish(&mut self) {
+        self.finished = true;
+        for pending in self.pending.drain(..) {
+            pending.cancel();
+        }
+    }
+
+    // Starts executing the query on the next folder.
+    //
+    // Returns `None` and takes a snapshot of the in memory data if there are no more folders.
+    fn next_folder(&mut self) -> Option<PendingResult> {
         let folder_index = match self.folder_index_iterator.as_mut().and_then(|i| i.next()) {
             Some(folder_index) => folder_index,
             None => {
-                self.finished = true;
+                let in_mem = self.handler.state.in_mem.load_full();
 
-                let in_mem = self.handler.state.in_mem.load();
+                let to_block = self.query.to_block.unwrap_or(u64::MAX);
 
-                if let Some(to_block) = self.query.to_block {
-                    if to_block <= in_mem.from_block {
-                        return None;
+                // Some of the in memory data might have been written to a new folder and
+                // dropped from memory after the folder index iterator was opened.
+                if self.folders_to < cmp::min(in_mem.from_block, to_block) {
+                    match self
+                        .handler
+                        .state
+                        .db
+                        .iterate_folder_indices(BlockRange(self.folders_to, to_block))
+                    {
+                        Ok(Some(iter)) => {
+                            self.folder_index_iterator = Some(iter);
+                            return self.next_folder();
+                        }
+                        Ok(None) => (),
+                        Err(e) => {
+                            return Some(PendingResult::Ready(Err(
+                                e.context("restart folder index iterator")
+                            )));
+                        }
                     }
                 }
 
-                if self.query.from_block >= in_mem.to_block {
-                    return None;
-                }
-
-                let data_provider = InMemDataProvider { in_mem: &in_mem };
-
-                let query_res = execute_query(&data_provider, &self.query)
-                    .map(|data| QueryResult {
-                        data,
-                        next_block: next_block(in_mem.to_block, self.query.to_block),
-                    })
-                    .context("execute in memory query");
+                self.in_mem = Some(in_mem);
 
-                return Some(query_res);
+                return None;
             }
         };
 
         let folder_index = match folder_index {
             Ok(folder_index) => folder_index,
-            Err(e) => return Some(Err(e.context("failed to read folder index"))),
+            Err(e) => {
+                return Some(PendingResult::Ready(Err(
+                    e.context("failed to read folder index")
+                )))
+            }
         };
 
-        let pruned_query = prune_query(&self.query, &folder_index.address_filter.0);
+        self.folders_to = folder_index.block_range.1;
 
-        if pruned_query.logs.is_empty()
-            && pruned_query.transactions.is_empty()
-            && !pruned_query.include_all_blocks
-        {
-            return Some(Ok(QueryResult {
-                data: QueryResultData::default(),
-                next_block: next_block(folder_index.block_range.1, self.query.to_block),
-            }));
-        }
+        let metrics = self.handler.state.metrics.clone();
 
-        let rg_index = match self
-            .folder_index_iterator
-            .as_mut()
-            .unwrap()
-            .read_row_group_index(folder_index.row_group_index_offset)
-        {
+        let (pruned_query, row_groups) =
+            match prune_folder(&self.query, self.postings.as_ref(), &folder_index) {
+                Some(pruned) => pruned,
+                None => {
+                    metrics.folders_pruned.inc();
+                    return Some(PendingResult::Ready(Ok(QueryResult {
+                        data: QueryResultData::default(),
+                        next_block: next_block(folder_index.block_range.1, self.query.to_block),
+                    })));
+                }
+            };
+
+        let folder_index_iterator = self.folder_index_iterator.as_mut().unwrap();
+        let rg_index = match self.handler.state.metadata_cache.row_group_index(
+            folder_index.block_range,
+            folder_index.row_group_index_offset,
+            || folder_index_iterator.read_row_group_index(folder_index.row_group_index_offset),
+        ) {
             Ok(rg_index) => rg_index,
-            Err(e) => return Some(Err(e.context("read row group index"))),
+            Err(e) => return Some(PendingResult::Ready(Err(e.context("read row group index")))),
         };
 
         let mut path = self.handler.parquet_path.clone();
@@ -179,46 +571,222 @@ impl Iterator for QueryResultIterator {
             folder_index.block_range.0, folder_index.block_range.1
         ));
 
-        let data_provider = ParquetDataProvider { path, rg_index };
+        metrics.folders_scanned.inc();
 
-        let query_result = execute_query(&data_provider, &pruned_query).map(|data| QueryResult {
-            data,
-            next_block: next_block(folder_index.block_range.1, self.query.to_block),
+        let data_provider = ParquetDataProvider {
+            path,
+            rg_index,
+            row_groups,
+            metadata_cache: self.handler.state.metadata_cache.clone(),
+        };
+
+        let limits = FolderLimits {
+            deadline: self.start_time + self.time_limit,
+            size_limit: self.size_limit,
+            result_size: self.result_size.clone(),
+        };
+
+        let (tx, rx) = std_mpsc::sync_channel(1);
+        let cancel = self.cancel.child_token();
+        let folder_cancel = cancel.clone();
+
+        rayon::spawn(move || {
+            let query_result = execute_folder(
+                &data_provider,
+                &pruned_query,
+                folder_index.block_range,
+                &limits,
+                &folder_cancel,
+            );
+            tx.send(query_result).ok();
         });
 
-        Some(query_result)
+        Some(PendingResult::Running { rx, cancel })
     }
 }
 
-fn prune_query(query: &Query, filter: &SbbfFilter) -> Query {
-    let prune_addrs = |addrs: Vec<Address>| -> Option<Vec<Address>> {
-        if !addrs.is_empty() {
-            let out = addrs
-                .into_iter()
-                .filter(|addr| filter.contains_hash(wyhash(addr.as_slice(), 0)))
-                .collect::<Vec<_>>();
+/// Limits that are checked at the row group boundaries inside a folder.
+struct FolderLimits {
+    deadline: Instant,
+    size_limit: usize,
+    /// Size of the results of the whole query, the folder adds the size of its results to this.
+    result_size: Arc<AtomicUsize>,
+}
 
-            if out.is_empty() {
-                None
-            } else {
-                Some(out)
-            }
-        } else {
-            Some(Default::default())
+// Executes the query on the folder one block range at a time. The ranges end at row group
+// boundaries so the execution can stop in the middle of the folder when it hits the limits.
+//
+// Returns the result and whether the execution stopped before the end of the folder.
+fn execute_folder(
+    provider: &ParquetDataProvider,
+    query: &Query,
+    folder_range: BlockRange,
+    limits: &FolderLimits,
+    cancel: &CancellationToken,
+) -> Result<(QueryResult, bool)> {
+    let from_block = cmp::max(query.from_block, folder_range.0);
+    let to_block = next_block(folder_range.1, query.to_block);
+
+    let mut data = QueryResultData::default();
+
+    let mut slice_from = from_block;
+    for slice_to in split_points(&provider.rg_index, from_block, to_block)
+        .into_iter()
+        .chain(std::iter::once(to_block))
+    {
+        let slice_query = Query {
+            from_block: slice_from,
+            to_block: Some(slice_to),
+            ..query.clone()
+        };
+        let slice_data = execute_query(provider, &slice_query, cancel)
+            .with_context(|| format!("execute query on blocks {slice_from} to {slice_to}"))?;
+
+        let slice_size = result_size(&slice_data);
+        let size = limits.result_size.fetch_add(slice_size, Ordering::Relaxed) + slice_size;
+        data.logs.extend(slice_data.logs);
+        data.transactions.extend(slice_data.transactions);
+        data.blocks.extend(slice_data.blocks);
+
+        slice_from = slice_to;
+
+        if slice_to < to_block && (Instant::now() >= limits.deadline || size >= limits.size_limit) {
+            return Ok((
+                QueryResult {
+                    data,
+                    next_block: slice_to,
+                },
+                true,
+            ));
         }
+    }
+
+    Ok((
+        QueryResult {
+            data,
+            next_block: to_block,
+        },
+        false,
+    ))
+}
+
+// Returns the block numbers in (from_block, to_block) where a row group of the table with
+// the fewest row groups ends, so most of the row groups are only loaded once.
+fn split_points(rg_index: &RowGroupIndex, from_block: u64, to_block: u64) -> Vec<u64> {
+    let tables: [Vec<u64>; 3] = [
+        rg_index.block.iter().map(|rg| rg.max_block_num).collect(),
+        rg_index
+            .transaction
+            .iter()
+            .map(|rg| rg.max_block_num)
+            .collect(),
+        rg_index.log.iter().map(|rg| rg.max_block_num).collect(),
+    ];
+
+    let ends = tables
+        .into_iter()
+        .filter(|ends| !ends.is_empty())
+        .min_by_key(|ends| ends.len())
+        .unwrap_or_default();
+
+    let mut points = ends
+        .into_iter()
+        .map(|max_block_num| max_block_num + 1)
+        .filter(|&end| end > from_block && end < to_block)
+        .collect::<Vec<_>>();
+    points.sort_unstable();
+    points.dedup();
+
+    points
+}
+
+// Returns `None` if the query starts before the in memory data. The result is empty and
+// `next_block` stays at the start of the query if there is nothing new to read.
+fn query_in_memory(
+    in_mem: &InMemory,
+    query: &Query,
+    cancel: &CancellationToken,
+) -> Result<Option<QueryResult>> {
+    if query.from_block < in_mem.from_block {
+        return Ok(None);
+    }
+
+    let to_block = query.to_block.unwrap_or(u64::MAX);
+    if query.from_block >= cmp::min(to_block, in_mem.to_block) {
+        return Ok(Some(QueryResult {
+            data: QueryResultData::default(),
+            next_block: query.from_block,
+        }));
+    }
+
+    let data = execute_query(&InMemDataProvider { in_mem }, query, cancel)
+        .context("execute in memory query")?;
+
+    Ok(Some(QueryResult {
+        data,
+        next_block: next_block(in_mem.to_block, query.to_block),
+    }))
+}
+
+fn result_size(data: &QueryResultData) -> usize {
+    data.logs
+        .iter()
+        .chain(data.transactions.iter())
+        .chain(data.blocks.iter())
+        .flat_map(|batch| batch.chunk.columns().iter())
+        .map(|col| estimated_bytes_size(col.as_ref()))
+        .sum()
+}
+
+/// Removes the selections that can't match anything in the folder according to the folder
+/// filters and the address index, and returns the row groups that can match the rest.
+///
+/// Returns `None` if nothing in the folder can match the query.
+pub(super) fn prune_folder(
+    query: &Query,
+    postings: Option<&QueryPostings>,
+    folder_index: &FolderIndex,
+) -> Option<(Query, RowGroupSelection)> {
+    let (pruned_query, row_groups) = match postings {
+        Some(postings) => {
+            let (query, row_groups) = postings.prune(query, folder_index.block_range);
+            (prune_query(&query, folder_index), row_groups)
+        }
+        None => (
+            prune_query(query, folder_index),
+            RowGroupSelection::default(),
+        ),
     };
 
+    if pruned_query.logs.is_empty()
+        && pruned_query.transactions.is_empty()
+        && !pruned_query.include_all_blocks
+    {
+        return None;
+    }
+
+    Some((pruned_query, row_groups))
+}
+
+fn prune_query(query: &Query, folder_index: &FolderIndex) -> Query {
+    let address_filter = Some(&folder_index.address_filter);
+    let sighash_filter = folder_index.sighash_filter.as_ref();
+
     Query {
         logs: query
             .logs
             .iter()
             .cloned()
             .filter_map(|selection| {
-                let address = prune_addrs(selection.address)?;
-                Some(LogSelection {
-                    address,
-                    ..selection
-                })
+                let address = prune_values(selection.address, address_filter)?;
+
+                let mut topics = ArrayVec::new();
+                for (i, topic) in selection.topics.into_iter().enumerate() {
+                    let filter = folder_index.topic_filters.as_ref().map(|f| &f[i]);
+                    topics.push(prune_values(topic, filter)?);
+                }
+
+                Some(LogSelection { address, topics })
             })
             .collect(),
         transactions: query
@@ -226,11 +794,13 @@ fn prune_query(query: &Query, filter: &SbbfFilter) -> Query {
             .iter()
             .cloned()
             .filter_map(|selection| {
-                let from = prune_addrs(selection.from)?;
-                let to = prune_addrs(selection.to)?;
+                let from = prune_values(selection.from, address_filter)?;
+                let to = prune_values(selection.to, address_filter)?;
+                let sighash = prune_values(selection.sighash, sighash_filter)?;
                 Some(TransactionSelection {
                     from,
                     to,
+                    sighash,
                     ..selection
                 })
             })
@@ -242,7 +812,29 @@ fn prune_query(query: &Query, filter: &SbbfFilter) -> Query {
     }
 }
 
-fn next_block(mut to_block: u64, query_limit: Option<u64>) -> u64 {
+// Removes the values that are not in the filter.
+//
+// Returns `None` if none of the values are in the filter, which means the selection can't match anything.
+// Empty selections match everything so they are returned as is, same goes for missing filters.
+fn prune_values<T: AsRef<[u8]>>(values: Vec<T>, filter: Option<&BloomFilter>) -> Option<Vec<T>> {
+    let filter = match filter {
+        Some(filter) if !values.is_empty() => filter,
+        _ => return Some(values),
+    };
+
+    let out = values
+        .into_iter()
+        .filter(|v| filter.0.contains_hash(wyhash(v.as_ref(), 0)))
+        .collect::<Vec<_>>();
+
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+pub(super) fn next_block(mut to_block: u64, query_limit: Option<u64>) -> u64 {
     if let Some(limit) = query_limit {
         to_block = cmp::min(limit, to_block);
     }
@@ -252,7 +844,24 @@ fn next_block(mut to_block: u64, query_limit: Option<u64>) -> u64 {
 
 #[cfg(test)]
 mod tests {
+    use sbbf_rs_safe::Filter as SbbfFilter;
+
     use super::*;
+    use crate::{
+        db::BlockRowGroupIndex,
+        tests::{in_mem_fixture, TestState},
+        types::FieldSelection,
+    };
+
+    fn folder_index(address_filter: SbbfFilter) -> FolderIndex {
+        FolderIndex {
+            block_range: BlockRange(0, 1),
+            address_filter: BloomFilter(address_filter),
+            topic_filters: None,
+            sighash_filter: None,
+            row_group_index_offset: 0,
+        }
+    }
 
     #[test]
     fn test_prune_query_empty_filter() {
@@ -281,7 +890,7 @@ mod tests {
             }],
         };
 
-        let filter = SbbfFilter::new(15, 31);
+        let filter = folder_index(SbbfFilter::new(15, 31));
 
         let pruned_query = prune_query(&query, &filter);
 
@@ -347,7 +956,7 @@ mod tests {
             &hex_literal::hex!("48bBf1c68037BF35b0eB090f1B5E0fa52F690502"),
             0,
         ));
-        let filter = filter;
+        let filter = folder_index(filter);
 
         let query = Query {
             from_block: 0,
@@ -408,4 +1017,205 @@ mod tests {
         assert_eq!(pruned_query.transactions.len(), 1);
         assert_eq!(pruned_query.logs.len(), 2);
     }
+
+    #[test]
+    fn test_prune_query_topics_and_sighash() {
+        let topic0 =
+            hex_literal::hex!("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
+        let sighash = hex_literal::hex!("a9059cbb");
+
+        let mut topic_filter = SbbfFilter::new(100, 1000);
+        topic_filter.insert_hash(wyhash(&topic0, 0));
+        let mut sighash_filter = SbbfFilter::new(100, 1000);
+        sighash_filter.insert_hash(wyhash(&sighash, 0));
+
+        let mut index = folder_index(SbbfFilter::new(15, 31));
+        index.topic_filters = Some([
+            BloomFilter(topic_filter),
+            BloomFilter(SbbfFilter::new(15, 31)),
+            BloomFilter(SbbfFilter::new(15, 31)),
+            BloomFilter(SbbfFilter::new(15, 31)),
+        ]);
+        index.sighash_filter = Some(BloomFilter(sighash_filter));
+
+        let mut matching_topics = ArrayVec::new();
+        matching_topics.push(vec![topic0.into()]);
+        let mut missing_topics = ArrayVec::new();
+        missing_topics.push(vec![hex_literal::hex!(
+            "12f252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+        )
+        .into()]);
+        let mut missing_second_topic = ArrayVec::new();
+        missing_second_topic.push(vec![topic0.into()]);
+        missing_second_topic.push(vec![topic0.into()]);
+
+        let query = Query {
+            from_block: 0,
+            to_block: None,
+            field_selection: Default::default(),
+            include_all_blocks: false,
+            transactions: vec![
+                TransactionSelection {
+                    from: vec![],
+                    to: vec![],
+                    sighash: vec![sighash.into()],
+                    status: None,
+                },
+                TransactionSelection {
+                    from: vec![],
+                    to: vec![],
+                    sighash: vec![hex_literal::hex!("12345678").into()],
+                    status: None,
+                },
+            ],
+            logs: vec![
+                LogSelection {
+                    address: vec![],
+                    topics: matching_topics,
+                },
+                LogSelection {
+                    address: vec![],
+                    topics: missing_topics,
+                },
+                LogSelection {
+                    address: vec![],
+                    topics: missing_second_topic,
+                },
+            ],
+        };
+
+        let pruned_query = prune_query(&query, &index);
+
+        assert_eq!(pruned_query.transactions.len(), 1);
+        assert_eq!(pruned_query.logs.len(), 1);
+
+        // folders written before topic and sighash filters existed can't be pruned by them
+        let pruned_query = prune_query(&query, &folder_index(SbbfFilter::new(15, 31)));
+
+        assert_eq!(pruned_query.transactions.len(), 2);
+        assert_eq!(pruned_query.logs.len(), 3);
+    }
+
+    #[test]
+    fn test_split_points() {
+        let block_rg = |min_block_num, max_block_num| BlockRowGroupIndex {
+            min_block_num,
+            max_block_num,
+        };
+
+        let rg_index = RowGroupIndex {
+            block: vec![block_rg(0, 9), block_rg(10, 19), block_rg(20, 29)],
+            transaction: Vec::new(),
+            log: Vec::new(),
+        };
+
+        assert_eq!(split_points(&rg_index, 0, 30), vec![10, 20]);
+        assert_eq!(split_points(&rg_index, 10, 25), vec![20]);
+        assert_eq!(split_points(&rg_index, 12, 18), Vec::<u64>::new());
+    }
+
+    fn query_all_logs(from_block: u64, to_block: Option<u64>) -> Query {
+        Query {
+            from_block,
+            to_block,
+            logs: vec![LogSelection {
+                address: Vec::new(),
+                topics: Default::default(),
+            }],
+            transactions: Vec::new(),
+            include_all_blocks: false,
+            field_selection: FieldSelection {
+                log: ["address".to_owned()].into_iter().collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_query_in_memory() {
+        let in_mem = in_mem_fixture(12911679);
+        let cancel = CancellationToken::new();
+        let query = query_all_logs;
+        let num_logs = |res: &QueryResult| {
+            res.data
+                .logs
+                .iter()
+                .map(|batch| batch.chunk.len())
+                .sum::<usize>()
+        };
+
+        // The block before the in memory data is in a folder, so the subscription has to catch up.
+        assert!(query_in_memory(&in_mem, &query(12911678, None), &cancel)
+            .unwrap()
+            .is_none());
+
+        // Caught up to the archive height before the block was appended.
+        let res = query_in_memory(&in_mem, &query(12911679, None), &cancel)
+            .unwrap()
+            .unwrap();
+        assert_eq!(num_logs(&res), 187);
+        assert_eq!(res.next_block, 12911680);
+
+        // Nothing new since the last update.
+        let res = query_in_memory(&in_mem, &query(12911680, None), &cancel)
+            .unwrap()
+            .unwrap();
+        assert_eq!(num_logs(&res), 0);
+        assert_eq!(res.next_block, 12911680);
+
+        let res = query_in_memory(&in_mem, &query(12911679, Some(12911679)), &cancel)
+            .unwrap()
+            .unwrap();
+        assert_eq!(num_logs(&res), 0);
+        assert_eq!(res.next_block, 12911679);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_on_receiver_drop() {
+        const NUM_FOLDERS: u64 = 16;
+
+        let test_state = TestState::new(QueryConfig {
+            time_limit_ms: 60_000,
+            max_concurrent_folders: Some(1),
+            metadata_cache_mb: None,
+        });
+        for block_number in 0..NUM_FOLDERS {
+            test_state.flush(in_mem_fixture(block_number)).await;
+        }
+        let metrics = &test_state.state.metrics;
+
+        let mut rx = test_state
+            .handler
+            .clone()
+            .handle(query_all_logs(0, None), QueryLimits::new(usize::MAX))
+            .unwrap();
+        let first = rx.recv().await.unwrap().unwrap();
+        assert_eq!(first.next_block, 1);
+        drop(rx);
+
+        // Wait for the execution to stop.
+        let start = Instant::now();
+        while metrics.query_duration.get_sample_count() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The first folder, the one whose result was waiting in the channel and the one
+        // that was running when the receiver was dropped.
+        assert!(metrics.folders_scanned.get() <= 3);
+
+        // A folder that runs after the receiver is dropped stops with the cancelled error.
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = execute_query(
+            &InMemDataProvider {
+                in_mem: &in_mem_fixture(NUM_FOLDERS),
+            },
+            &query_all_logs(NUM_FOLDERS, None),
+            &cancel,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "query was cancelled");
+    }
 }
@@ -0,0 +1,478 @@
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use arrow2::{
+    array::{BinaryArray, UInt64Array},
+    compute,
+    datatypes::SchemaRef,
+    scalar::PrimitiveScalar,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    db::BlockRange,
+    schema,
+    state::{InMemoryTable, State},
+    types::{FieldSelection, LogSelection, Query, QueryResultData, TransactionSelection},
+};
+
+use super::{
+    data_provider::{ArrowBatch, InMemDataProvider, ParquetDataProvider},
+    execution::{execute_query, filter_chunk},
+};
+
+const RECEIPT_FIELDS: &[&str] = &[
+    "block_hash",
+    "block_number",
+    "from",
+    "to",
+    "hash",
+    "transaction_index",
+    "cumulative_gas_used",
+    "effective_gas_price",
+    "gas_used",
+    "contract_address",
+    "logs_bloom",
+    "type",
+    "root",
+    "status",
+];
+
+/// Returns the transaction with the given hash.
+///
+/// The returned batches are empty if the transaction is not found.
+pub fn transaction_by_hash(
+    state: &State,
+    parquet_path: &Path,
+    hash: &[u8],
+) -> Result<Vec<ArrowBatch>> {
+    let (block_number, tx_index) = match find_transaction(state, hash)? {
+        Some(location) => location,
+        None => return Ok(Vec::new()),
+    };
+
+    let query = Query {
+        transactions: vec![match_all_transactions()],
+        field_selection: FieldSelection {
+            transaction: all_fields(schema::transaction()),
+            ..Default::default()
+        },
+        ..single_block_query(block_number)
+    };
+
+    let data = query_single_block(state, parquet_path, &query).context("query block")?;
+
+    filter_batches(data.transactions, "transaction_index", tx_index)
+}
+
+/// Returns the receipt fields of the transaction with the given hash and the logs it emitted.
+///
+/// The returned transaction batches are empty if the transaction is not found.
+pub fn receipt_by_hash(state: &State, parquet_path: &Path, hash: &[u8]) -> Result<QueryResultData> {
+    let (block_number, tx_index) = match find_transaction(state, hash)? {
+        Some(location) => location,
+        None => return Ok(QueryResultData::default()),
+    };
+
+    let query = Query {
+        transactions: vec![match_all_transactions()],
+        logs: vec![LogSelection {
+            address: Vec::new(),
+            topics: Default::default(),
+        }],
+        field_selection: FieldSelection {
+            transaction: RECEIPT_FIELDS.iter().map(|s| s.to_string()).collect(),
+            log: all_fields(schema::log()),
+            ..Default::default()
+        },
+        ..single_block_query(block_number)
+    };
+
+    let data = query_single_block(state, parquet_path, &query).context("query block")?;
+
+    Ok(QueryResultData {
+        transactions: filter_batches(data.transactions, "transaction_index", tx_index)?,
+        logs: filter_batches(data.logs, "transaction_index", tx_index)?,
+        blocks: Vec::new(),
+    })
+}
+
+/// Returns the header of the block with the given hash.
+///
+/// The returned batches are empty if the block is not found.
+pub fn block_by_hash(state: &State, parquet_path: &Path, hash: &[u8]) -> Result<Vec<ArrowBatch>> {
+    let block_number = match state
+        .db
+        .get_block_number(hash)
+        .context("get block number from db")?
+    {
+        Some(block_number) => Some(block_number),
+        None => {
+            let in_mem = state.in_mem.load();
+            match find_in_memory(&in_mem.blocks, schema::block_header(), hash)? {
+                Some((batch, idx)) => Some(batch.column::<UInt64Array>("number")?.value(idx)),
+                None => None,
+            }
+        }
+    };
+
+    match block_number {
+        Some(block_number) => block_by_number(state, parquet_path, block_number),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns the header of the block with the given number.
+///
+/// The returned batches are empty if the block is not found.
+pub fn block_by_number(
+    state: &State,
+    parquet_path: &Path,
+    block_number: u64,
+) -> Result<Vec<ArrowBatch>> {
+    let query = Query {
+        include_all_blocks: true,
+        field_selection: FieldSelection {
+            block: all_fields(schema::block_header()),
+            ..Default::default()
+        },
+        ..single_block_query(block_number)
+    };
+
+    let data = query_single_block(state, parquet_path, &query).context("query block")?;
+
+    Ok(data.blocks)
+}
+
+/// Returns the header and the transactions of the block with the given number.
+///
+/// The returned block batches are empty if the block is not found.
+pub fn block_with_transactions(
+    state: &State,
+    parquet_path: &Path,
+    block_number: u64,
+) -> Result<QueryResultData> {
+    let query = Query {
+        include_all_blocks: true,
+        transactions: vec![match_all_transactions()],
+        field_selection: FieldSelection {
+            block: all_fields(schema::block_header()),
+            transaction: all_fields(schema::transaction()),
+            ..Default::default()
+        },
+        ..single_block_query(block_number)
+    };
+
+    query_single_block(state, parquet_path, &query).context("query block")
+}
+
+/// Returns the receipt fields of the transactions in the block with the given number and the logs they emitted.
+pub fn block_receipts(
+    state: &State,
+    parquet_path: &Path,
+    block_number: u64,
+) -> Result<QueryResultData> {
+    let query = Query {
+        transactions: vec![match_all_transactions()],
+        logs: vec![LogSelection {
+            address: Vec::new(),
+            topics: Default::default(),
+        }],
+        field_selection: FieldSelection {
+            transaction: RECEIPT_FIELDS.iter().map(|s| s.to_string()).collect(),
+            log: all_fields(schema::log()),
+            ..Default::default()
+        },
+        ..single_block_query(block_number)
+    };
+
+    query_single_block(state, parquet_path, &query).context("query block")
+}
+
+// Finds (block_number, transaction_index) of the transaction with the given hash.
+//
+// Transactions that are not written to parquet yet aren't in the hash table,
+// so the in memory data is scanned if the hash table doesn't have the transaction.
+fn find_transaction(state: &State, hash: &[u8]) -> Result<Option<(u64, u64)>> {
+    if let Some(location) = state
+        .db
+        .get_transaction_location(hash)
+        .context("get transaction location from db")?
+    {
+        return Ok(Some(location));
+    }
+
+    let in_mem = state.in_mem.load();
+
+    match find_in_memory(&in_mem.transactions, schema::transaction(), hash)? {
+        Some((batch, idx)) => {
+            let block_number = batch.column::<UInt64Array>("block_number")?.value(idx);
+            let tx_index = batch.column::<UInt64Array>("transaction_index")?.value(idx);
+            Ok(Some((block_number, tx_index)))
+        }
+        None => Ok(None),
+    }
+}
+
+// Returns the batch and the row index of the row that has the given hash.
+fn find_in_memory<I>(
+    table: &InMemoryTable<I>,
+    schema: SchemaRef,
+    hash: &[u8],
+) -> Result<Option<(ArrowBatch, usize)>> {
+    for chunk in table.iter() {
+        let batch = ArrowBatch {
+            chunk: chunk.clone(),
+            schema: schema.clone(),
+        };
+
+        let pos = batch
+            .column::<BinaryArray<i32>>("hash")?
+            .values_iter()
+            .position(|h| h == hash);
+
+        if let Some(pos) = pos {
+            return Ok(Some((batch, pos)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Executes the query against the folder or the in memory data that contains `query.from_block`.
+fn query_single_block(
+    state: &State,
+    parquet_path: &Path,
+    query: &Query,
+) -> Result<QueryResultData> {
+    let block_number = query.from_block;
+
+    let in_mem = state.in_mem.load();
+    if block_number >= in_mem.from_block && block_number < in_mem.to_block {
+        let data_provider = InMemDataProvider { in_mem: &in_mem };
+        return execute_query(&data_provider, query, &CancellationToken::new())
+            .context("execute in memory query");
+    }
+
+    let mut folder_index_iterator = match state
+        .db
+        .iterate_folder_indices(BlockRange(block_number, block_number + 1))
+        .context("start folder index iterator")?
+    {
+        Some(iter) => iter,
+        None => return Ok(QueryResultData::default()),
+    };
+
+    let folder_index = match folder_index_iterator.next() {
+        Some(folder_index) => folder_index.context("read folder index")?,
+        None => return Ok(QueryResultData::default()),
+    };
+
+    let rg_index = state
+        .metadata_cache
+        .row_group_index(
+            folder_index.block_range,
+            folder_index.row_group_index_offset,
+            || folder_index_iterator.read_row_group_index(folder_index.row_group_index_offset),
+        )
+        .context("read row group index")?;
+
+    let mut path = parquet_path.to_owned();
+    path.push(format!(
+        "{}-{}",
+        folder_index.block_range.0, folder_index.block_range.1
+    ));
+
+    let data_provider = ParquetDataProvider {
+        path,
+        rg_index,
+        row_groups: Default::default(),
+        metadata_cache: state.metadata_cache.clone(),
+    };
+
+    execute_query(&data_provider, query, &CancellationToken::new()).context("execute parquet query")
+}
+
+fn filter_batches(batches: Vec<ArrowBatch>, column: &str, value: u64) -> Result<Vec<ArrowBatch>> {
+    let mut res = Vec::new();
+
+    for batch in batches {
+        let col = batch.column::<UInt64Array>(column)?;
+        let filter = compute::comparison::eq_scalar(col, &PrimitiveScalar::from(Some(value)));
+
+        let chunk = filter_chunk(&batch.chunk, &filter).context("filter record batch")?;
+
+        if chunk.len() > 0 {
+            res.push(ArrowBatch {
+                chunk: Arc::new(chunk),
+                schema: batch.schema,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+fn single_block_query(block_number: u64) -> Query {
+    Query {
+        from_block: block_number,
+        to_block: Some(block_number + 1),
+        logs: Vec::new(),
+        transactions: Vec::new(),
+        include_all_blocks: false,
+        field_selection: FieldSelection::default(),
+    }
+}
+
+fn match_all_transactions() -> TransactionSelection {
+    TransactionSelection {
+        from: Vec::new(),
+        to: Vec::new(),
+        sighash: Vec::new(),
+        status: None,
+    }
+}
+
+fn all_fields(schema: SchemaRef) -> BTreeSet<String> {
+    schema.fields.iter().map(|f| f.name.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::QueryConfig,
+        tests::{in_mem_fixture, TestState, FIXTURE_BLOCK},
+    };
+
+    use super::*;
+
+    const BLOCK_HASH: [u8; 32] =
+        hex_literal::hex!("a917fcc721a5465a484e9be17cda0cc5493933dd3bc70c9adbee192cb419c9d7");
+    // The second transaction of the block, it emitted 7 logs.
+    const TX_HASH: [u8; 32] =
+        hex_literal::hex!("4594fadbfa1b5ec0f3a0a13dd1d0ab42d176efd91ef14f6fcb84e9d06b02a159");
+    const UNKNOWN_HASH: [u8; 32] = [7; 32];
+
+    fn test_state() -> TestState {
+        TestState::new(QueryConfig {
+            time_limit_ms: 60_000,
+            max_concurrent_folders: None,
+            metadata_cache_mb: None,
+        })
+    }
+
+    fn num_rows(batches: &[ArrowBatch]) -> usize {
+        batches.iter().map(|batch| batch.chunk.len()).sum()
+    }
+
+    fn values(batches: &[ArrowBatch], column: &str) -> Vec<u64> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column::<UInt64Array>(column)
+                    .unwrap()
+                    .values_iter()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    // Runs the lookups against the fixture that has the test data at `block_number`.
+    fn check_lookups(test_state: &TestState, block_number: u64) {
+        let state = &test_state.state;
+        let path = &test_state.parquet_config.path;
+
+        let txs = transaction_by_hash(state, path, &TX_HASH).unwrap();
+        assert_eq!(num_rows(&txs), 1);
+        assert_eq!(values(&txs, "block_number"), vec![block_number]);
+        assert_eq!(values(&txs, "transaction_index"), vec![1]);
+        assert_eq!(
+            txs[0].column::<BinaryArray<i32>>("hash").unwrap().value(0),
+            TX_HASH
+        );
+        assert_eq!(
+            txs[0].chunk.columns().len(),
+            schema::transaction().fields.len()
+        );
+
+        let receipt = receipt_by_hash(state, path, &TX_HASH).unwrap();
+        assert_eq!(values(&receipt.transactions, "transaction_index"), vec![1]);
+        assert_eq!(values(&receipt.logs, "transaction_index"), vec![1; 7]);
+        assert!(receipt.blocks.is_empty());
+
+        let blocks = block_by_hash(state, path, &BLOCK_HASH).unwrap();
+        assert_eq!(values(&blocks, "number"), vec![block_number]);
+
+        let blocks = block_by_number(state, path, block_number).unwrap();
+        assert_eq!(values(&blocks, "number"), vec![block_number]);
+        assert_eq!(
+            blocks[0]
+                .column::<BinaryArray<i32>>("hash")
+                .unwrap()
+                .value(0),
+            BLOCK_HASH
+        );
+
+        let data = query_single_block(
+            state,
+            path,
+            &Query {
+                transactions: vec![match_all_transactions()],
+                field_selection: FieldSelection {
+                    transaction: ["hash".to_owned()].into_iter().collect(),
+                    ..Default::default()
+                },
+                ..single_block_query(block_number)
+            },
+        )
+        .unwrap();
+        assert_eq!(num_rows(&data.transactions), 204);
+
+        // Not found
+        assert!(transaction_by_hash(state, path, &UNKNOWN_HASH)
+            .unwrap()
+            .is_empty());
+        let receipt = receipt_by_hash(state, path, &UNKNOWN_HASH).unwrap();
+        assert!(receipt.transactions.is_empty() && receipt.logs.is_empty());
+        assert!(block_by_hash(state, path, &UNKNOWN_HASH)
+            .unwrap()
+            .is_empty());
+        assert!(block_by_number(state, path, block_number + 1)
+            .unwrap()
+            .is_empty());
+
+        let data = query_single_block(
+            state,
+            path,
+            &Query {
+                include_all_blocks: true,
+                field_selection: FieldSelection {
+                    block: ["number".to_owned()].into_iter().collect(),
+                    ..Default::default()
+                },
+                ..single_block_query(block_number + 1)
+            },
+        )
+        .unwrap();
+        assert!(data.blocks.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lookup_in_memory() {
+        let test_state = test_state();
+        test_state.set_in_mem(in_mem_fixture(FIXTURE_BLOCK));
+
+        check_lookups(&test_state, FIXTURE_BLOCK);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lookup_flushed() {
+        let test_state = test_state();
+        // The first folder has to start at block zero.
+        test_state.flush(in_mem_fixture(0)).await;
+
+        check_lookups(&test_state, 0);
+    }
+}
@@ -1,6 +1,10 @@
+mod address_index;
 mod data_provider;
 mod execution;
+mod explain;
 mod handler;
+mod lookup;
 
 pub use data_provider::ArrowBatch;
-pub use handler::Handler;
+pub use explain::QueryPlan;
+pub use handler::{Handler, QueryLimits, QueryResultReceiver};
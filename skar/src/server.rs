@@ -1,6 +1,10 @@
+use std::cmp;
+use std::convert::Infallible;
 use std::io::Write;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Instant;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use arrow2::array::BinaryArray;
@@ -8,45 +12,216 @@ use arrow2::array::MutableUtf8Array;
 use arrow2::array::Utf8Array;
 use arrow2::datatypes::DataType;
 use arrow2::datatypes::Field;
+use arrow2::datatypes::Metadata;
 use arrow2::datatypes::Schema;
+use arrow2::io::ipc::write::{StreamWriter, WriteOptions};
 use arrow2::io::json::write::RecordSerializer;
+use axum::body::{BoxBody, Bytes, HttpBody, StreamBody};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::Extension;
 use axum::extract::Json as ReqJson;
+use axum::extract::Path as AxumPath;
+use axum::extract::Query as AxumQuery;
 use axum::extract::State as AxumState;
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Json, Response};
+use http_body::SizeHint;
+use serde::Deserialize;
+use skar_format::Hash;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc as tokio_mpsc, Semaphore};
 use tower::ServiceBuilder;
 use tower_http::compression::CompressionLayer;
 
+use crate::admission::{Admission, AdmissionPermit, Overloaded, QueryCost};
+use crate::api_keys::{ApiKey, ApiKeys, Rejection};
 use crate::config::HttpServerConfig;
+use crate::metadata_cache::MetadataCacheStats;
 use crate::query::ArrowBatch;
-use crate::query::Handler;
-use crate::state::ArrowChunk;
-use crate::types::{Query, QueryResultData};
+use crate::query::{Handler, QueryLimits, QueryResultReceiver};
+use crate::state::{ArrowChunk, InMemoryUsage, IngestEvent};
+use crate::types::{Query, QueryResult, QueryResultData};
 use crate::write_parquet::concat_chunks;
 
+mod rpc;
+
 struct ServerState {
     cfg: HttpServerConfig,
     handler: Arc<Handler>,
+    admission: Arc<Admission>,
+    subscriptions: Arc<Semaphore>,
+    api_keys: Option<Arc<ApiKeys>>,
+}
+
+/// Api key of the request, if keys are required.
+type RequestKey = Option<Extension<Arc<ApiKey>>>;
+
+impl ServerState {
+    fn query_limits(&self, key: &RequestKey) -> QueryLimits {
+        let size_limit = self.cfg.response_size_limit_mb * MEGABYTES;
+
+        match key {
+            Some(Extension(key)) => key.query_limits(size_limit),
+            None => QueryLimits::new(size_limit),
+        }
+    }
 }
 
 const MEGABYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_SUBSCRIPTIONS: usize = 256;
 
-pub(crate) async fn run(cfg: HttpServerConfig, handler: Arc<Handler>) -> anyhow::Result<()> {
+pub(crate) async fn run(
+    cfg: HttpServerConfig,
+    handler: Arc<Handler>,
+    admission: Arc<Admission>,
+    api_keys: Option<Arc<ApiKeys>>,
+) -> anyhow::Result<()> {
     let addr = cfg.addr;
-    let state = ServerState { cfg, handler };
-    let state = Arc::new(state);
+    let state = ServerState {
+        subscriptions: Arc::new(Semaphore::new(
+            cfg.max_subscriptions.unwrap_or(DEFAULT_MAX_SUBSCRIPTIONS),
+        )),
+        cfg,
+        handler,
+        admission,
+        api_keys,
+    };
+
+    axum::Server::bind(&addr)
+        .serve(router(Arc::new(state)).into_make_service())
+        .await
+        .context("run http server")
+}
 
-    let app = axum::Router::new()
+fn router(state: Arc<ServerState>) -> axum::Router {
+    axum::Router::new()
         .route(
             "/height",
             axum::routing::get(get_height).with_state(state.clone()),
         )
-        .route("/query", axum::routing::post(run_query).with_state(state))
-        .layer(ServiceBuilder::new().layer(CompressionLayer::new()));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .route(
+            "/memory",
+            axum::routing::get(get_memory).with_state(state.clone()),
+        )
+        .route(
+            "/health",
+            axum::routing::get(get_health).with_state(state.clone()),
+        )
+        .route(
+            "/ready",
+            axum::routing::get(get_ready).with_state(state.clone()),
+        )
+        .route(
+            "/metrics",
+            axum::routing::get(get_metrics).with_state(state.clone()),
+        )
+        .route(
+            "/metadata_cache",
+            axum::routing::get(get_metadata_cache).with_state(state.clone()),
+        )
+        .route(
+            "/tx/:hash",
+            axum::routing::get(get_transaction).with_state(state.clone()),
+        )
+        .route(
+            "/receipt/:hash",
+            axum::routing::get(get_receipt).with_state(state.clone()),
+        )
+        .route(
+            "/block/:hash_or_number",
+            axum::routing::get(get_block).with_state(state.clone()),
+        )
+        .route(
+            "/query",
+            axum::routing::post(run_query).with_state(state.clone()),
+        )
+        .route(
+            "/query/explain",
+            axum::routing::post(explain_query).with_state(state.clone()),
+        )
+        .route(
+            "/subscribe",
+            axum::routing::post(subscribe).with_state(state.clone()),
+        )
+        .route(
+            "/rpc",
+            axum::routing::post(rpc::handle_rpc).with_state(state.clone()),
+        )
+        .layer(axum::middleware::from_fn_with_state(state, authenticate))
+        .layer(ServiceBuilder::new().layer(CompressionLayer::new()))
+}
+
+// Rejects requests without a valid api key if keys are required.
+//
+// `/health`, `/ready` and `/metrics` don't require a key so load balancers and prometheus can
+// check them.
+//
+// The key is added to the request extensions so the handlers can apply its limits, and the
+// response body is counted against its daily quota as it is sent.
+async fn authenticate<B>(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) if !matches!(request.uri().path(), "/health" | "/ready" | "/metrics") => {
+            api_keys
+        }
+        _ => return next.run(request).await,
+    };
+
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let key = match api_keys.authenticate(authorization) {
+        Ok(key) => key,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    request.extensions_mut().insert(key.clone());
+
+    next.run(request)
         .await
-        .context("run http server")
+        .map(|body| axum::body::boxed(CountingBody { inner: body, key }))
+}
+
+struct CountingBody {
+    inner: BoxBody,
+    key: Arc<ApiKey>,
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            self.key.add_response_bytes(data.len());
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
 }
 
 async fn get_height(
@@ -63,33 +238,378 @@ async fn get_height(
     })))
 }
 
+async fn get_health(AxumState(state): AxumState<Arc<ServerState>>) -> Result<Response, AppError> {
+    let report = state.handler.health(&state.cfg.health).await?;
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok((status, Json(report)).into_response())
+}
+
+async fn get_ready(AxumState(state): AxumState<Arc<ServerState>>) -> Result<Response, AppError> {
+    let report = state.handler.health(&state.cfg.health).await?;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok((status, Json(report)).into_response())
+}
+
+async fn get_memory(AxumState(state): AxumState<Arc<ServerState>>) -> Json<InMemoryUsage> {
+    Json(state.handler.in_memory_usage())
+}
+
+async fn get_metrics(AxumState(state): AxumState<Arc<ServerState>>) -> Result<Response, AppError> {
+    let metrics = state.handler.encode_metrics()?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics).into_response())
+}
+
+async fn get_metadata_cache(
+    AxumState(state): AxumState<Arc<ServerState>>,
+) -> Json<MetadataCacheStats> {
+    Json(state.handler.metadata_cache_stats())
+}
+
+async fn get_transaction(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    AxumPath(hash): AxumPath<String>,
+) -> Result<Response, AppError> {
+    let hash = parse_hash(&hash)?;
+
+    let batches = state
+        .handler
+        .clone()
+        .transaction_by_hash(hash)
+        .await
+        .context("lookup transaction")?;
+
+    let tx = batches_to_json_rows(&batches)?.into_iter().next();
+
+    json_or_not_found(tx, "transaction")
+}
+
+async fn get_receipt(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    AxumPath(hash): AxumPath<String>,
+) -> Result<Response, AppError> {
+    let hash = parse_hash(&hash)?;
+
+    let data = state
+        .handler
+        .clone()
+        .receipt_by_hash(hash)
+        .await
+        .context("lookup receipt")?;
+
+    let receipt = match batches_to_json_rows(&data.transactions)?.into_iter().next() {
+        Some(serde_json::Value::Object(mut receipt)) => {
+            let logs = batches_to_json_rows(&data.logs)?;
+            receipt.insert("logs".to_owned(), logs.into());
+            Some(receipt.into())
+        }
+        _ => None,
+    };
+
+    json_or_not_found(receipt, "receipt")
+}
+
+async fn get_block(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    AxumPath(hash_or_number): AxumPath<String>,
+) -> Result<Response, AppError> {
+    let handler = state.handler.clone();
+
+    let batches = if hash_or_number.starts_with("0x") {
+        let hash = parse_hash(&hash_or_number)?;
+        handler.block_by_hash(hash).await
+    } else {
+        let block_number = hash_or_number
+            .parse::<u64>()
+            .map_err(|e| AppError::BadRequest(format!("invalid block number: {}", e)))?;
+        handler.block_by_number(block_number).await
+    }
+    .context("lookup block")?;
+
+    let block = batches_to_json_rows(&batches)?.into_iter().next();
+
+    json_or_not_found(block, "block")
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, AppError> {
+    let hash: Vec<u8> = prefix_hex::decode(hash)
+        .map_err(|e| AppError::BadRequest(format!("failed to decode hash: {:?}", e)))?;
+    let hash = Hash::try_from(hash)
+        .map_err(|_| AppError::BadRequest("hash should be 32 bytes".to_owned()))?;
+
+    Ok(hash)
+}
+
+fn json_or_not_found(value: Option<serde_json::Value>, item: &str) -> Result<Response, AppError> {
+    match value {
+        Some(value) => Ok(Json(value).into_response()),
+        None => Err(AppError::NotFound(format!("{} not found", item))),
+    }
+}
+
+// Rejected bodies are the client's fault, so they are not turned into internal errors by `?`.
+fn json_body<T>(body: Result<ReqJson<T>, JsonRejection>) -> Result<T, AppError> {
+    match body {
+        Ok(ReqJson(body)) => Ok(body),
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            Err(AppError::PayloadTooLarge(rejection.body_text()))
+        }
+        Err(rejection) => Err(AppError::BadRequest(rejection.body_text())),
+    }
+}
+
+// Parses the query body and checks it before any work is done for it.
+fn query_body(body: Result<ReqJson<Query>, JsonRejection>) -> Result<Query, AppError> {
+    let query = json_body(body)?;
+    query
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    Ok(query)
+}
+
+// Converts the batches into json rows, using the same encoding as the query endpoint
+fn batches_to_json_rows(batches: &[ArrowBatch]) -> Result<Vec<serde_json::Value>, AppError> {
+    let batches = batches
+        .iter()
+        .map(hex_encode_batch)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("hex encode the data")?;
+
+    let json_rows = record_batches_to_json_rows(&batches).context("serialize arrow into json")?;
+    let rows = serde_json::from_slice(&json_rows).context("parse json rows")?;
+
+    Ok(rows)
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseFormat {
+    Json,
+    Ndjson,
+    Arrow,
+}
+
+const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// The `format` query parameter takes precedence over the `Accept` header
+fn response_format(params: &QueryParams, headers: &HeaderMap) -> Result<ResponseFormat, AppError> {
+    if let Some(format) = params.format.as_deref() {
+        return match format {
+            "json" => Ok(ResponseFormat::Json),
+            "ndjson" => Ok(ResponseFormat::Ndjson),
+            "arrow" => Ok(ResponseFormat::Arrow),
+            _ => Err(AppError::BadRequest(format!(
+                "unknown response format: {}",
+                format
+            ))),
+        };
+    }
+
+    let accepts = |content_type: &str| {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_type| {
+                media_type.split(';').next().unwrap_or_default().trim() == content_type
+            })
+    };
+
+    if accepts(ARROW_STREAM_CONTENT_TYPE) {
+        Ok(ResponseFormat::Arrow)
+    } else if accepts(NDJSON_CONTENT_TYPE) {
+        Ok(ResponseFormat::Ndjson)
+    } else {
+        Ok(ResponseFormat::Json)
+    }
+}
+
 async fn run_query(
     AxumState(state): AxumState<Arc<ServerState>>,
-    ReqJson(query): ReqJson<Query>,
+    params: Result<AxumQuery<QueryParams>, QueryRejection>,
+    key: RequestKey,
+    headers: HeaderMap,
+    query: Result<ReqJson<Query>, JsonRejection>,
 ) -> Result<Response, AppError> {
     let query_start = Instant::now();
 
-    let mut rx = state
+    let AxumQuery(params) =
+        params.map_err(|rejection| AppError::BadRequest(rejection.body_text()))?;
+    let format = response_format(&params, &headers)?;
+    let query = query_body(query)?;
+
+    let num_folders = state
+        .handler
+        .count_folders(&query)
+        .await
+        .context("count folders in query range")?;
+    let cost = QueryCost::estimate(state.admission.cfg(), &query, num_folders);
+
+    // Held until the response is sent so the slot is released after the query finishes.
+    let permit = match state.admission.admit(cost).await {
+        Ok(permit) => permit,
+        Err(overloaded) => return Ok(overloaded.into_response()),
+    };
+
+    let limits = state.query_limits(&key);
+
+    let rx = state
         .handler
         .clone()
-        .handle(query)
+        .handle(query, limits)
         .context("start running query")?;
 
-    let mut bytes = br#"{"data":["#.to_vec();
+    match format {
+        ResponseFormat::Json | ResponseFormat::Ndjson => {
+            json_query_response(state, rx, query_start, permit, format, limits.size_limit).await
+        }
+        ResponseFormat::Arrow => arrow_query_response(&state, rx, query_start).await,
+    }
+}
 
-    let mut next_block = 0;
+// Plans the query without reading the data, so users can see which folders and row groups
+// their selections read.
+async fn explain_query(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    key: RequestKey,
+    query: Result<ReqJson<Query>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let query = query_body(query)?;
+
+    // Explaining only reads the indices and parquet footers, so it always takes a light slot.
+    let _permit = match state.admission.admit(QueryCost::Light).await {
+        Ok(permit) => permit,
+        Err(overloaded) => return Ok(overloaded.into_response()),
+    };
+
+    let plan = state
+        .handler
+        .clone()
+        .explain(query, state.query_limits(&key))
+        .await
+        .context("explain query")?;
+
+    Ok(Json(plan).into_response())
+}
+
+type BodySender = tokio_mpsc::Sender<anyhow::Result<Vec<u8>>>;
+
+// Streams the response body with chunked encoding, writing each folder result as it is received.
+//
+// The first result is awaited before responding, so a query that fails right away still gets
+// an error status. Errors after that abort the response since the status is already sent.
+async fn json_query_response(
+    state: Arc<ServerState>,
+    mut rx: QueryResultReceiver,
+    query_start: Instant,
+    permit: AdmissionPermit,
+    format: ResponseFormat,
+    size_limit: usize,
+) -> Result<Response, AppError> {
+    let first = match rx.recv().await {
+        Some(res) => Some(res.context("execute parquet query")?),
+        None => None,
+    };
 
+    let (tx, rx_body) = tokio_mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let _permit = permit;
+
+        let res = match format {
+            ResponseFormat::Ndjson => {
+                stream_ndjson(&state, rx, first, query_start, size_limit, &tx).await
+            }
+            _ => stream_json(&state, rx, first, query_start, size_limit, &tx).await,
+        };
+
+        if let Err(e) = res {
+            log::error!("failed to stream query response: {:?}", e);
+            tx.send(Err(e)).await.ok();
+        }
+    });
+
+    let content_type = match format {
+        ResponseFormat::Ndjson => NDJSON_CONTENT_TYPE,
+        _ => "application/json",
+    };
+
+    let body = futures::stream::unfold(rx_body, |mut rx| async move {
+        rx.recv().await.map(|bytes| (bytes, rx))
+    });
+
+    let mut response = StreamBody::new(body).into_response();
+
+    response
+        .headers_mut()
+        .insert("content-type", content_type.try_into().unwrap());
+
+    Ok(response)
+}
+
+// Writes `{"data":[...],"archive_height":..,"next_block":..,"total_execution_time":..}`
+// with an element in `data` for each folder result that has any rows.
+//
+// Returns early without an error if the client is gone, dropping the receiver cancels the query.
+async fn stream_json(
+    state: &ServerState,
+    mut rx: QueryResultReceiver,
+    first: Option<QueryResult>,
+    query_start: Instant,
+    size_limit: usize,
+    tx: &BodySender,
+) -> anyhow::Result<()> {
+    if tx.send(Ok(br#"{"data":["#.to_vec())).await.is_err() {
+        return Ok(());
+    }
+
+    let mut bytes_written = 0;
+    let mut next_block = 0;
     let mut put_comma = false;
-    while let Some(res) = rx.recv().await {
-        let query_result = res.context("execute parquet query")?;
 
-        put_comma |= extend_bytes_with_data(put_comma, &mut bytes, &query_result.data)?;
+    let mut query_result = first;
+    while let Some(result) = query_result {
+        let mut bytes = Vec::new();
+        put_comma |= extend_bytes_with_data(put_comma, &mut bytes, &result.data)?;
+        next_block = result.next_block;
 
-        next_block = query_result.next_block;
+        if !bytes.is_empty() {
+            bytes_written += bytes.len();
+            if tx.send(Ok(bytes)).await.is_err() {
+                return Ok(());
+            }
+        }
 
-        if bytes.len() >= state.cfg.response_size_limit_mb * MEGABYTES {
+        if bytes_written >= size_limit {
             break;
         }
+
+        // Stop waiting for the folder as soon as the client is gone, instead of noticing it
+        // on the next send.
+        query_result = tokio::select! {
+            _ = tx.closed() => return Ok(()),
+            res = rx.recv() => match res {
+                Some(res) => Some(res.context("execute parquet query")?),
+                None => None,
+            },
+        };
     }
 
     let height = state
@@ -98,6 +618,7 @@ async fn run_query(
         .await
         .context("get archive height")?;
 
+    let mut bytes = Vec::new();
     write!(
         &mut bytes,
         r#"],"archive_height":{},"next_block":{},"total_execution_time":{}}}"#,
@@ -107,21 +628,434 @@ async fn run_query(
     )
     .unwrap();
 
+    tx.send(Ok(bytes)).await.ok();
+
+    Ok(())
+}
+
+// Writes a `{"data":{..},"next_block":..}` line for each folder result that has any rows
+// and ends with a `{"archive_height":..,"next_block":..,"total_execution_time":..}` line.
+//
+// Returns early without an error if the client is gone, dropping the receiver cancels the query.
+async fn stream_ndjson(
+    state: &ServerState,
+    mut rx: QueryResultReceiver,
+    first: Option<QueryResult>,
+    query_start: Instant,
+    size_limit: usize,
+    tx: &BodySender,
+) -> anyhow::Result<()> {
+    let mut bytes_written = 0;
+    let mut next_block = 0;
+
+    let mut query_result = first;
+    while let Some(result) = query_result {
+        let mut bytes = br#"{"data":"#.to_vec();
+        next_block = result.next_block;
+
+        if extend_bytes_with_data(false, &mut bytes, &result.data)? {
+            writeln!(&mut bytes, r#","next_block":{}}}"#, next_block).unwrap();
+
+            bytes_written += bytes.len();
+            if tx.send(Ok(bytes)).await.is_err() {
+                return Ok(());
+            }
+        }
+
+        if bytes_written >= size_limit {
+            break;
+        }
+
+        // Stop waiting for the folder as soon as the client is gone, instead of noticing it
+        // on the next send.
+        query_result = tokio::select! {
+            _ = tx.closed() => return Ok(()),
+            res = rx.recv() => match res {
+                Some(res) => Some(res.context("execute parquet query")?),
+                None => None,
+            },
+        };
+    }
+
+    let height = state
+        .handler
+        .archive_height()
+        .await
+        .context("get archive height")?;
+
+    let mut bytes = Vec::new();
+    writeln!(
+        &mut bytes,
+        r#"{{"archive_height":{},"next_block":{},"total_execution_time":{}}}"#,
+        height.map(|n| n.to_string()).unwrap_or("null".to_owned()),
+        next_block,
+        query_start.elapsed().as_millis(),
+    )
+    .unwrap();
+
+    tx.send(Ok(bytes)).await.ok();
+
+    Ok(())
+}
+
+type EventSender = tokio_mpsc::Sender<Event>;
+
+// Streams the results of the query as server sent events, first for the data that is
+// already ingested and then for the new data as it enters memory.
+async fn subscribe(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    key: RequestKey,
+    query: Result<ReqJson<Query>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let query = query_body(query)?;
+
+    let permit = match state.subscriptions.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            return Ok(Overloaded {
+                retry_after: Duration::from_secs(state.admission.cfg().retry_after_secs),
+            }
+            .into_response())
+        }
+    };
+
+    let limits = state.query_limits(&key);
+    let (tx, rx) = tokio_mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let _permit = permit;
+
+        if let Err(e) = run_subscription(&state, query, limits, &tx).await {
+            log::error!("subscription failed: {:?}", e);
+            let event = Event::default().event("error").data(
+                serde_json::json!({
+                    "code": INTERNAL_ERROR_CODE,
+                    "message": INTERNAL_ERROR_MESSAGE,
+                })
+                .to_string(),
+            );
+            tx.send(event).await.ok();
+        }
+    });
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+// Returns early without an error if the client is gone.
+async fn run_subscription(
+    state: &ServerState,
+    mut query: Query,
+    limits: QueryLimits,
+    tx: &EventSender,
+) -> anyhow::Result<()> {
+    // Subscribe before catching up so no new data is missed in between.
+    let mut events = state.handler.ingest_events();
+    let to_block = query.to_block.unwrap_or(u64::MAX);
+
+    loop {
+        // Catch up with the data that is already ingested.
+        loop {
+            let height = state
+                .handler
+                .archive_height()
+                .await
+                .context("get archive height")?;
+            let end = cmp::min(to_block, height.map(|h| h + 1).unwrap_or(0));
+
+            if query.from_block >= end {
+                break;
+            }
+
+            let from_block = query.from_block;
+            query.to_block = Some(end);
+
+            if !catch_up(state, &mut query, limits, tx).await? {
+                return Ok(());
+            }
+
+            if tx
+                .send(progress_event(query.from_block, height))
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+
+            // Nothing to read until new data is ingested.
+            if query.from_block == from_block {
+                break;
+            }
+        }
+
+        // Follow the data as it enters memory until the subscription falls behind the in memory
+        // data, then catch up from the folders again.
+        loop {
+            if query.from_block >= to_block {
+                return Ok(());
+            }
+
+            let event = tokio::select! {
+                _ = tx.closed() => return Ok(()),
+                event = events.recv() => event,
+            };
+
+            match event {
+                // Missed events don't matter since each update reads everything from
+                // `next_block` to the end of the in memory data.
+                Ok(IngestEvent::NewData { .. }) | Err(RecvError::Lagged(_)) => {
+                    query.to_block = Some(to_block);
+
+                    match live_update(state, &mut query, tx).await? {
+                        LiveUpdate::Sent => (),
+                        LiveUpdate::NotInMemory => break,
+                        LiveUpdate::Disconnected => return Ok(()),
+                    }
+                }
+                Ok(IngestEvent::Reorg {
+                    block_number,
+                    parent_hash,
+                    expected_parent_hash,
+                }) => {
+                    let event = Event::default().event("reorg").data(
+                        serde_json::json!({
+                            "block_number": block_number,
+                            "parent_hash": parent_hash,
+                            "expected_parent_hash": expected_parent_hash,
+                        })
+                        .to_string(),
+                    );
+                    if tx.send(event).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+enum LiveUpdate {
+    Sent,
+    /// Some of the blocks after `next_block` were already dropped from memory.
+    NotInMemory,
+    Disconnected,
+}
+
+// Runs the query on the blocks that entered memory since the last update, without going
+// through admission, and sends a `data` event if there are any rows and a `progress` event.
+async fn live_update(
+    state: &ServerState,
+    query: &mut Query,
+    tx: &EventSender,
+) -> anyhow::Result<LiveUpdate> {
+    let query_result = match state
+        .handler
+        .query_in_memory(query.clone())
+        .await
+        .context("run query on in memory data")?
+    {
+        Some(query_result) => query_result,
+        None => return Ok(LiveUpdate::NotInMemory),
+    };
+
+    if query_result.next_block == query.from_block {
+        return Ok(LiveUpdate::Sent);
+    }
+    query.from_block = query_result.next_block;
+
+    if !send_data(tx, &query_result.data, query.from_block).await? {
+        return Ok(LiveUpdate::Disconnected);
+    }
+
+    let height = state
+        .handler
+        .archive_height()
+        .await
+        .context("get archive height")?;
+    if tx
+        .send(progress_event(query.from_block, height))
+        .await
+        .is_err()
+    {
+        return Ok(LiveUpdate::Disconnected);
+    }
+
+    Ok(LiveUpdate::Sent)
+}
+
+fn progress_event(next_block: u64, archive_height: Option<u64>) -> Event {
+    Event::default().event("progress").data(
+        serde_json::json!({
+            "next_block": next_block,
+            "archive_height": archive_height,
+        })
+        .to_string(),
+    )
+}
+
+// Sends a `data` event if there are any rows, returns false if the client is gone.
+async fn send_data(
+    tx: &EventSender,
+    data: &QueryResultData,
+    next_block: u64,
+) -> anyhow::Result<bool> {
+    let mut bytes = br#"{"data":"#.to_vec();
+    if !extend_bytes_with_data(false, &mut bytes, data)? {
+        return Ok(true);
+    }
+    write!(&mut bytes, r#","next_block":{}}}"#, next_block).unwrap();
+
+    let event = Event::default()
+        .event("data")
+        .data(String::from_utf8(bytes).context("convert json to string")?);
+
+    Ok(tx.send(event).await.is_ok())
+}
+
+// Runs the query once and sends a `data` event for each folder result that has any rows,
+// advancing `query.from_block` to the `next_block` of the results.
+//
+// Returns false if the client is gone.
+async fn catch_up(
+    state: &ServerState,
+    query: &mut Query,
+    limits: QueryLimits,
+    tx: &EventSender,
+) -> anyhow::Result<bool> {
+    let num_folders = state
+        .handler
+        .count_folders(query)
+        .await
+        .context("count folders in query range")?;
+    let cost = QueryCost::estimate(state.admission.cfg(), query, num_folders);
+
+    let _permit = loop {
+        match state.admission.admit(cost).await {
+            Ok(permit) => break permit,
+            Err(overloaded) => {
+                tokio::select! {
+                    _ = tx.closed() => return Ok(false),
+                    _ = tokio::time::sleep(overloaded.retry_after) => (),
+                }
+            }
+        }
+    };
+
+    let mut rx = state
+        .handler
+        .clone()
+        .handle(query.clone(), limits)
+        .context("start running query")?;
+
+    while let Some(res) = rx.recv().await {
+        let query_result = res.context("execute query")?;
+        query.from_block = query_result.next_block;
+
+        if !send_data(tx, &query_result.data, query.from_block).await? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Writes logs, transactions and blocks as three consecutive arrow ipc streams.
+//
+// The schema of each stream has the name of the table in its metadata along with
+// `next_block`, `archive_height` and `total_execution_time`. Binary columns are not hex encoded.
+async fn arrow_query_response(
+    state: &ServerState,
+    mut rx: QueryResultReceiver,
+    query_start: Instant,
+) -> Result<Response, AppError> {
+    let mut data = QueryResultData::default();
+    let mut next_block = 0;
+
+    while let Some(res) = rx.recv().await {
+        let query_result = res.context("execute parquet query")?;
+
+        data.logs.extend(query_result.data.logs);
+        data.transactions.extend(query_result.data.transactions);
+        data.blocks.extend(query_result.data.blocks);
+
+        next_block = query_result.next_block;
+    }
+
+    let height = state
+        .handler
+        .archive_height()
+        .await
+        .context("get archive height")?;
+
+    let mut metadata = Metadata::new();
+    metadata.insert("next_block".to_owned(), next_block.to_string());
+    if let Some(height) = height {
+        metadata.insert("archive_height".to_owned(), height.to_string());
+    }
+    metadata.insert(
+        "total_execution_time".to_owned(),
+        query_start.elapsed().as_millis().to_string(),
+    );
+
+    let mut bytes = Vec::new();
+    for (table, batches) in [
+        ("logs", &data.logs),
+        ("transactions", &data.transactions),
+        ("blocks", &data.blocks),
+    ] {
+        let mut metadata = metadata.clone();
+        metadata.insert("table".to_owned(), table.to_owned());
+
+        write_ipc_stream(&mut bytes, batches, metadata)
+            .with_context(|| format!("write {} to arrow ipc stream", table))?;
+    }
+
     let mut response: Response = bytes.into_response();
 
-    response
-        .headers_mut()
-        .insert("content-type", "application/json".try_into().unwrap());
+    response.headers_mut().insert(
+        "content-type",
+        ARROW_STREAM_CONTENT_TYPE.try_into().unwrap(),
+    );
 
     Ok(response)
 }
 
+fn write_ipc_stream(
+    out: &mut Vec<u8>,
+    batches: &[ArrowBatch],
+    metadata: Metadata,
+) -> anyhow::Result<()> {
+    let schema = match batches.first() {
+        Some(batch) => Schema::clone(&batch.schema),
+        None => Schema::default(),
+    };
+    let schema = schema.with_metadata(metadata);
+
+    let mut writer = StreamWriter::new(out, WriteOptions { compression: None });
+
+    writer.start(&schema, None).context("start stream")?;
+    for batch in batches {
+        writer.write(&batch.chunk, None).context("write batch")?;
+    }
+    writer.finish().context("finish stream")?;
+
+    Ok(())
+}
+
 // returns if it wrote any data
 fn extend_bytes_with_data(
     put_comma_outer: bool,
     bytes: &mut Vec<u8>,
     data: &QueryResultData,
-) -> Result<bool, AppError> {
+) -> anyhow::Result<bool> {
     if data.logs.is_empty() && data.transactions.is_empty() && data.blocks.is_empty() {
         return Ok(false);
     }
@@ -190,28 +1124,122 @@ fn record_batches_to_json_rows(batches: &[ArrowBatch]) -> anyhow::Result<Vec<u8>
     Ok(out)
 }
 
-// Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+const INTERNAL_ERROR_CODE: &str = "internal_error";
+const INTERNAL_ERROR_MESSAGE: &str = "Internal server error";
 
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
+// Every error response has a `{"code": .., "message": ..}` body.
+//
+// The codes are stable so clients can match on them, the messages are for humans.
+fn error_response(status: StatusCode, code: &str, message: impl Into<String>) -> Response {
+    (
+        status,
+        Json(serde_json::json!({
+            "code": code,
+            "message": message.into(),
+        })),
+    )
+        .into_response()
+}
+
+impl IntoResponse for Overloaded {
     fn into_response(self) -> Response {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {:?}", self.0),
+            [(header::RETRY_AFTER, retry_after_secs(self.retry_after))],
+            error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "overloaded",
+                "Too many queries are running, retry later",
+            ),
         )
             .into_response()
     }
 }
 
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Rejection::Unauthorized => (
+                [(header::WWW_AUTHENTICATE, "Bearer".to_owned())],
+                error_response(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "Missing or unknown api key",
+                ),
+            )
+                .into_response(),
+            Rejection::RateLimited { retry_after } => (
+                [(header::RETRY_AFTER, retry_after_secs(retry_after))],
+                error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    "Rate limit of the api key is exceeded, retry later",
+                ),
+            )
+                .into_response(),
+            Rejection::QuotaExceeded { retry_after } => (
+                [(header::RETRY_AFTER, retry_after_secs(retry_after))],
+                error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "quota_exceeded",
+                    "Daily quota of the api key is used up",
+                ),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// Rounds up so clients don't retry before the limit is lifted.
+fn retry_after_secs(retry_after: Duration) -> String {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    secs.to_string()
+}
+
+enum AppError {
+    /// The request is malformed or the query is invalid.
+    BadRequest(String),
+    NotFound(String),
+    /// The request body is over the size limit.
+    PayloadTooLarge(String),
+    /// Only logged, so file paths and other internals don't leak to clients.
+    Internal(anyhow::Error),
+}
+
+// Tell axum how to convert `AppError` into a response.
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match self {
+            AppError::BadRequest(message) => {
+                error_response(StatusCode::BAD_REQUEST, "bad_request", message)
+            }
+            AppError::NotFound(message) => {
+                error_response(StatusCode::NOT_FOUND, "not_found", message)
+            }
+            AppError::PayloadTooLarge(message) => {
+                error_response(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+            }
+            AppError::Internal(e) => {
+                log::error!("failed to handle request: {:?}", e);
+                error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    INTERNAL_ERROR_CODE,
+                    INTERNAL_ERROR_MESSAGE,
+                )
+            }
+        }
+    }
+}
+
 // This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
 // `Result<_, AppError>`. That way you don't need to do that manually.
+//
+// Errors that are the client's fault have to be mapped to the other variants explicitly.
 impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self::Internal(err.into())
     }
 }
 
@@ -269,3 +1297,126 @@ fn hex_encode(input: &BinaryArray<i32>) -> Utf8Array<i32> {
 
     arr.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use crate::{config::QueryConfig, tests::TestState};
+
+    use super::*;
+
+    #[test]
+    fn test_response_format() {
+        let no_format = QueryParams { format: None };
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            response_format(&no_format, &headers).unwrap(),
+            ResponseFormat::Json
+        );
+
+        headers.insert(
+            header::ACCEPT,
+            "application/json, application/vnd.apache.arrow.stream;q=0.9"
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(
+            response_format(&no_format, &headers).unwrap(),
+            ResponseFormat::Arrow
+        );
+
+        let json = QueryParams {
+            format: Some("json".to_owned()),
+        };
+        assert_eq!(
+            response_format(&json, &headers).unwrap(),
+            ResponseFormat::Json
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "application/x-ndjson".try_into().unwrap());
+        assert_eq!(
+            response_format(&no_format, &headers).unwrap(),
+            ResponseFormat::Ndjson
+        );
+
+        let unknown = QueryParams {
+            format: Some("csv".to_owned()),
+        };
+        assert!(response_format(&unknown, &headers).is_err());
+    }
+
+    #[test]
+    fn test_error_status() {
+        let response = AppError::BadRequest("invalid".to_owned()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = AppError::from(anyhow::anyhow!("/secret/path")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = Overloaded {
+            retry_after: Duration::from_millis(1500),
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+
+    async fn status(app: &axum::Router, uri: &str, key: Option<&str>) -> StatusCode {
+        let mut request = Request::get(uri);
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        let request = request.body(axum::body::Body::empty()).unwrap();
+
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authenticate() {
+        let test_state = TestState::new(QueryConfig {
+            time_limit_ms: 1000,
+            max_concurrent_folders: None,
+            metadata_cache_mb: None,
+        });
+        let cfg: HttpServerConfig = serde_json::from_value(serde_json::json!({
+            "addr": "127.0.0.1:0",
+            "response_size_limit_mb": 1,
+            "api_keys": [{"name": "test", "key": "secret"}],
+        }))
+        .unwrap();
+        let api_keys = ApiKeys::load(&cfg, test_state.state.db.clone())
+            .unwrap()
+            .unwrap();
+
+        let app = router(Arc::new(ServerState {
+            handler: test_state.handler.clone(),
+            admission: Arc::new(Admission::new(cfg.admission)),
+            subscriptions: Arc::new(Semaphore::new(1)),
+            api_keys: Some(Arc::new(api_keys)),
+            cfg,
+        }));
+
+        assert_eq!(status(&app, "/metrics", None).await, StatusCode::OK);
+        assert_ne!(
+            status(&app, "/health", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_ne!(status(&app, "/ready", None).await, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(
+            status(&app, "/height", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/height", Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, "/height", Some("secret")).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,909 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::body::Bytes;
+use axum::extract::State as AxumState;
+use axum::response::Json;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+use skar_format::{Address, Hash, LogArgument};
+
+use crate::admission::QueryCost;
+use crate::query::QueryLimits;
+use crate::schema;
+use crate::types::{FieldSelection, LogSelection, Query};
+
+use super::{batches_to_json_rows, AppError, RequestKey, ServerState};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const LIMIT_EXCEEDED: i64 = -32005;
+
+const BLOCK_QUANTITIES: &[&str] = &[
+    "difficulty",
+    "total_difficulty",
+    "size",
+    "gas_limit",
+    "gas_used",
+    "timestamp",
+    "base_fee_per_gas",
+];
+
+const TRANSACTION_QUANTITIES: &[&str] = &[
+    "gas",
+    "gas_price",
+    "nonce",
+    "value",
+    "v",
+    "r",
+    "s",
+    "max_priority_fee_per_gas",
+    "max_fee_per_gas",
+    "chain_id",
+    "cumulative_gas_used",
+    "effective_gas_price",
+    "gas_used",
+];
+
+// The transaction table also has the receipt fields, these are the ones of a transaction object.
+const TRANSACTION_FIELDS: &[&str] = &[
+    "block_hash",
+    "block_number",
+    "from",
+    "gas",
+    "gas_price",
+    "hash",
+    "input",
+    "nonce",
+    "to",
+    "transaction_index",
+    "value",
+    "v",
+    "r",
+    "s",
+    "max_priority_fee_per_gas",
+    "max_fee_per_gas",
+    "chain_id",
+    "type",
+];
+
+// Fields that are encoded as null instead of being left out when they are missing.
+const NULLABLE_FIELDS: &[&str] = &["to", "contract_address"];
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// The details of internal errors are only logged, like in the rest of the http api.
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        log::error!("failed to handle rpc request: {:?}", e);
+        Self::new(INTERNAL_ERROR, "internal error")
+    }
+}
+
+impl From<AppError> for RpcError {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::BadRequest(message)
+            | AppError::NotFound(message)
+            | AppError::PayloadTooLarge(message) => Self::new(INVALID_PARAMS, message),
+            AppError::Internal(e) => e.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogFilter {
+    from_block: Option<Value>,
+    to_block: Option<Value>,
+    block_hash: Option<Hash>,
+    address: Option<OneOrMany<Address>>,
+    #[serde(default)]
+    topics: Vec<Option<OneOrMany<LogArgument>>>,
+}
+
+// Handles a single request or a batch of requests.
+pub(super) async fn handle_rpc(
+    AxumState(state): AxumState<Arc<ServerState>>,
+    key: RequestKey,
+    body: Bytes,
+) -> Json<Value> {
+    let limits = state.query_limits(&key);
+
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Json(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ))
+        }
+    };
+
+    match request {
+        Value::Array(requests) if !requests.is_empty() => {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.push(handle_request(&state, limits, request).await);
+            }
+            Json(Value::Array(responses))
+        }
+        request => Json(handle_request(&state, limits, request).await),
+    }
+}
+
+async fn handle_request(state: &ServerState, limits: QueryLimits, request: Value) -> Value {
+    let request: Request = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return error_response(Value::Null, RpcError::new(INVALID_REQUEST, e.to_string()))
+        }
+    };
+
+    match call(state, limits, &request.method, &request.params).await {
+        Ok(result) => json!({
+            "jsonrpc": "2.0",
+            "id": request.id,
+            "result": result,
+        }),
+        Err(e) => error_response(request.id, e),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": error.code,
+            "message": error.message,
+        },
+    })
+}
+
+async fn call(
+    state: &ServerState,
+    limits: QueryLimits,
+    method: &str,
+    params: &[Value],
+) -> Result<Value, RpcError> {
+    match method {
+        "eth_blockNumber" => {
+            let height = state
+                .handler
+                .archive_height()
+                .await
+                .context("get archive height")?;
+            Ok(quantity(height.unwrap_or(0)))
+        }
+        "eth_getBlockByNumber" => {
+            let block_number = block_number_param(state, param(params, 0)?).await?;
+            let full = optional_param(params, 1)?.unwrap_or(false);
+            match block_number {
+                Some(block_number) => block(state, block_number, full).await,
+                None => Ok(Value::Null),
+            }
+        }
+        "eth_getBlockByHash" => {
+            let full = optional_param(params, 1)?.unwrap_or(false);
+            match block_number_by_hash(state, param(params, 0)?).await? {
+                Some(block_number) => block(state, block_number, full).await,
+                None => Ok(Value::Null),
+            }
+        }
+        "eth_getTransactionByHash" => {
+            let batches = state
+                .handler
+                .clone()
+                .transaction_by_hash(param(params, 0)?)
+                .await
+                .context("lookup transaction")?;
+            Ok(batches_to_json_rows(&batches)?
+                .into_iter()
+                .next()
+                .map(|tx| transaction_object(tx).into())
+                .unwrap_or(Value::Null))
+        }
+        "eth_getTransactionReceipt" => {
+            let data = state
+                .handler
+                .clone()
+                .receipt_by_hash(param(params, 0)?)
+                .await
+                .context("lookup receipt")?;
+            let logs = batches_to_json_rows(&data.logs)?;
+            Ok(batches_to_json_rows(&data.transactions)?
+                .into_iter()
+                .next()
+                .map(|tx| receipt_object(tx, logs).into())
+                .unwrap_or(Value::Null))
+        }
+        "eth_getBlockReceipts" => {
+            let block: Value = param(params, 0)?;
+            let block_number = match block.as_str() {
+                // A block hash instead of a number or a tag
+                Some(hash) if hash.len() == 66 => {
+                    let hash = serde_json::from_value(block.clone()).map_err(|e| {
+                        RpcError::new(INVALID_PARAMS, format!("invalid block hash: {}", e))
+                    })?;
+                    block_number_by_hash(state, hash).await?
+                }
+                _ => block_number_param(state, block).await?,
+            };
+            match block_number {
+                Some(block_number) => block_receipts(state, block_number).await,
+                None => Ok(Value::Null),
+            }
+        }
+        "eth_getLogs" => logs(state, limits, param(params, 0)?).await,
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("method {} is not supported", method),
+        )),
+    }
+}
+
+fn param<T: DeserializeOwned>(params: &[Value], idx: usize) -> Result<T, RpcError> {
+    optional_param(params, idx)?
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing param {}", idx)))
+}
+
+fn optional_param<T: DeserializeOwned>(
+    params: &[Value],
+    idx: usize,
+) -> Result<Option<T>, RpcError> {
+    match params.get(idx) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid param {}: {}", idx, e))),
+    }
+}
+
+// Returns the number of the given block tag or hex number,
+// tags that point to the tip are resolved to the archive height.
+async fn block_number_param(state: &ServerState, block: Value) -> Result<Option<u64>, RpcError> {
+    let block = match block.as_str() {
+        Some(block) => block,
+        None => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "block number must be a hex string or a tag",
+            ))
+        }
+    };
+
+    match block {
+        "earliest" => Ok(Some(0)),
+        "latest" | "safe" | "finalized" | "pending" => Ok(state
+            .handler
+            .archive_height()
+            .await
+            .context("get archive height")?),
+        _ => parse_quantity(block).map(Some),
+    }
+}
+
+fn parse_quantity(value: &str) -> Result<u64, RpcError> {
+    value
+        .strip_prefix("0x")
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("invalid block number: {}", value)))
+}
+
+async fn block_number_by_hash(state: &ServerState, hash: Hash) -> Result<Option<u64>, RpcError> {
+    let batches = state
+        .handler
+        .clone()
+        .block_by_hash(hash)
+        .await
+        .context("lookup block")?;
+
+    Ok(batches_to_json_rows(&batches)?
+        .into_iter()
+        .next()
+        .and_then(|block| block["number"].as_u64()))
+}
+
+async fn block(state: &ServerState, block_number: u64, full: bool) -> Result<Value, RpcError> {
+    let data = state
+        .handler
+        .clone()
+        .block_with_transactions(block_number)
+        .await
+        .context("lookup block")?;
+
+    let header = match batches_to_json_rows(&data.blocks)?.into_iter().next() {
+        Some(header) => header,
+        None => return Ok(Value::Null),
+    };
+
+    let transactions = batches_to_json_rows(&data.transactions)?
+        .into_iter()
+        .map(|tx| {
+            if full {
+                transaction_object(tx).into()
+            } else {
+                tx["hash"].clone()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut block = block_object(header);
+    block.insert("transactions".to_owned(), transactions.into());
+
+    Ok(block.into())
+}
+
+async fn block_receipts(state: &ServerState, block_number: u64) -> Result<Value, RpcError> {
+    let height = state
+        .handler
+        .archive_height()
+        .await
+        .context("get archive height")?;
+    if height.map_or(true, |height| block_number > height) {
+        return Ok(Value::Null);
+    }
+
+    let data = state
+        .handler
+        .clone()
+        .block_receipts(block_number)
+        .await
+        .context("lookup block receipts")?;
+
+    let mut logs = batches_to_json_rows(&data.logs)?;
+
+    let receipts = batches_to_json_rows(&data.transactions)?
+        .into_iter()
+        .map(|tx| {
+            let tx_index = tx["transaction_index"].clone();
+            let (tx_logs, rest): (Vec<_>, Vec<_>) = logs
+                .drain(..)
+                .partition(|log| log["transaction_index"] == tx_index);
+            logs = rest;
+            receipt_object(tx, tx_logs).into()
+        })
+        .collect::<Vec<Value>>();
+
+    Ok(receipts.into())
+}
+
+async fn logs(
+    state: &ServerState,
+    limits: QueryLimits,
+    filter: LogFilter,
+) -> Result<Value, RpcError> {
+    if filter.topics.len() > 4 {
+        return Err(RpcError::new(INVALID_PARAMS, "too many topics"));
+    }
+
+    let height = match state
+        .handler
+        .archive_height()
+        .await
+        .context("get archive height")?
+    {
+        Some(height) => height,
+        None => return Ok(Value::Array(Vec::new())),
+    };
+
+    let (from_block, to_block) = match filter.block_hash {
+        Some(hash) => match block_number_by_hash(state, hash).await? {
+            Some(block_number) => (block_number, block_number),
+            None => return Ok(Value::Array(Vec::new())),
+        },
+        None => {
+            let latest = Value::String("latest".to_owned());
+            let from_block = block_number_param(state, filter.from_block.unwrap_or(latest.clone()))
+                .await?
+                .unwrap_or(0);
+            let to_block = block_number_param(state, filter.to_block.unwrap_or(latest))
+                .await?
+                .unwrap_or(0);
+            (from_block, to_block.min(height))
+        }
+    };
+
+    if from_block > to_block {
+        return Ok(Value::Array(Vec::new()));
+    }
+
+    let query = Query {
+        from_block,
+        to_block: Some(to_block + 1),
+        logs: vec![LogSelection {
+            address: filter.address.map(OneOrMany::into_vec).unwrap_or_default(),
+            topics: filter
+                .topics
+                .into_iter()
+                .map(|topic| topic.map(OneOrMany::into_vec).unwrap_or_default())
+                .collect(),
+        }],
+        transactions: Vec::new(),
+        include_all_blocks: false,
+        field_selection: FieldSelection {
+            log: schema::log()
+                .fields
+                .iter()
+                .map(|field| field.name.clone())
+                .collect(),
+            ..Default::default()
+        },
+    };
+
+    let num_folders = state
+        .handler
+        .count_folders(&query)
+        .await
+        .context("count folders in query range")?;
+    let cost = QueryCost::estimate(state.admission.cfg(), &query, num_folders);
+    let _permit = state
+        .admission
+        .admit(cost)
+        .await
+        .map_err(|_| RpcError::new(LIMIT_EXCEEDED, "too many queries are running"))?;
+
+    let mut rx = state
+        .handler
+        .clone()
+        .handle(query, limits)
+        .context("start running query")?;
+
+    let mut logs = Vec::new();
+    let mut next_block = from_block;
+    while let Some(res) = rx.recv().await {
+        let query_result = res.context("execute query")?;
+        logs.extend(
+            batches_to_json_rows(&query_result.data.logs)?
+                .into_iter()
+                .map(|log| Value::from(log_object(log))),
+        );
+        next_block = query_result.next_block;
+    }
+
+    // The query stopped early because of the time or size limit.
+    if next_block <= to_block {
+        return Err(RpcError::new(
+            LIMIT_EXCEEDED,
+            format!(
+                "query exceeds the limits of the server, blocks up to {} could be returned",
+                next_block
+            ),
+        ));
+    }
+
+    Ok(logs.into())
+}
+
+fn quantity(value: u64) -> Value {
+    Value::String(format!("{:#x}", value))
+}
+
+// Removes the leading zeros of a hex encoded quantity.
+fn trim_quantity(value: &str) -> String {
+    let digits = value
+        .strip_prefix("0x")
+        .unwrap_or(value)
+        .trim_start_matches('0');
+
+    if digits.is_empty() {
+        "0x0".to_owned()
+    } else {
+        format!("0x{}", digits)
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+// Converts a json row of the query endpoint into the json-rpc encoding.
+//
+// Integer columns and binary quantity columns are encoded as hex quantities,
+// the other binary columns are already hex encoded.
+fn rpc_object(row: Value, fields: Option<&[&str]>, quantities: &[&str]) -> Map<String, Value> {
+    let mut out = Map::new();
+
+    let row = match row {
+        Value::Object(row) => row,
+        _ => return out,
+    };
+
+    for (name, value) in row {
+        if fields.map_or(false, |fields| !fields.contains(&name.as_str())) {
+            continue;
+        }
+
+        let value = match value {
+            Value::Null if !NULLABLE_FIELDS.contains(&name.as_str()) => continue,
+            Value::Number(n) => n.as_u64().map(quantity).unwrap_or(Value::Number(n)),
+            Value::String(s) if quantities.contains(&name.as_str()) => {
+                Value::String(trim_quantity(&s))
+            }
+            value => value,
+        };
+
+        out.insert(camel_case(&name), value);
+    }
+
+    out
+}
+
+fn block_object(row: Value) -> Map<String, Value> {
+    let mut block = rpc_object(row, None, BLOCK_QUANTITIES);
+
+    // Uncle hashes are stored concatenated in a single column.
+    let uncles = match block.remove("uncles") {
+        Some(Value::String(uncles)) => uncles
+            .strip_prefix("0x")
+            .unwrap_or_default()
+            .as_bytes()
+            .chunks(64)
+            .map(|hash| Value::String(format!("0x{}", std::str::from_utf8(hash).unwrap())))
+            .collect(),
+        _ => Vec::new(),
+    };
+    block.insert("uncles".to_owned(), uncles.into());
+
+    block
+}
+
+fn transaction_object(row: Value) -> Map<String, Value> {
+    rpc_object(row, Some(TRANSACTION_FIELDS), TRANSACTION_QUANTITIES)
+}
+
+fn receipt_object(row: Value, logs: Vec<Value>) -> Map<String, Value> {
+    let mut receipt = rpc_object(row, None, TRANSACTION_QUANTITIES);
+
+    if let Some(hash) = receipt.remove("hash") {
+        receipt.insert("transactionHash".to_owned(), hash);
+    }
+    receipt.insert(
+        "logs".to_owned(),
+        logs.into_iter()
+            .map(|log| Value::from(log_object(log)))
+            .collect::<Vec<_>>()
+            .into(),
+    );
+
+    receipt
+}
+
+fn log_object(row: Value) -> Map<String, Value> {
+    let mut log = rpc_object(row, None, &[]);
+
+    let topics = ["topic0", "topic1", "topic2", "topic3"]
+        .iter()
+        .filter_map(|name| log.remove(*name))
+        .collect::<Vec<_>>();
+    log.insert("topics".to_owned(), topics.into());
+
+    log.entry("removed").or_insert(Value::Bool(false));
+
+    log
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Semaphore;
+
+    use crate::{
+        admission::Admission,
+        config::{HttpServerConfig, QueryConfig},
+        tests::{in_mem_fixture, TestState},
+    };
+
+    use super::*;
+
+    const BLOCK_HASH: &str = "0xa917fcc721a5465a484e9be17cda0cc5493933dd3bc70c9adbee192cb419c9d7";
+    const TRANSFER_TOPIC: &str =
+        "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+    // Number of logs in a block of the test data.
+    const NUM_LOGS: usize = 187;
+
+    // Block 0 is in a folder and block 1 is in memory, both have the same test data.
+    async fn server_state() -> (ServerState, TestState) {
+        let test_state = TestState::new(QueryConfig {
+            time_limit_ms: 60_000,
+            max_concurrent_folders: None,
+            metadata_cache_mb: None,
+        });
+        test_state.flush(in_mem_fixture(0)).await;
+        test_state.set_in_mem(in_mem_fixture(1));
+
+        let cfg: HttpServerConfig = serde_json::from_value(json!({
+            "addr": "127.0.0.1:0",
+            "response_size_limit_mb": 100,
+        }))
+        .unwrap();
+
+        let state = ServerState {
+            handler: test_state.handler.clone(),
+            admission: Arc::new(Admission::new(cfg.admission)),
+            subscriptions: Arc::new(Semaphore::new(1)),
+            api_keys: None,
+            cfg,
+        };
+
+        (state, test_state)
+    }
+
+    async fn call_ok(state: &ServerState, method: &str, params: Value) -> Value {
+        let limits = QueryLimits::new(usize::MAX);
+        match call(state, limits, method, params.as_array().unwrap()).await {
+            Ok(result) => result,
+            Err(e) => panic!("{} failed with {}: {}", method, e.code, e.message),
+        }
+    }
+
+    async fn call_err(
+        state: &ServerState,
+        limits: QueryLimits,
+        method: &str,
+        params: Value,
+    ) -> i64 {
+        match call(state, limits, method, params.as_array().unwrap()).await {
+            Ok(result) => panic!("{} returned {}", method, result),
+            Err(e) => e.code,
+        }
+    }
+
+    async fn block_number(state: &ServerState, block: Value) -> Value {
+        let block = call_ok(state, "eth_getBlockByNumber", json!([block, false])).await;
+        block["number"].clone()
+    }
+
+    async fn num_logs(state: &ServerState, filter: Value) -> usize {
+        call_ok(state, "eth_getLogs", json!([filter]))
+            .await
+            .as_array()
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block_tags() {
+        let (state, _test_state) = server_state().await;
+
+        assert_eq!(call_ok(&state, "eth_blockNumber", json!([])).await, "0x1");
+
+        assert_eq!(block_number(&state, json!("latest")).await, "0x1");
+        assert_eq!(block_number(&state, json!("finalized")).await, "0x1");
+        assert_eq!(block_number(&state, json!("earliest")).await, "0x0");
+        assert_eq!(block_number(&state, json!("0x1")).await, "0x1");
+        assert_eq!(block_number(&state, json!("0x0")).await, "0x0");
+        assert_eq!(block_number(&state, json!("0x2")).await, Value::Null);
+
+        let limits = QueryLimits::new(usize::MAX);
+        for block in [json!("latest1"), json!("12"), json!(12)] {
+            assert_eq!(
+                call_err(&state, limits, "eth_getBlockByNumber", json!([block])).await,
+                INVALID_PARAMS
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_logs() {
+        let (state, _test_state) = server_state().await;
+
+        // Defaults to the latest block.
+        assert_eq!(num_logs(&state, json!({})).await, NUM_LOGS);
+        assert_eq!(
+            num_logs(
+                &state,
+                json!({"fromBlock": "earliest", "toBlock": "latest"})
+            )
+            .await,
+            NUM_LOGS * 2
+        );
+        assert_eq!(
+            num_logs(&state, json!({"fromBlock": "0x0", "toBlock": "0x0"})).await,
+            NUM_LOGS
+        );
+        // The range is clipped to the archive height.
+        assert_eq!(
+            num_logs(&state, json!({"fromBlock": "0x1", "toBlock": "0x10"})).await,
+            NUM_LOGS
+        );
+        assert_eq!(
+            num_logs(&state, json!({"fromBlock": "0x1", "toBlock": "0x0"})).await,
+            0
+        );
+        assert_eq!(
+            num_logs(
+                &state,
+                json!({
+                    "fromBlock": "0x0",
+                    "address": "0x1f573d6fb3f13d689ff844b4ce37794d79a7ff1c",
+                    "topics": [TRANSFER_TOPIC],
+                })
+            )
+            .await,
+            4
+        );
+        assert_eq!(
+            num_logs(
+                &state,
+                json!({"fromBlock": "0x0", "topics": [[TRANSFER_TOPIC], null]})
+            )
+            .await,
+            84 * 2
+        );
+
+        // The hash is resolved to the block in the folder.
+        let logs = call_ok(&state, "eth_getLogs", json!([{"blockHash": BLOCK_HASH}])).await;
+        let logs = logs.as_array().unwrap();
+        assert_eq!(logs.len(), NUM_LOGS);
+        assert!(logs.iter().all(|log| log["blockNumber"] == "0x0"));
+        assert_eq!(
+            num_logs(
+                &state,
+                json!({"blockHash": format!("0x{}", "77".repeat(32))})
+            )
+            .await,
+            0
+        );
+
+        assert_eq!(
+            call_err(
+                &state,
+                QueryLimits::new(1),
+                "eth_getLogs",
+                json!([{"fromBlock": "0x0"}])
+            )
+            .await,
+            LIMIT_EXCEEDED
+        );
+        assert_eq!(
+            call_err(
+                &state,
+                QueryLimits::new(usize::MAX),
+                "eth_getLogs",
+                json!([{"topics": [null, null, null, null, null]}])
+            )
+            .await,
+            INVALID_PARAMS
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_block_receipts() {
+        let (state, _test_state) = server_state().await;
+
+        for block in [json!("0x0"), json!(BLOCK_HASH), json!("latest")] {
+            let receipts = call_ok(&state, "eth_getBlockReceipts", json!([block])).await;
+            let receipts = receipts.as_array().unwrap();
+            assert_eq!(receipts.len(), 204);
+
+            let mut num_logs = 0;
+            for receipt in receipts {
+                let logs = receipt["logs"].as_array().unwrap();
+                assert!(logs
+                    .iter()
+                    .all(|log| log["transactionIndex"] == receipt["transactionIndex"]));
+                num_logs += logs.len();
+            }
+            assert_eq!(num_logs, NUM_LOGS);
+
+            assert_eq!(receipts[1]["transactionIndex"], "0x1");
+            assert_eq!(
+                receipts[1]["transactionHash"],
+                "0x4594fadbfa1b5ec0f3a0a13dd1d0ab42d176efd91ef14f6fcb84e9d06b02a159"
+            );
+            assert_eq!(receipts[1]["logs"].as_array().unwrap().len(), 7);
+            assert!(receipts[2]["logs"].as_array().unwrap().is_empty());
+        }
+
+        assert_eq!(
+            call_ok(&state, "eth_getBlockReceipts", json!(["0x2"])).await,
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_trim_quantity() {
+        assert_eq!(trim_quantity("0x00"), "0x0");
+        assert_eq!(trim_quantity("0x"), "0x0");
+        assert_eq!(trim_quantity("0x01a0"), "0x1a0");
+    }
+
+    #[test]
+    fn test_log_object() {
+        let log = log_object(json!({
+            "removed": null,
+            "log_index": 3,
+            "block_number": 16,
+            "address": "0x3883f5e181fccaf8410fa61e12b59bad963fb645",
+            "topic0": "0x01",
+            "topic1": "0x02",
+            "topic2": null,
+            "topic3": null,
+        }));
+
+        assert_eq!(
+            Value::from(log),
+            json!({
+                "removed": false,
+                "logIndex": "0x3",
+                "blockNumber": "0x10",
+                "address": "0x3883f5e181fccaf8410fa61e12b59bad963fb645",
+                "topics": ["0x01", "0x02"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_block_object() {
+        let block = block_object(json!({
+            "number": 1,
+            "gas_used": "0x0000",
+            "base_fee_per_gas": null,
+            "uncles": format!("0x{}{}", "aa".repeat(32), "bb".repeat(32)),
+        }));
+
+        assert_eq!(
+            Value::from(block),
+            json!({
+                "number": "0x1",
+                "gasUsed": "0x0",
+                "uncles": [format!("0x{}", "aa".repeat(32)), format!("0x{}", "bb".repeat(32))],
+            })
+        );
+    }
+}
@@ -1,20 +1,39 @@
-use std::{cmp, sync::Arc};
+use std::{
+    cmp,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::{
-    build_parquet_idx::build_parquet_indices,
+    admission::Admission,
+    api_keys::ApiKeys,
+    build_parquet_idx::{build_address_index, build_hash_index, build_parquet_indices},
     config::{Config, ParquetConfig},
     db::{BlockRange, Db},
+    filter_tools::{rebuild_filters, report_false_positives},
+    flight,
+    metadata_cache::MetadataCache,
+    metrics::Metrics,
     query::Handler,
     schema::data_to_batches,
     server,
-    state::{InMemory, State},
+    state::{InMemory, IngestEvent, State, WriteStatus},
     validate_parquet::validate_parquet_folder_data,
-    write_parquet::write_folder,
-    Args,
+    write_parquet::{sync_dir, write_folder, MEGABYTE},
+    Args, Command,
 };
 use anyhow::{Context, Result};
 use arc_swap::ArcSwap;
-use skar_ingest::Ingest;
+use skar_format::Hash;
+use skar_ingest::{BatchData, Ingest};
+use tokio::{sync::broadcast, task::JoinHandle, time::Instant};
+
+const DEFAULT_METADATA_CACHE_MB: usize = 64;
+// Subscriptions that fall behind more than this many events catch up by querying the data.
+const INGEST_EVENTS_CAPACITY: usize = 128;
+// Usage of api keys since the last write is lost if the process stops.
+const API_KEY_USAGE_WRITE_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct SkarRunner;
 
@@ -29,21 +48,70 @@ impl SkarRunner {
             .await
             .context("create db directory if not exists")?;
 
-        let db = Db::new(&cfg.db.path).context("open db")?;
+        let db = Db::new(&cfg.db).context("open db")?;
         let db = Arc::new(db);
 
+        match args.command {
+            Some(Command::FilterStats { num_probes }) => {
+                return report_false_positives(&db, &cfg.parquet.path, num_probes)
+                    .await
+                    .context("report false positives");
+            }
+            Some(Command::RebuildFilters) => {
+                return rebuild_filters(&db, &cfg.parquet)
+                    .await
+                    .context("rebuild filters");
+            }
+            None => (),
+        }
+
+        backfill_hash_index(&db, &cfg.parquet.path)
+            .await
+            .context("backfill hash index")?;
+
+        let address_index = cfg.db.address_index.unwrap_or(false);
+
+        if address_index {
+            backfill_address_index(&db, &cfg.parquet.path)
+                .await
+                .context("backfill address index")?;
+        }
+
         let db_next_block_num = db
             .next_block_num()
             .await
             .context("get next block num from db")?;
 
+        let metrics = Metrics::new().context("create metrics")?;
+        metrics.folders.set(
+            db.count_folders(BlockRange(0, u64::MAX))
+                .context("count folders")? as i64,
+        );
+        let metrics = Arc::new(metrics);
+
         let mut ingest_cfg = cfg.ingest;
         ingest_cfg.inner.from_block = ingest_cfg.inner.from_block.max(db_next_block_num);
-        let ingest = Ingest::spawn(ingest_cfg);
+        let ingest = Ingest::spawn(ingest_cfg, metrics.ingest.clone());
+
+        let metadata_cache = MetadataCache::new(
+            cfg.query
+                .metadata_cache_mb
+                .unwrap_or(DEFAULT_METADATA_CACHE_MB)
+                * MEGABYTE,
+            &metrics,
+        );
 
         let state = State {
             db: db.clone(),
             in_mem: ArcSwap::new(InMemory::default().into()),
+            metadata_cache: Arc::new(metadata_cache),
+            events: broadcast::channel(INGEST_EVENTS_CAPACITY).0,
+            metrics,
+            ingest: ingest.status(),
+            write_status: Mutex::new(WriteStatus {
+                last_append: std::time::Instant::now(),
+                last_flush: None,
+            }),
         };
         let state = Arc::new(state);
 
@@ -53,7 +121,10 @@ impl SkarRunner {
         let write = Write {
             state: state.clone(),
             ingest,
-            parquet_config: cfg.parquet,
+            parquet_config: Arc::new(cfg.parquet),
+            address_index,
+            unflushed_since: None,
+            last_block: None,
         };
 
         tokio::task::spawn(async move {
@@ -62,77 +133,464 @@ impl SkarRunner {
             }
         });
 
-        server::run(cfg.http_server, handler)
+        // Queries from the http and flight servers share the same limits.
+        let admission = Arc::new(Admission::new(cfg.http_server.admission));
+
+        let api_keys = ApiKeys::load(&cfg.http_server, db)
+            .context("load api keys")?
+            .map(Arc::new);
+
+        if let Some(api_keys) = api_keys.clone() {
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(API_KEY_USAGE_WRITE_INTERVAL).await;
+
+                    if let Err(e) = api_keys.persist_usage().await {
+                        log::error!("failed to write api key usage: {:?}", e);
+                    }
+                }
+            });
+        }
+
+        let http_server = server::run(
+            cfg.http_server,
+            handler.clone(),
+            admission.clone(),
+            api_keys.clone(),
+        );
+
+        match cfg.flight_server {
+            Some(flight_cfg) => {
+                let flight_server = flight::run(flight_cfg, handler, admission, api_keys);
+
+                tokio::try_join!(
+                    async { http_server.await.context("run http server") },
+                    async { flight_server.await.context("run flight server") },
+                )
+                .map(|_| ())
+            }
+            None => http_server.await.context("run http server"),
+        }
+    }
+}
+
+/// Fills the hash lookup tables for the folders that were written before the tables existed.
+async fn backfill_hash_index(db: &Db, parquet_path: &Path) -> Result<()> {
+    let from_block = db
+        .hash_index_next_block()
+        .await
+        .context("get hash index next block")?;
+    let to_block = db
+        .next_block_num()
+        .await
+        .context("get next block num from db")?;
+
+    if from_block >= to_block {
+        return Ok(());
+    }
+
+    log::info!(
+        "building hash index for blocks {} to {}",
+        from_block,
+        to_block
+    );
+
+    let folder_indices = match db
+        .iterate_folder_indices(BlockRange(from_block, to_block))
+        .context("start folder index iterator")?
+    {
+        Some(folder_indices) => folder_indices,
+        None => return Ok(()),
+    };
+
+    for folder_index in folder_indices {
+        let block_range = folder_index.context("read folder index")?.block_range;
+
+        let mut path = parquet_path.to_owned();
+        path.push(format!("{}-{}", block_range.0, block_range.1));
+
+        let hash_index = build_hash_index(&path).context("build hash index")?;
+
+        db.insert_hash_index(block_range, hash_index)
             .await
-            .context("run http server")
+            .context("insert hash index to db")?;
     }
+
+    log::info!("finished building hash index");
+
+    Ok(())
+}
+
+/// Fills the address index tables for the folders that were written while the address index was disabled.
+async fn backfill_address_index(db: &Db, parquet_path: &Path) -> Result<()> {
+    let from_block = db
+        .address_index_next_block()
+        .context("get address index next block")?;
+    let to_block = db
+        .next_block_num()
+        .await
+        .context("get next block num from db")?;
+
+    if from_block >= to_block {
+        return Ok(());
+    }
+
+    log::info!(
+        "building address index for blocks {} to {}",
+        from_block,
+        to_block
+    );
+
+    let folder_indices = match db
+        .iterate_folder_indices(BlockRange(from_block, to_block))
+        .context("start folder index iterator")?
+    {
+        Some(folder_indices) => folder_indices,
+        None => return Ok(()),
+    };
+
+    for folder_index in folder_indices {
+        let block_range = folder_index.context("read folder index")?.block_range;
+
+        let mut path = parquet_path.to_owned();
+        path.push(format!("{}-{}", block_range.0, block_range.1));
+
+        let address_index = build_address_index(&path).context("build address index")?;
+
+        db.insert_address_index(block_range, address_index)
+            .await
+            .context("insert address index to db")?;
+    }
+
+    log::info!("finished building address index");
+
+    Ok(())
 }
 
 struct Write {
     state: Arc<State>,
     ingest: Ingest,
-    parquet_config: ParquetConfig,
+    parquet_config: Arc<ParquetConfig>,
+    address_index: bool,
+    /// When the oldest in memory data that isn't being flushed was ingested.
+    unflushed_since: Option<Instant>,
+    /// Number and hash of the last ingested block.
+    last_block: Option<(u64, Hash)>,
 }
 
 impl Write {
     async fn ingest(mut self) -> Result<()> {
-        while let Ok(data) = self.ingest.recv().await {
-            let mut in_mem = InMemory::clone(&self.state.in_mem.load());
-
-            if in_mem.blocks.num_rows >= self.parquet_config.blocks.max_file_size
-                || in_mem.transactions.num_rows >= self.parquet_config.transactions.max_file_size
-                || in_mem.logs.num_rows >= self.parquet_config.logs.max_file_size
-            {
-                let to_block = in_mem.to_block;
-                let from_block = in_mem.from_block;
-                let mut temp_path = self.parquet_config.path.clone();
-                temp_path.push(format!("{}-{}temp", from_block, to_block,));
-
-                tokio::fs::create_dir_all(&temp_path)
-                    .await
-                    .context("create parquet directory")?;
+        // Writes the frozen in memory data to a folder and returns it when finished
+        let mut flush: Option<JoinHandle<Result<Arc<InMemory>>>> = None;
 
-                write_folder(&self.state.in_mem.load(), &temp_path, &self.parquet_config)
-                    .await
-                    .context("write temp parquet folder")?;
+        loop {
+            let age_deadline = match (flush.is_none(), self.unflushed_since) {
+                (true, Some(since)) => self
+                    .parquet_config
+                    .max_unflushed_age_secs
+                    .map(|secs| since + Duration::from_secs(secs)),
+                _ => None,
+            };
 
-                validate_parquet_folder_data(&temp_path)
-                    .context("validate parquet folder after writing")?;
+            tokio::select! {
+                res = async { flush.as_mut().unwrap().await }, if flush.is_some() => {
+                    flush = None;
 
-                let mut final_path = self.parquet_config.path.clone();
-                final_path.push(format!("{}-{}", from_block, to_block,));
+                    let frozen = res.context("join flush task")?.context("flush folder")?;
 
-                tokio::fs::remove_dir_all(&final_path).await.ok();
+                    self.state.write_status.lock().unwrap().last_flush =
+                        Some((std::time::Instant::now(), frozen.to_block));
 
-                tokio::fs::rename(&temp_path, &final_path)
-                    .await
-                    .context("rename parquet dir")?;
+                    // New data might have been appended while the folder was being written,
+                    // so only the frozen part is dropped from memory.
+                    let in_mem = self.state.in_mem.load().without_prefix(&frozen);
+                    self.state.in_mem.store(in_mem.into());
 
-                let (folder_index, rg_index) =
-                    build_parquet_indices(&final_path).context("build parquet indices")?;
-                assert_eq!(BlockRange(from_block, to_block), folder_index.block_range);
+                    // The thresholds might have been reached while the folder was being written.
+                    if self.should_flush() {
+                        flush = Some(self.start_flush());
+                    }
+                }
+                _ = tokio::time::sleep_until(age_deadline.unwrap_or_else(Instant::now)), if age_deadline.is_some() => {
+                    flush = Some(self.start_flush());
+                }
+                data = self.ingest.recv() => {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(_) => break,
+                    };
 
-                self.state
-                    .db
-                    .insert_folder_index(folder_index, rg_index)
-                    .await
-                    .context("insert parquet idx to db")?;
+                    self.append(data).context("append data to memory")?;
 
-                in_mem = InMemory::default();
+                    if flush.is_none() && self.should_flush() {
+                        flush = Some(self.start_flush());
+                    }
+                }
             }
+        }
 
-            in_mem.from_block = cmp::min(data.from_block, in_mem.from_block);
-            in_mem.to_block = cmp::max(data.to_block, in_mem.to_block);
+        if let Some(flush) = flush {
+            flush
+                .await
+                .context("join flush task")?
+                .context("flush folder")?;
+        }
 
-            let batches = data_to_batches(data);
+        Ok(())
+    }
 
-            in_mem.blocks.extend(batches.blocks.into());
-            in_mem.transactions.extend(batches.transactions.into());
-            in_mem.logs.extend(batches.logs.into());
+    fn append(&mut self, data: BatchData) -> Result<()> {
+        check_reorg(&mut self.last_block, &data, &self.state.events);
 
-            self.state.in_mem.store(in_mem.into());
-        }
+        let mut in_mem = InMemory::clone(&self.state.in_mem.load());
+
+        in_mem.from_block = cmp::min(data.from_block, in_mem.from_block);
+        in_mem.to_block = cmp::max(data.to_block, in_mem.to_block);
+
+        let batches = data_to_batches(data);
+
+        in_mem
+            .blocks
+            .extend(batches.blocks.into(), &self.parquet_config.bloom_filters)
+            .context("append blocks")?;
+        in_mem
+            .transactions
+            .extend(
+                batches.transactions.into(),
+                &self.parquet_config.bloom_filters,
+            )
+            .context("append transactions")?;
+        in_mem
+            .logs
+            .extend(batches.logs.into(), &self.parquet_config.bloom_filters)
+            .context("append logs")?;
+
+        let to_block = in_mem.to_block;
+        self.state.in_mem.store(in_mem.into());
+
+        self.state.metrics.set_latest_block(to_block - 1);
+        self.state.write_status.lock().unwrap().last_append = std::time::Instant::now();
+
+        // There might be no subscribers.
+        self.state
+            .events
+            .send(IngestEvent::NewData { to_block })
+            .ok();
+
+        self.unflushed_since.get_or_insert_with(Instant::now);
 
         Ok(())
     }
+
+    fn should_flush(&self) -> bool {
+        let in_mem = self.state.in_mem.load();
+
+        if in_mem.from_block >= in_mem.to_block {
+            return false;
+        }
+
+        let max_bytes = self
+            .parquet_config
+            .max_in_memory_mb
+            .map(|mb| mb * MEGABYTE)
+            .unwrap_or(usize::MAX);
+
+        in_mem.blocks.num_rows >= self.parquet_config.blocks.max_file_size
+            || in_mem.transactions.num_rows >= self.parquet_config.transactions.max_file_size
+            || in_mem.logs.num_rows >= self.parquet_config.logs.max_file_size
+            || in_mem.num_bytes() >= max_bytes
+    }
+
+    fn start_flush(&mut self) -> JoinHandle<Result<Arc<InMemory>>> {
+        // Queries keep reading the frozen data from memory until it is
+        // indexed in the database, so there is no gap in the served data.
+        let frozen = self.state.in_mem.load_full();
+        self.unflushed_since = None;
+
+        log::info!(
+            "writing blocks {} to {} to a new folder, {} MB in memory",
+            frozen.from_block,
+            frozen.to_block,
+            frozen.num_bytes() / MEGABYTE
+        );
+
+        let flush = flush_folder(
+            frozen,
+            self.state.db.clone(),
+            self.parquet_config.clone(),
+            self.address_index,
+        );
+        let metrics = self.state.metrics.clone();
+
+        tokio::spawn(async move {
+            let timer = metrics.flush_duration.start_timer();
+            let res = flush.await;
+
+            if res.is_ok() {
+                timer.observe_duration();
+                metrics.folders.inc();
+            } else {
+                timer.stop_and_discard();
+            }
+
+            res
+        })
+    }
+}
+
+/// Writes the given data to a new parquet folder and inserts its indices into the database.
+pub(crate) async fn flush_folder(
+    in_mem: Arc<InMemory>,
+    db: Arc<Db>,
+    parquet_config: Arc<ParquetConfig>,
+    address_index: bool,
+) -> Result<Arc<InMemory>> {
+    let to_block = in_mem.to_block;
+    let from_block = in_mem.from_block;
+    let mut temp_path = parquet_config.path.clone();
+    temp_path.push(format!("{}-{}temp", from_block, to_block,));
+
+    tokio::fs::create_dir_all(&temp_path)
+        .await
+        .context("create parquet directory")?;
+
+    write_folder(&in_mem, &temp_path, &parquet_config)
+        .await
+        .context("write temp parquet folder")?;
+
+    tokio::task::block_in_place(|| validate_parquet_folder_data(&temp_path))
+        .context("validate parquet folder after writing")?;
+
+    let mut final_path = parquet_config.path.clone();
+    final_path.push(format!("{}-{}", from_block, to_block,));
+
+    tokio::fs::remove_dir_all(&final_path).await.ok();
+
+    tokio::fs::rename(&temp_path, &final_path)
+        .await
+        .context("rename parquet dir")?;
+    tokio::task::block_in_place(|| sync_dir(&parquet_config.path))
+        .context("sync parquet directory")?;
+
+    // Building the indices reads the whole folder, so don't block the other tasks on this thread.
+    let (folder_index, rg_index, hash_index, address_index) =
+        tokio::task::block_in_place(|| -> Result<_> {
+            let (folder_index, rg_index) =
+                build_parquet_indices(&final_path, &parquet_config.bloom_filters)
+                    .context("build parquet indices")?;
+            assert_eq!(BlockRange(from_block, to_block), folder_index.block_range);
+            let hash_index = build_hash_index(&final_path).context("build hash index")?;
+            let address_index = if address_index {
+                Some(build_address_index(&final_path).context("build address index")?)
+            } else {
+                None
+            };
+
+            Ok((folder_index, rg_index, hash_index, address_index))
+        })?;
+
+    db.insert_folder_index(folder_index, rg_index, hash_index, address_index)
+        .await
+        .context("insert parquet idx to db")?;
+
+    Ok(in_mem)
+}
+
+// Notifies the subscribers if a block doesn't build on the previously ingested block.
+//
+// `last_block` is the number and hash of the last ingested block.
+fn check_reorg(
+    last_block: &mut Option<(u64, Hash)>,
+    data: &BatchData,
+    events: &broadcast::Sender<IngestEvent>,
+) {
+    for block in data.blocks.iter() {
+        let number = *block.header.number;
+
+        if let Some((last_number, last_hash)) = last_block {
+            if *last_number + 1 == number && *last_hash != block.header.parent_hash {
+                log::warn!(
+                    "block {} doesn't build on the previously ingested block",
+                    number
+                );
+
+                // There might be no subscribers.
+                events
+                    .send(IngestEvent::Reorg {
+                        block_number: number,
+                        parent_hash: block.header.parent_hash.clone(),
+                        expected_parent_hash: last_hash.clone(),
+                    })
+                    .ok();
+            }
+        }
+
+        *last_block = Some((number, block.header.hash.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skar_format::{Block, Transaction};
+
+    use super::*;
+    use crate::tests::read_json;
+
+    #[test]
+    fn test_check_reorg() {
+        let fixture: Block<Transaction> = read_json("block_data");
+        let block = |number: u64, hash: u8, parent_hash: u8| {
+            let mut block = fixture.clone();
+            block.header.number = number.into();
+            block.header.hash = [hash; 32].into();
+            block.header.parent_hash = [parent_hash; 32].into();
+            block
+        };
+        let data = |blocks| BatchData {
+            blocks,
+            receipts: Vec::new(),
+            from_block: 0,
+            to_block: 0,
+        };
+
+        let (events, mut rx) = broadcast::channel(16);
+        let mut last_block = None;
+
+        // The first block has nothing to build on.
+        check_reorg(&mut last_block, &data(vec![block(10, 1, 9)]), &events);
+        assert!(rx.try_recv().is_err());
+
+        check_reorg(
+            &mut last_block,
+            &data(vec![block(11, 2, 1), block(12, 3, 2)]),
+            &events,
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(last_block, Some((12, Hash::from([3; 32]))));
+
+        // Block 13 builds on a different block 12, and block 14 builds on the new block 13.
+        check_reorg(
+            &mut last_block,
+            &data(vec![block(13, 4, 7), block(14, 5, 4)]),
+            &events,
+        );
+        match rx.try_recv().unwrap() {
+            IngestEvent::Reorg {
+                block_number,
+                parent_hash,
+                expected_parent_hash,
+            } => {
+                assert_eq!(block_number, 13);
+                assert_eq!(parent_hash, Hash::from([7; 32]));
+                assert_eq!(expected_parent_hash, Hash::from([3; 32]));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(rx.try_recv().is_err());
+
+        // Gaps in the block numbers are not checked.
+        check_reorg(&mut last_block, &data(vec![block(20, 6, 0)]), &events);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(last_block, Some((20, Hash::from([6; 32]))));
+    }
 }
@@ -1,23 +1,66 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use anyhow::{Context, Result};
 use arc_swap::ArcSwap;
 use arrow2::array::Array;
 use arrow2::chunk::Chunk;
+use arrow2::compute::aggregate::estimated_bytes_size;
+use serde::Serialize;
+use skar_format::Hash;
+use skar_ingest::IngestStatus;
+use tokio::sync::broadcast;
 
-use crate::db::Db;
+use crate::build_parquet_idx::{
+    build_block_row_group_index, build_log_row_group_index, build_tx_row_group_index,
+};
+use crate::config::BloomFilterConfig;
+use crate::db::{BlockRowGroupIndex, Db, LogRowGroupIndex, TransactionRowGroupIndex};
+use crate::metadata_cache::MetadataCache;
+use crate::metrics::Metrics;
+use crate::write_parquet::concat_chunks;
 
 pub type ArrowChunk = Chunk<Box<dyn Array>>;
 
 pub struct State {
     pub in_mem: ArcSwap<InMemory>,
     pub db: Arc<Db>,
+    pub metadata_cache: Arc<MetadataCache>,
+    /// Notifies live subscriptions about the data that enters memory.
+    pub events: broadcast::Sender<IngestEvent>,
+    pub metrics: Arc<Metrics>,
+    pub ingest: IngestStatus,
+    pub write_status: Mutex<WriteStatus>,
+}
+
+/// Progress of the write task that is reported by `/health` and `/ready`.
+#[derive(Clone, Copy)]
+pub struct WriteStatus {
+    /// When blocks were last appended to memory, or when the write task started.
+    pub last_append: Instant,
+    /// When the last folder was written and the end of its block range (exclusive).
+    pub last_flush: Option<(Instant, u64)>,
+}
+
+#[derive(Clone, Debug)]
+pub enum IngestEvent {
+    /// Blocks up to `to_block` (exclusive) were appended to the in memory data.
+    NewData { to_block: u64 },
+    /// A new block doesn't build on the previously ingested block.
+    ///
+    /// The data isn't rolled back, this only notifies the subscribers.
+    Reorg {
+        block_number: u64,
+        parent_hash: Hash,
+        expected_parent_hash: Hash,
+    },
 }
 
 #[derive(Clone)]
 pub struct InMemory {
-    pub blocks: InMemoryTable,
-    pub transactions: InMemoryTable,
-    pub logs: InMemoryTable,
+    pub blocks: InMemoryTable<BlockRowGroupIndex>,
+    pub transactions: InMemoryTable<TransactionRowGroupIndex>,
+    pub logs: InMemoryTable<LogRowGroupIndex>,
     pub from_block: u64,
     pub to_block: u64,
 }
@@ -34,15 +77,406 @@ impl Default for InMemory {
     }
 }
 
-#[derive(Default, Clone)]
-pub struct InMemoryTable {
-    pub data: Vec<Arc<ArrowChunk>>,
+impl InMemory {
+    /// Estimated size of the in memory data in bytes.
+    pub fn num_bytes(&self) -> usize {
+        self.blocks.num_bytes + self.transactions.num_bytes + self.logs.num_bytes
+    }
+
+    pub fn usage(&self) -> InMemoryUsage {
+        InMemoryUsage {
+            num_bytes: self.num_bytes(),
+            num_blocks: self.blocks.num_rows,
+            num_transactions: self.transactions.num_rows,
+            num_logs: self.logs.num_rows,
+            from_block: self.from_block,
+            to_block: self.to_block,
+        }
+    }
+
+    /// Returns the data that was appended after `prefix` was taken from this state.
+    ///
+    /// Used to drop the data of a folder from memory after it is written to disk.
+    pub fn without_prefix(&self, prefix: &InMemory) -> InMemory {
+        if prefix.to_block >= self.to_block {
+            return InMemory {
+                from_block: prefix.to_block,
+                to_block: prefix.to_block,
+                ..Default::default()
+            };
+        }
+
+        InMemory {
+            blocks: self.blocks.without_prefix(&prefix.blocks),
+            transactions: self.transactions.without_prefix(&prefix.transactions),
+            logs: self.logs.without_prefix(&prefix.logs),
+            from_block: prefix.to_block,
+            to_block: self.to_block,
+        }
+    }
+}
+
+/// Size of the data that is not written to parquet yet.
+#[derive(Serialize)]
+pub struct InMemoryUsage {
+    /// Estimated size of the data in bytes.
+    pub num_bytes: usize,
+    pub num_blocks: usize,
+    pub num_transactions: usize,
+    pub num_logs: usize,
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+/// Chunks with fewer rows than this are coalesced with the chunks that are appended after them.
+const MIN_SEALED_ROWS: usize = 8192;
+/// Maximum number of small chunks that are kept before they are coalesced.
+pub(crate) const MAX_TAIL_CHUNKS: usize = 64;
+
+/// Index of an in memory chunk.
+///
+/// This is the same index that is built for the row groups of the parquet files, so the
+/// chunks can be skipped the same way the row groups are.
+/// The filters are sized with the same config as the filters of the parquet row groups.
+pub trait ChunkIndex {
+    fn build(chunk: &ArrowChunk, cfg: &BloomFilterConfig) -> Self;
+}
+
+impl ChunkIndex for BlockRowGroupIndex {
+    fn build(chunk: &ArrowChunk, _cfg: &BloomFilterConfig) -> Self {
+        build_block_row_group_index(chunk)
+    }
+}
+
+impl ChunkIndex for TransactionRowGroupIndex {
+    fn build(chunk: &ArrowChunk, cfg: &BloomFilterConfig) -> Self {
+        build_tx_row_group_index(chunk, cfg, None)
+    }
+}
+
+impl ChunkIndex for LogRowGroupIndex {
+    fn build(chunk: &ArrowChunk, cfg: &BloomFilterConfig) -> Self {
+        build_log_row_group_index(chunk, cfg, None)
+    }
+}
+
+pub struct IndexedChunk<I> {
+    pub chunk: Arc<ArrowChunk>,
+    pub index: Arc<I>,
+}
+
+impl<I> Clone for IndexedChunk<I> {
+    fn clone(&self) -> Self {
+        Self {
+            chunk: self.chunk.clone(),
+            index: self.index.clone(),
+        }
+    }
+}
+
+/// Append only table of arrow chunks.
+///
+/// Cloning is cheap so a new snapshot can be made for each appended batch. The big chunks are
+/// shared between the snapshots and the small chunks that are appended while following the
+/// chain tip are coalesced into big ones, so queries don't have to scan many tiny chunks.
+///
+/// The big chunks are indexed when they are sealed so queries can skip them.
+pub struct InMemoryTable<I> {
+    /// Coalesced chunks and the chunks that were big enough when they were appended.
+    sealed: Arc<Vec<IndexedChunk<I>>>,
+    /// Small chunks that were appended after the sealed ones.
+    tail: Vec<Arc<ArrowChunk>>,
+    tail_rows: usize,
     pub num_rows: usize,
+    /// Estimated size of the data in bytes.
+    pub num_bytes: usize,
+}
+
+impl<I> Default for InMemoryTable<I> {
+    fn default() -> Self {
+        Self {
+            sealed: Default::default(),
+            tail: Vec::new(),
+            tail_rows: 0,
+            num_rows: 0,
+            num_bytes: 0,
+        }
+    }
+}
+
+impl<I> Clone for InMemoryTable<I> {
+    fn clone(&self) -> Self {
+        Self {
+            sealed: self.sealed.clone(),
+            tail: self.tail.clone(),
+            tail_rows: self.tail_rows,
+            num_rows: self.num_rows,
+            num_bytes: self.num_bytes,
+        }
+    }
+}
+
+impl<I> InMemoryTable<I> {
+    /// Iterates over the chunks in the order they were appended.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<ArrowChunk>> {
+        self.sealed
+            .iter()
+            .map(|sealed| &sealed.chunk)
+            .chain(self.tail.iter())
+    }
+
+    /// Iterates over the chunks in the order they were appended, along with their index.
+    ///
+    /// The index is `None` for the small chunks that are not sealed yet.
+    pub fn iter_indexed(&self) -> impl Iterator<Item = (&Arc<ArrowChunk>, Option<&I>)> {
+        self.sealed
+            .iter()
+            .map(|sealed| (&sealed.chunk, Some(sealed.index.as_ref())))
+            .chain(self.tail.iter().map(|chunk| (chunk, None)))
+    }
+
+    fn without_prefix(&self, prefix: &InMemoryTable<I>) -> InMemoryTable<I> {
+        // Chunks might have been coalesced after the prefix was taken, so the
+        // prefix is skipped by row count instead of by chunk.
+        let mut skip = prefix.num_rows;
+
+        // A sliced chunk keeps the index of the whole chunk. It might not skip
+        // as much as it could but it is still correct.
+        let sealed = self
+            .sealed
+            .iter()
+            .filter_map(|sealed| {
+                let chunk = skip_rows(&sealed.chunk, &mut skip)?;
+                Some(IndexedChunk {
+                    chunk,
+                    index: sealed.index.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let tail = self
+            .tail
+            .iter()
+            .filter_map(|chunk| skip_rows(chunk, &mut skip))
+            .collect::<Vec<_>>();
+
+        InMemoryTable {
+            sealed: Arc::new(sealed),
+            tail_rows: tail.iter().map(|chunk| chunk.len()).sum(),
+            tail,
+            num_rows: self.num_rows - prefix.num_rows,
+            num_bytes: self.num_bytes - prefix.num_bytes,
+        }
+    }
 }
 
-impl InMemoryTable {
-    pub fn extend(&mut self, chunk: Arc<ArrowChunk>) {
+impl<I: ChunkIndex> InMemoryTable<I> {
+    /// Appends the chunk, `cfg` sizes the filters of the chunks that are sealed.
+    pub fn extend(&mut self, chunk: Arc<ArrowChunk>, cfg: &BloomFilterConfig) -> Result<()> {
         self.num_rows += chunk.len();
-        self.data.push(chunk);
+        self.num_bytes += chunk
+            .columns()
+            .iter()
+            .map(|col| estimated_bytes_size(col.as_ref()))
+            .sum::<usize>();
+
+        if chunk.len() >= MIN_SEALED_ROWS {
+            self.seal_tail(cfg).context("seal tail")?;
+            let index = Arc::new(I::build(&chunk, cfg));
+            Arc::make_mut(&mut self.sealed).push(IndexedChunk { chunk, index });
+            return Ok(());
+        }
+
+        self.tail_rows += chunk.len();
+        self.tail.push(chunk);
+
+        if self.tail_rows >= MIN_SEALED_ROWS || self.tail.len() >= MAX_TAIL_CHUNKS {
+            self.seal_tail(cfg).context("seal tail")?;
+        }
+
+        Ok(())
+    }
+
+    // Coalesces the tail into a single sealed chunk. The last sealed chunk is merged into it if it
+    // is small, so the sealed chunks grow until they reach `MIN_SEALED_ROWS`.
+    fn seal_tail(&mut self, cfg: &BloomFilterConfig) -> Result<()> {
+        if self.tail.is_empty() {
+            return Ok(());
+        }
+
+        let sealed = Arc::make_mut(&mut self.sealed);
+
+        let mut chunks = Vec::with_capacity(self.tail.len() + 1);
+        if let Some(last) = sealed.last() {
+            if last.chunk.len() < MIN_SEALED_ROWS {
+                chunks.push(sealed.pop().unwrap().chunk);
+            }
+        }
+        chunks.append(&mut self.tail);
+        self.tail_rows = 0;
+
+        let chunk = if chunks.len() == 1 {
+            chunks.pop().unwrap()
+        } else {
+            Arc::new(concat_chunks(&chunks).context("concat chunks")?)
+        };
+
+        let index = Arc::new(I::build(&chunk, cfg));
+        sealed.push(IndexedChunk { chunk, index });
+
+        Ok(())
+    }
+}
+
+// Skips the first `skip` rows of the chunk and subtracts the skipped rows from `skip`.
+// Returns `None` if the whole chunk is skipped.
+fn skip_rows(chunk: &Arc<ArrowChunk>, skip: &mut usize) -> Option<Arc<ArrowChunk>> {
+    if *skip >= chunk.len() {
+        *skip -= chunk.len();
+        return None;
+    }
+
+    if *skip == 0 {
+        return Some(chunk.clone());
+    }
+
+    let len = chunk.len() - *skip;
+    let cols = chunk
+        .columns()
+        .iter()
+        .map(|col| col.sliced(*skip, len))
+        .collect();
+    *skip = 0;
+
+    Some(Arc::new(ArrowChunk::new(cols)))
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow2::array::UInt64Array;
+
+    use super::*;
+
+    fn chunk_of(value: u64, len: usize) -> Arc<ArrowChunk> {
+        Arc::new(Chunk::new(vec![
+            UInt64Array::from_vec(vec![value; len]).boxed()
+        ]))
+    }
+
+    fn chunk(len: usize) -> Arc<ArrowChunk> {
+        chunk_of(0, len)
+    }
+
+    struct NoIndex;
+
+    impl ChunkIndex for NoIndex {
+        fn build(_chunk: &ArrowChunk, _cfg: &BloomFilterConfig) -> Self {
+            NoIndex
+        }
+    }
+
+    fn values<I>(table: &InMemoryTable<I>) -> Vec<u64> {
+        table
+            .iter()
+            .flat_map(|chunk| {
+                chunk.columns()[0]
+                    .as_any()
+                    .downcast_ref::<UInt64Array>()
+                    .unwrap()
+                    .values_iter()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut table = InMemoryTable::<NoIndex>::default();
+
+        for i in 0..MAX_TAIL_CHUNKS as u64 * 3 {
+            table
+                .extend(chunk_of(i, 1), &BloomFilterConfig::default())
+                .unwrap();
+        }
+
+        assert_eq!(table.sealed.len(), 1);
+        assert!(table.tail.is_empty());
+
+        table
+            .extend(
+                chunk_of(1000, MIN_SEALED_ROWS),
+                &BloomFilterConfig::default(),
+            )
+            .unwrap();
+        table
+            .extend(chunk_of(2000, 2), &BloomFilterConfig::default())
+            .unwrap();
+
+        assert_eq!(table.iter().count(), 3);
+        assert_eq!(table.num_rows, MAX_TAIL_CHUNKS * 3 + MIN_SEALED_ROWS + 2);
+
+        let all = values(&table);
+        assert_eq!(all.len(), table.num_rows);
+        assert_eq!(all[..3], [0, 1, 2]);
+        assert_eq!(all[MAX_TAIL_CHUNKS * 3], 1000);
+        assert_eq!(all[all.len() - 2..], [2000, 2000]);
+
+        let mut prefix = table.clone();
+        prefix.num_rows = MAX_TAIL_CHUNKS * 3 + 1;
+        let remaining = table.without_prefix(&prefix);
+        assert_eq!(remaining.num_rows, MIN_SEALED_ROWS + 1);
+        assert_eq!(
+            remaining
+                .iter_indexed()
+                .filter(|(_, i)| i.is_some())
+                .count(),
+            1
+        );
+
+        let values = values(&remaining);
+        assert_eq!(values.len(), MIN_SEALED_ROWS + 1);
+        assert_eq!(values[0], 1000);
+        assert_eq!(values[values.len() - 1], 2000);
+    }
+
+    #[test]
+    fn test_without_prefix() {
+        let mut in_mem = InMemory::default();
+        in_mem.from_block = 10;
+        in_mem.to_block = 20;
+        in_mem
+            .logs
+            .extend(chunk(3), &BloomFilterConfig::default())
+            .unwrap();
+        in_mem
+            .logs
+            .extend(chunk(4), &BloomFilterConfig::default())
+            .unwrap();
+
+        let frozen = in_mem.clone();
+
+        let remaining = in_mem.without_prefix(&frozen);
+        assert_eq!(remaining.from_block, 20);
+        assert_eq!(remaining.to_block, 20);
+        assert_eq!(remaining.logs.num_rows, 0);
+
+        in_mem.to_block = 25;
+        in_mem
+            .logs
+            .extend(chunk_of(1, 5), &BloomFilterConfig::default())
+            .unwrap();
+        in_mem
+            .blocks
+            .extend(chunk(5), &BloomFilterConfig::default())
+            .unwrap();
+
+        let remaining = in_mem.without_prefix(&frozen);
+        assert_eq!(remaining.from_block, 20);
+        assert_eq!(remaining.to_block, 25);
+        assert_eq!(remaining.logs.num_rows, 5);
+        assert_eq!(values(&remaining.logs), vec![1; 5]);
+        assert_eq!(remaining.blocks.num_rows, 5);
+        assert_eq!(remaining.logs.num_bytes, 5 * 8);
+        assert_eq!(remaining.num_bytes(), 2 * 5 * 8);
     }
 }
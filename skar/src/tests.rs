@@ -1,56 +1,187 @@
-use std::env::temp_dir;
+use std::{
+    env::temp_dir,
+    sync::{Arc, Mutex},
+};
 
+use arc_swap::ArcSwap;
 use serde::de::DeserializeOwned;
-use skar_ingest::BatchData;
+use skar_ingest::{BatchData, Ingest, IngestConfig};
+use tokio::sync::broadcast;
 
 use crate::{
-    config::{ParquetConfig, TableConfig},
+    config::{BloomFilterConfig, DbConfig, ParquetConfig, QueryConfig, TableConfig},
+    db::Db,
+    metadata_cache::MetadataCache,
+    metrics::Metrics,
+    query::Handler,
     schema::data_to_batches,
-    state::{InMemory, InMemoryTable},
+    skar_runner::flush_folder,
+    state::{InMemory, State, WriteStatus},
     validate_parquet::validate_parquet_folder_data,
-    write_parquet::write_folder,
+    write_parquet::{write_folder, MEGABYTE},
 };
 
-fn read_json<T: DeserializeOwned>(name: &str) -> T {
-    let data = std::fs::read_to_string(format!(
+/// Block number of the test data.
+pub(crate) const FIXTURE_BLOCK: u64 = 12911679;
+
+fn read_file(name: &str) -> String {
+    std::fs::read_to_string(format!(
         "{}/test-data/{name}.json",
         env!("CARGO_MANIFEST_DIR")
     ))
-    .unwrap();
+    .unwrap()
+}
 
-    serde_json::from_str(&data).unwrap()
+pub(crate) fn read_json<T: DeserializeOwned>(name: &str) -> T {
+    serde_json::from_str(&read_file(name)).unwrap()
 }
 
-#[tokio::test(flavor = "multi_thread")]
-async fn test_read_write_validate() {
-    let block_data = read_json("block_data");
-    let receipt_data = read_json("receipt_data");
-
-    let data = BatchData {
-        blocks: vec![block_data],
-        receipts: vec![receipt_data],
-        from_block: 12911679,
-        to_block: 12911680,
+/// Test data of block 12911679 with the block number replaced by `block_number`.
+///
+/// The hashes are not changed, so only use a single block number if the test looks up hashes.
+pub(crate) fn batch_data(block_number: u64) -> BatchData {
+    let read = |name| {
+        let data = read_file(name).replace(
+            &format!("\"{:#x}\"", FIXTURE_BLOCK),
+            &format!("\"{:#x}\"", block_number),
+        );
+        serde_json::from_str(&data).unwrap()
+    };
+
+    BatchData {
+        blocks: vec![read("block_data")],
+        receipts: vec![read("receipt_data")],
+        from_block: block_number,
+        to_block: block_number + 1,
+    }
+}
+
+/// In memory data of a single block of test data, see [`batch_data`].
+pub(crate) fn in_mem_fixture(block_number: u64) -> InMemory {
+    let batches = data_to_batches(batch_data(block_number));
+
+    let mut in_mem = InMemory {
+        from_block: block_number,
+        to_block: block_number + 1,
+        ..Default::default()
     };
+    let cfg = BloomFilterConfig::default();
+    in_mem.blocks.extend(batches.blocks.into(), &cfg).unwrap();
+    in_mem
+        .transactions
+        .extend(batches.transactions.into(), &cfg)
+        .unwrap();
+    in_mem.logs.extend(batches.logs.into(), &cfg).unwrap();
+
+    in_mem
+}
 
-    let batches = data_to_batches(data);
+fn temp_path() -> std::path::PathBuf {
+    let mut path = temp_dir();
+    path.push(format!("{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
 
-    let in_mem = InMemory {
-        blocks: InMemoryTable {
-            num_rows: batches.blocks.len(),
-            data: vec![batches.blocks.into()],
+fn parquet_config(path: std::path::PathBuf) -> ParquetConfig {
+    ParquetConfig {
+        path,
+        blocks: TableConfig {
+            max_file_size: 69,
+            max_row_group_size: 69,
         },
-        transactions: InMemoryTable {
-            num_rows: batches.transactions.len(),
-            data: vec![batches.transactions.into()],
+        transactions: TableConfig {
+            max_file_size: 69,
+            max_row_group_size: 69,
         },
-        logs: InMemoryTable {
-            num_rows: batches.logs.len(),
-            data: vec![batches.logs.into()],
+        logs: TableConfig {
+            max_file_size: 69,
+            max_row_group_size: 69,
         },
-        from_block: 12911679,
-        to_block: 12911680,
-    };
+        writer_memory_budget_mb: None,
+        max_in_memory_mb: None,
+        max_unflushed_age_secs: None,
+        bloom_filters: Default::default(),
+    }
+}
+
+/// State over an empty database and parquet directory in a temporary directory.
+///
+/// The ingester doesn't have any rpc endpoints, so nothing is ingested. This needs a multi
+/// thread runtime.
+pub(crate) struct TestState {
+    pub state: Arc<State>,
+    pub handler: Arc<Handler>,
+    pub parquet_config: Arc<ParquetConfig>,
+}
+
+impl TestState {
+    pub fn new(query: QueryConfig) -> Self {
+        let db = Db::new(&DbConfig {
+            path: temp_path(),
+            max_size_gb: None,
+            address_index: None,
+        })
+        .unwrap();
+        let metrics = Metrics::new().unwrap();
+
+        let ingest_cfg: IngestConfig = serde_json::from_value(serde_json::json!({
+            "rpc_client": {
+                "http_req_timeout_millis": 1000,
+                "endpoints": [],
+            },
+            "from_block": 0,
+            "concurrency_limit": 1,
+            "batch_size": 1,
+        }))
+        .unwrap();
+        let ingest = Ingest::spawn(ingest_cfg, metrics.ingest.clone());
+
+        let metadata_cache = MetadataCache::new(MEGABYTE, &metrics);
+
+        let state = Arc::new(State {
+            db: Arc::new(db),
+            in_mem: ArcSwap::new(InMemory::default().into()),
+            metadata_cache: Arc::new(metadata_cache),
+            events: broadcast::channel(16).0,
+            metrics: Arc::new(metrics),
+            ingest: ingest.status(),
+            write_status: Mutex::new(WriteStatus {
+                last_append: std::time::Instant::now(),
+                last_flush: None,
+            }),
+        });
+
+        let parquet_config = parquet_config(temp_path());
+        let handler = Arc::new(Handler::new(query, state.clone(), &parquet_config.path));
+
+        Self {
+            state,
+            handler,
+            parquet_config: Arc::new(parquet_config),
+        }
+    }
+
+    /// Writes the data to a parquet folder and indexes it like the write task does.
+    pub async fn flush(&self, in_mem: InMemory) {
+        flush_folder(
+            Arc::new(in_mem),
+            self.state.db.clone(),
+            self.parquet_config.clone(),
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    pub fn set_in_mem(&self, in_mem: InMemory) {
+        self.state.in_mem.store(in_mem.into());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_write_validate() {
+    let in_mem = in_mem_fixture(FIXTURE_BLOCK);
 
     let mut tmp = temp_dir();
     tmp.push(format!("{}", uuid::Uuid::new_v4()));
@@ -74,6 +205,10 @@ async fn test_read_write_validate() {
                 max_file_size: 69,
                 max_row_group_size: 69,
             },
+            writer_memory_budget_mb: None,
+            max_in_memory_mb: None,
+            max_unflushed_age_secs: None,
+            bloom_filters: Default::default(),
         },
     )
     .await
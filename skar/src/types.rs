@@ -1,10 +1,12 @@
 use std::collections::BTreeSet;
 
+use anyhow::{anyhow, Result};
 use arrayvec::ArrayVec;
 use serde::{Deserialize, Serialize};
 use skar_format::{Address, FixedSizeData, LogArgument};
+use tokio_util::sync::CancellationToken;
 
-use crate::query::ArrowBatch;
+use crate::{query::ArrowBatch, schema};
 
 pub type Sighash = FixedSizeData<4>;
 
@@ -51,6 +53,53 @@ pub struct FieldSelection {
     pub log: BTreeSet<String>,
 }
 
+impl Query {
+    /// Checks the parts of the query that deserializing doesn't, so invalid queries
+    /// are rejected before any data is read.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(to_block) = self.to_block {
+            if to_block < self.from_block {
+                return Err(anyhow!(
+                    "to_block ({}) is lower than from_block ({})",
+                    to_block,
+                    self.from_block
+                ));
+            }
+        }
+
+        if let Some(status) = self
+            .transactions
+            .iter()
+            .filter_map(|selection| selection.status)
+            .find(|&status| status > 1)
+        {
+            return Err(anyhow!(
+                "transaction status should be 0 or 1, got {}",
+                status
+            ));
+        }
+
+        for (table, selected, schema) in [
+            ("block", &self.field_selection.block, schema::block_header()),
+            (
+                "transaction",
+                &self.field_selection.transaction,
+                schema::transaction(),
+            ),
+            ("log", &self.field_selection.log, schema::log()),
+        ] {
+            if let Some(name) = selected
+                .iter()
+                .find(|name| !schema.fields.iter().any(|field| &field.name == *name))
+            {
+                return Err(anyhow!("unknown {} field: {}", table, name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct QueryResult {
     pub data: QueryResultData,
@@ -66,7 +115,54 @@ pub struct QueryResultData {
 
 pub struct QueryContext {
     pub query: Query,
+    /// Checked between the steps of the execution so abandoned queries stop early.
+    pub cancel: CancellationToken,
     // these "set"s are used for joining transactions and blocks
     pub transaction_set: BTreeSet<(u64, u64)>,
     pub block_set: BTreeSet<u64>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(json: serde_json::Value) -> Result<Query, serde_json::Error> {
+        serde_json::from_value(json)
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = query(serde_json::json!({
+            "from_block": 10,
+            "to_block": 20,
+            "transactions": [{ "status": 1 }],
+            "field_selection": { "block": ["number"], "log": ["address", "data"] },
+        }))
+        .unwrap();
+        assert!(valid.validate().is_ok());
+
+        let reversed = query(serde_json::json!({ "from_block": 20, "to_block": 10 })).unwrap();
+        assert!(reversed.validate().is_err());
+
+        let bad_status = query(serde_json::json!({
+            "from_block": 0,
+            "transactions": [{ "status": 2 }],
+        }))
+        .unwrap();
+        assert!(bad_status.validate().is_err());
+
+        let unknown_field = query(serde_json::json!({
+            "from_block": 0,
+            "field_selection": { "log": ["adress"] },
+        }))
+        .unwrap();
+        assert!(unknown_field.validate().is_err());
+
+        assert!(query(serde_json::json!({ "from_block": 0, "form_block": 10 })).is_err());
+        assert!(query(serde_json::json!({
+            "from_block": 0,
+            "logs": [{ "address": ["0x1234"] }],
+        }))
+        .is_err());
+    }
+}
@@ -1,4 +1,11 @@
-use std::{cmp, fs, path::Path, sync::Arc};
+use std::{
+    cmp::{self, Reverse},
+    collections::BinaryHeap,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::Arc,
+};
 
 use crate::{
     config::{ParquetConfig, TableConfig},
@@ -7,11 +14,8 @@ use crate::{
 };
 use anyhow::{anyhow, Context, Error, Result};
 use arrow2::{
-    array::UInt32Array,
-    compute::{
-        self,
-        sort::{lexsort_to_indices, SortColumn},
-    },
+    array::{growable::make_growable, UInt64Array},
+    compute::{self, aggregate::estimated_bytes_size},
     datatypes::{Schema, SchemaRef},
     io::parquet::write::{
         transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
@@ -19,6 +23,10 @@ use arrow2::{
     },
 };
 
+const DEFAULT_WRITER_MEMORY_BUDGET_MB: usize = 256;
+
+pub(crate) const MEGABYTE: usize = 1024 * 1024;
+
 const BLOCK_SORT_INDICES: &[usize] = &[
     0, // block.number
 ];
@@ -40,10 +48,16 @@ async fn write_parquet_file(
     path: &Path,
     schema: SchemaRef,
     table_cfg: &TableConfig,
+    memory_budget: usize,
 ) -> Result<()> {
     tokio::task::block_in_place(|| {
-        let row_groups = prepare_row_groups(sort_indices, data, table_cfg.max_row_group_size)
-            .context("prepare row groups")?;
+        let row_groups = MergedRowGroups::new(
+            sort_indices,
+            data,
+            table_cfg.max_row_group_size,
+            memory_budget,
+        )
+        .context("prepare row groups")?;
 
         let encodings = schema
             .fields
@@ -52,50 +66,226 @@ async fn write_parquet_file(
             .collect();
 
         let row_groups = RowGroupIterator::try_new(
-            row_groups.into_iter().map(Ok),
+            row_groups.map(Ok),
             &schema,
             parquet_write_options(),
             encodings,
         )
         .context("create row groups")?;
 
-        let mut buf = Vec::new();
-        let mut writer =
-            FileWriter::try_new(&mut buf, Schema::clone(&schema), parquet_write_options())
-                .context("create file writer")?;
+        let file = File::create(path).context("create file")?;
+        let mut writer = FileWriter::try_new(
+            BufWriter::new(file),
+            Schema::clone(&schema),
+            parquet_write_options(),
+        )
+        .context("create file writer")?;
 
         for group in row_groups {
-            writer.write(group.unwrap()).context("write file data")?;
+            let group = group.context("encode row group")?;
+            writer.write(group).context("write file data")?;
         }
 
         let _size = writer.end(None).context("write footer")?;
 
-        fs::write(path, buf).context("write file to disk")?;
+        let file = writer
+            .into_inner()
+            .into_inner()
+            .context("flush file writer")?;
+        file.sync_all().context("sync file to disk")?;
 
         Ok::<_, Error>(())
     })
 }
 
-fn prepare_row_groups(
-    sort_indices: &[usize],
-    data: &[Arc<ArrowChunk>],
-    items_per_chunk: usize,
-) -> Result<Vec<ArrowChunk>> {
-    let chunk = concat_chunks(data).context("concatenate chunks")?;
-    let chunk = lexsort_chunk(sort_indices, &chunk).context("sort chunk")?;
-
-    let len = chunk.len();
-
-    (0..len)
-        .step_by(items_per_chunk)
-        .map(|start| {
-            let end = cmp::min(len, start + items_per_chunk);
-            let length = end - start;
-            Ok(ArrowChunk::new(
-                chunk.iter().map(|arr| arr.sliced(start, length)).collect(),
-            ))
+/// Merges chunks into sorted row groups without concatenating them into a single chunk first.
+///
+/// Each chunk is sorted on its own and the chunks are merged with a k-way merge, so only the
+/// row group that is being built is copied. Row groups are cut at `max_rows` rows or when they
+/// reach `max_bytes` estimated bytes, whichever comes first.
+struct MergedRowGroups<'a> {
+    chunks: Vec<SortedChunk<'a>>,
+    heap: BinaryHeap<Reverse<(SortKey, usize)>>,
+    max_rows: usize,
+    max_bytes: usize,
+}
+
+// Sort columns are padded with zeroes, there are at most 3 of them
+type SortKey = [u64; 3];
+
+struct SortedChunk<'a> {
+    chunk: &'a ArrowChunk,
+    sort_cols: Vec<&'a UInt64Array>,
+    // Order of the rows in the chunk, `None` if the chunk is already sorted
+    order: Option<Vec<usize>>,
+    // Position of the next row in `order`
+    pos: usize,
+    bytes_per_row: usize,
+}
+
+impl<'a> SortedChunk<'a> {
+    fn new(sort_indices: &[usize], chunk: &'a ArrowChunk) -> Result<Self> {
+        let sort_cols = sort_indices
+            .iter()
+            .map(|i| {
+                chunk
+                    .columns()
+                    .get(*i)
+                    .context("get column")?
+                    .as_any()
+                    .downcast_ref::<UInt64Array>()
+                    .context("sort column is not u64")
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut sorted_chunk = Self {
+            chunk,
+            sort_cols,
+            order: None,
+            pos: 0,
+            bytes_per_row: 0,
+        };
+
+        let is_sorted = (1..chunk.len()).all(|i| sorted_chunk.key(i - 1) <= sorted_chunk.key(i));
+
+        if !is_sorted {
+            let mut order = (0..chunk.len()).collect::<Vec<_>>();
+            order.sort_by_key(|&i| sorted_chunk.key(i));
+            sorted_chunk.order = Some(order);
+        }
+
+        if !chunk.is_empty() {
+            let bytes = chunk
+                .columns()
+                .iter()
+                .map(|col| estimated_bytes_size(col.as_ref()))
+                .sum::<usize>();
+            sorted_chunk.bytes_per_row = bytes / chunk.len();
+        }
+
+        Ok(sorted_chunk)
+    }
+
+    fn key(&self, row: usize) -> SortKey {
+        let mut key = SortKey::default();
+        for (k, col) in key.iter_mut().zip(self.sort_cols.iter()) {
+            *k = col.value(row);
+        }
+        key
+    }
+
+    fn row(&self, pos: usize) -> usize {
+        match self.order.as_ref() {
+            Some(order) => order[pos],
+            None => pos,
+        }
+    }
+
+    // Returns the key of the next row and advances the position
+    fn advance(&mut self) -> Option<SortKey> {
+        if self.pos >= self.chunk.len() {
+            return None;
+        }
+
+        let key = self.key(self.row(self.pos));
+        self.pos += 1;
+        Some(key)
+    }
+}
+
+impl<'a> MergedRowGroups<'a> {
+    fn new(
+        sort_indices: &[usize],
+        data: &'a [Arc<ArrowChunk>],
+        max_rows: usize,
+        max_bytes: usize,
+    ) -> Result<Self> {
+        if sort_indices.len() > SortKey::default().len() {
+            return Err(anyhow!("too many sort columns"));
+        }
+
+        let mut chunks = data
+            .iter()
+            .map(|chunk| SortedChunk::new(sort_indices, chunk))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut heap = BinaryHeap::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            if let Some(key) = chunk.advance() {
+                heap.push(Reverse((key, i)));
+            }
+        }
+
+        Ok(Self {
+            chunks,
+            heap,
+            max_rows: cmp::max(max_rows, 1),
+            max_bytes: cmp::max(max_bytes, 1),
         })
-        .collect()
+    }
+}
+
+impl<'a> Iterator for MergedRowGroups<'a> {
+    type Item = ArrowChunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.heap.is_empty() {
+            return None;
+        }
+
+        let num_cols = self.chunks[0].chunk.columns().len();
+        let mut growables = (0..num_cols)
+            .map(|col| {
+                let arrays = self
+                    .chunks
+                    .iter()
+                    .map(|c| c.chunk.columns()[col].as_ref())
+                    .collect::<Vec<_>>();
+                make_growable(&arrays, false, self.max_rows)
+            })
+            .collect::<Vec<_>>();
+
+        let mut num_rows = 0;
+        let mut num_bytes = 0;
+        // (chunk index, start row, length) of rows that are next to each other in the source chunk
+        let mut run: Option<(usize, usize, usize)> = None;
+
+        while num_rows < self.max_rows && num_bytes < self.max_bytes {
+            let Reverse((_, chunk_idx)) = match self.heap.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            let chunk = &mut self.chunks[chunk_idx];
+            let row = chunk.row(chunk.pos - 1);
+
+            run = match run {
+                Some((idx, start, len)) if idx == chunk_idx && start + len == row => {
+                    Some((idx, start, len + 1))
+                }
+                Some((idx, start, len)) => {
+                    growables.iter_mut().for_each(|g| g.extend(idx, start, len));
+                    Some((chunk_idx, row, 1))
+                }
+                None => Some((chunk_idx, row, 1)),
+            };
+
+            num_rows += 1;
+            num_bytes += chunk.bytes_per_row;
+
+            if let Some(key) = chunk.advance() {
+                self.heap.push(Reverse((key, chunk_idx)));
+            }
+        }
+
+        if let Some((idx, start, len)) = run {
+            growables.iter_mut().for_each(|g| g.extend(idx, start, len));
+        }
+
+        Some(ArrowChunk::new(
+            growables.iter_mut().map(|g| g.as_box()).collect(),
+        ))
+    }
 }
 
 pub fn concat_chunks(chunks: &[Arc<ArrowChunk>]) -> Result<ArrowChunk> {
@@ -124,102 +314,62 @@ pub fn concat_chunks(chunks: &[Arc<ArrowChunk>]) -> Result<ArrowChunk> {
     Ok(ArrowChunk::new(cols))
 }
 
-fn lexsort_chunk(sort_indices: &[usize], chunk: &ArrowChunk) -> Result<ArrowChunk> {
-    let sort_cols = sort_indices
-        .iter()
-        .map(|i| {
-            let values = chunk.columns().get(*i).context("get column")?.as_ref();
-
-            Ok(SortColumn {
-                values,
-                options: None,
-            })
-        })
-        .collect::<Result<Vec<_>>>()?;
-
-    let indices: UInt32Array = lexsort_to_indices(&sort_cols, None).context("sort data")?;
-
-    let cols = chunk
-        .columns()
-        .iter()
-        .map(|col| compute::take::take(col.as_ref(), &indices).context("sort column"))
-        .collect::<Result<Vec<_>>>()?;
-
-    Ok(ArrowChunk::new(cols))
-}
-
 pub(crate) async fn write_folder(
     in_mem: &InMemory,
     path: &Path,
     cfg: &ParquetConfig,
 ) -> Result<()> {
-    let blocks = {
-        let mut path = path.to_owned();
-        path.push("blocks.parquet");
-
-        let in_mem = in_mem.clone();
-
-        async move {
-            write_parquet_file(
-                BLOCK_SORT_INDICES,
-                &in_mem.blocks.data,
-                &path,
-                schema::block_header(),
-                &cfg.blocks,
-            )
-            .await
-            .context("write blocks.parquet")?;
-
-            Ok::<_, Error>(())
-        }
-    };
-
-    let transactions = {
-        let mut path = path.to_owned();
-        path.push("transactions.parquet");
-
-        let in_mem = in_mem.clone();
-
-        async move {
-            write_parquet_file(
-                TX_SORT_INDICES,
-                &in_mem.transactions.data,
-                &path,
-                schema::transaction(),
-                &cfg.transactions,
-            )
-            .await
-            .context("write transactions.parquet")?;
-
-            Ok::<_, Error>(())
-        }
-    };
-
-    let logs = {
-        let mut path = path.to_owned();
-        path.push("logs.parquet");
-        async move {
-            write_parquet_file(
-                LOG_SORT_INDICES,
-                &in_mem.logs.data,
-                &path,
-                schema::log(),
-                &cfg.logs,
-            )
-            .await
-            .context("write logs.parquet")?;
-
-            Ok::<_, Error>(())
-        }
-    };
-
-    let (b, t, l) = futures::future::join3(blocks, transactions, logs).await;
-
-    b?;
-    t?;
-    l?;
+    // The files are written one after another. Half of the budget is for the row group that is
+    // being built and the other half is for its encoded form.
+    let memory_budget = cfg
+        .writer_memory_budget_mb
+        .unwrap_or(DEFAULT_WRITER_MEMORY_BUDGET_MB)
+        * MEGABYTE
+        / 2;
+
+    write_parquet_file(
+        BLOCK_SORT_INDICES,
+        &in_mem.blocks.iter().cloned().collect::<Vec<_>>(),
+        &path.join("blocks.parquet"),
+        schema::block_header(),
+        &cfg.blocks,
+        memory_budget,
+    )
+    .await
+    .context("write blocks.parquet")?;
+
+    write_parquet_file(
+        TX_SORT_INDICES,
+        &in_mem.transactions.iter().cloned().collect::<Vec<_>>(),
+        &path.join("transactions.parquet"),
+        schema::transaction(),
+        &cfg.transactions,
+        memory_budget,
+    )
+    .await
+    .context("write transactions.parquet")?;
+
+    write_parquet_file(
+        LOG_SORT_INDICES,
+        &in_mem.logs.iter().cloned().collect::<Vec<_>>(),
+        &path.join("logs.parquet"),
+        schema::log(),
+        &cfg.logs,
+        memory_budget,
+    )
+    .await
+    .context("write logs.parquet")?;
+
+    tokio::task::block_in_place(|| sync_dir(path)).context("sync parquet folder")
+}
 
-    Ok(())
+/// Syncs the directory to disk so the files created in it and the
+/// files that are renamed into it persist.
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)
+        .context("open directory")?
+        .sync_all()
+        .context("sync directory")
 }
 
 pub fn parquet_write_options() -> WriteOptions {
@@ -230,3 +380,71 @@ pub fn parquet_write_options() -> WriteOptions {
         data_pagesize_limit: Some(usize::MAX),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use arrow2::array::Utf8Array;
+
+    use super::*;
+
+    fn chunk(block_numbers: &[u64], names: &[&str]) -> Arc<ArrowChunk> {
+        Arc::new(ArrowChunk::new(vec![
+            UInt64Array::from_slice(block_numbers).boxed(),
+            Utf8Array::<i32>::from_slice(names).boxed(),
+        ]))
+    }
+
+    #[test]
+    fn test_merged_row_groups() {
+        let data = vec![
+            chunk(&[1, 4, 6], &["a", "d", "f"]),
+            chunk(&[5, 2, 3], &["e", "b", "c"]),
+            chunk(&[], &[]),
+            chunk(&[7], &["g"]),
+        ];
+
+        let row_groups = MergedRowGroups::new(&[0], &data, 3, usize::MAX)
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            row_groups.iter().map(|rg| rg.len()).collect::<Vec<_>>(),
+            vec![3, 3, 1]
+        );
+
+        let names = row_groups
+            .iter()
+            .flat_map(|rg| {
+                rg.columns()[1]
+                    .as_any()
+                    .downcast_ref::<Utf8Array<i32>>()
+                    .unwrap()
+                    .values_iter()
+                    .map(|s| s.to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b", "c", "d", "e", "f", "g"]);
+    }
+
+    #[test]
+    fn test_merged_row_groups_memory_budget() {
+        let data = vec![chunk(&[1, 2, 3, 4], &["a", "b", "c", "d"])];
+
+        let bytes_per_row = data[0]
+            .columns()
+            .iter()
+            .map(|col| estimated_bytes_size(col.as_ref()))
+            .sum::<usize>()
+            / 4;
+
+        let row_groups = MergedRowGroups::new(&[0], &data, 100, bytes_per_row * 2)
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            row_groups.iter().map(|rg| rg.len()).collect::<Vec<_>>(),
+            vec![2, 2]
+        );
+    }
+}